@@ -0,0 +1,331 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::cell::RefCell;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use futures::future::poll_fn;
+use futures::io::{AsyncRead, AsyncWrite, Error as IoError, ErrorKind as IoErrorKind};
+
+use cros_async::fd_executor::{self, add_read_waker, add_write_waker};
+
+use crate::async_core::{Error, Result};
+
+/// Converts a failure to register a waker into an io::Error, so it can be
+/// returned from AsyncRead and AsyncWrite.
+fn waker_error(e: fd_executor::Error) -> IoError {
+    IoError::new(IoErrorKind::Other, e)
+}
+
+fn set_nonblocking(fd: RawFd) -> std::result::Result<(), IoError> {
+    // Safe because fcntl with these arguments modifies no memory and the
+    // return values are checked.
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(IoError::last_os_error());
+        }
+    }
+
+    Ok(())
+}
+
+/// Wraps a file descriptor so it can be used with the cros_async executor.
+///
+/// The fd is put into nonblocking mode, and any operation that would block
+/// instead registers a waker with the executor and returns `Poll::Pending`.
+/// Any `Read`/`Write` type gets `AsyncRead`/`AsyncWrite`, and other operations
+/// can be made async with `read_with` and `write_with`.
+pub struct AsyncFd<T: AsRawFd> {
+    inner: T,
+}
+
+impl<T: AsRawFd> AsyncFd<T> {
+    /// Wraps `inner`, setting its fd as nonblocking.
+    pub fn new(inner: T) -> Result<AsyncFd<T>> {
+        set_nonblocking(inner.as_raw_fd()).map_err(Error::SetNonblocking)?;
+        Ok(AsyncFd { inner })
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Unwraps the inner value. Its fd is left in nonblocking mode.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Splits into a read half and a write half that share the fd, so that
+    /// reading and writing can happen in separate tasks. Each half registers
+    /// its own wakers, so a pending read doesn't hold up writes.
+    ///
+    /// The halves can't be sent to other threads.
+    pub fn split(self) -> (ReadHalf<T>, WriteHalf<T>) {
+        let fd = Rc::new(RefCell::new(self));
+        (ReadHalf { fd: fd.clone() }, WriteHalf { fd })
+    }
+
+    /// Runs `op`, registering a read waker if it would block.
+    pub fn poll_read_with<R, F>(&self, cx: &mut Context, op: F) -> Poll<std::result::Result<R, IoError>>
+    where
+        F: FnOnce(&T) -> std::result::Result<R, IoError>,
+    {
+        match op(&self.inner) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                match add_read_waker(self.inner.as_raw_fd(), cx.waker().clone()) {
+                    Ok(_) => Poll::Pending,
+                    Err(e) => Poll::Ready(Err(waker_error(e))),
+                }
+            },
+            res => Poll::Ready(res),
+        }
+    }
+
+    /// Runs `op`, registering a write waker if it would block.
+    pub fn poll_write_with<R, F>(&self, cx: &mut Context, op: F) -> Poll<std::result::Result<R, IoError>>
+    where
+        F: FnOnce(&T) -> std::result::Result<R, IoError>,
+    {
+        match op(&self.inner) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                match add_write_waker(self.inner.as_raw_fd(), cx.waker().clone()) {
+                    Ok(_) => Poll::Pending,
+                    Err(e) => Poll::Ready(Err(waker_error(e))),
+                }
+            },
+            res => Poll::Ready(res),
+        }
+    }
+
+    /// Retries `op` each time the fd becomes readable until it stops failing
+    /// with `WouldBlock`.
+    pub async fn read_with<R, F>(&self, mut op: F) -> std::result::Result<R, IoError>
+    where
+        F: FnMut(&T) -> std::result::Result<R, IoError>,
+    {
+        poll_fn(|cx| self.poll_read_with(cx, &mut op)).await
+    }
+
+    /// Retries `op` each time the fd becomes writable until it stops failing
+    /// with `WouldBlock`.
+    pub async fn write_with<R, F>(&self, mut op: F) -> std::result::Result<R, IoError>
+    where
+        F: FnMut(&T) -> std::result::Result<R, IoError>,
+    {
+        poll_fn(|cx| self.poll_write_with(cx, &mut op)).await
+    }
+}
+
+impl<T: AsRawFd> AsRawFd for AsyncFd<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl<T: AsRawFd + Read + Unpin> AsyncRead for AsyncFd<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<std::result::Result<usize, IoError>> {
+        let this = self.get_mut();
+        let fd = this.inner.as_raw_fd();
+
+        match this.inner.read(buf) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                match add_read_waker(fd, cx.waker().clone()) {
+                    Ok(_) => Poll::Pending,
+                    Err(e) => Poll::Ready(Err(waker_error(e))),
+                }
+            },
+            res => Poll::Ready(res),
+        }
+    }
+}
+
+impl<T: AsRawFd + Write + Unpin> AsyncWrite for AsyncFd<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<std::result::Result<usize, IoError>> {
+        let this = self.get_mut();
+        let fd = this.inner.as_raw_fd();
+
+        match this.inner.write(buf) {
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                match add_write_waker(fd, cx.waker().clone()) {
+                    Ok(_) => Poll::Pending,
+                    Err(e) => Poll::Ready(Err(waker_error(e))),
+                }
+            },
+            res => Poll::Ready(res),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::result::Result<(), IoError>> {
+        // Writes go straight to the fd, so there is nothing to flush.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::result::Result<(), IoError>> {
+        // Shut down writes on sockets so the peer sees EOF. Other fds have no
+        // half-close and are simply closed when dropped.
+        // Safe because shutdown modifies no memory and the return value is checked.
+        let ret = unsafe { libc::shutdown(self.inner.as_raw_fd(), libc::SHUT_WR) };
+        if ret < 0 {
+            let err = IoError::last_os_error();
+            if err.raw_os_error() != Some(libc::ENOTSOCK) {
+                return Poll::Ready(Err(err));
+            }
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A stream that can be split into a read half and a write half, so that
+/// reading and writing can happen in separate tasks.
+pub trait Split {
+    type ReadHalf: AsyncRead + Unpin;
+    type WriteHalf: AsyncWrite + Unpin;
+
+    fn split(self) -> (Self::ReadHalf, Self::WriteHalf);
+}
+
+impl<T: AsRawFd + Read + Write + Unpin> Split for AsyncFd<T> {
+    type ReadHalf = ReadHalf<T>;
+    type WriteHalf = WriteHalf<T>;
+
+    fn split(self) -> (ReadHalf<T>, WriteHalf<T>) {
+        AsyncFd::split(self)
+    }
+}
+
+/// The read half of an AsyncFd, created by `AsyncFd::split`.
+pub struct ReadHalf<T: AsRawFd> {
+    fd: Rc<RefCell<AsyncFd<T>>>,
+}
+
+/// The write half of an AsyncFd, created by `AsyncFd::split`.
+pub struct WriteHalf<T: AsRawFd> {
+    fd: Rc<RefCell<AsyncFd<T>>>,
+}
+
+// The halves only borrow the shared AsyncFd for the duration of a poll. The
+// executor is single threaded, so the borrows can never overlap.
+
+impl<T: AsRawFd> AsRawFd for ReadHalf<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.borrow().as_raw_fd()
+    }
+}
+
+impl<T: AsRawFd> AsRawFd for WriteHalf<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.borrow().as_raw_fd()
+    }
+}
+
+impl<T: AsRawFd + Read + Unpin> AsyncRead for ReadHalf<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<std::result::Result<usize, IoError>> {
+        Pin::new(&mut *self.fd.borrow_mut()).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsRawFd + Write + Unpin> AsyncWrite for WriteHalf<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<std::result::Result<usize, IoError>> {
+        Pin::new(&mut *self.fd.borrow_mut()).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::result::Result<(), IoError>> {
+        Pin::new(&mut *self.fd.borrow_mut()).poll_flush(cx)
+    }
+
+    /// Shuts down writes on the shared socket. The read half can still
+    /// receive data until the peer closes its end.
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::result::Result<(), IoError>> {
+        Pin::new(&mut *self.fd.borrow_mut()).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::net::UnixStream;
+
+    use cros_async::complete2;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use futures::pin_mut;
+
+    fn pipe() -> (File, File) {
+        let mut fds = [0; 2];
+        // Safe because pipe2 only writes to the provided array and the return
+        // value is checked.
+        assert_eq!(unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) }, 0);
+        // Safe because both fds were just created and nothing else owns them.
+        unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) }
+    }
+
+    #[test]
+    fn pipe_rw() {
+        let (read_end, write_end) = pipe();
+        let read_end = AsyncFd::new(read_end).unwrap();
+        let write_end = AsyncFd::new(write_end).unwrap();
+
+        async fn read_all(mut read_end: AsyncFd<File>) -> String {
+            let mut buf = String::new();
+            read_end.read_to_string(&mut buf).await.unwrap();
+            buf
+        }
+
+        async fn write_all(mut write_end: AsyncFd<File>) {
+            write_end.write_all(b"foo").await.unwrap();
+            // Closing a pipe doesn't shut it down; dropping the write end
+            // is what signals EOF.
+            write_end.close().await.unwrap();
+        }
+
+        let r = read_all(read_end);
+        pin_mut!(r);
+        let w = write_all(write_end);
+        pin_mut!(w);
+
+        let (s, ()) = complete2(r, w).unwrap();
+        assert_eq!(s, "foo");
+    }
+
+    #[test]
+    fn split_rw() {
+        let (sock, peer) = UnixStream::pair().unwrap();
+        let (mut read_half, mut write_half) = AsyncFd::new(sock).unwrap().split();
+        let mut peer = AsyncFd::new(peer).unwrap();
+
+        // The read half waits for the peer's reply while the write half sends
+        // the request that the peer replies to.
+        let read = async {
+            let mut buf = String::new();
+            read_half.read_to_string(&mut buf).await.unwrap();
+            buf
+        };
+        pin_mut!(read);
+        let write_and_reply = async {
+            write_half.write_all(b"ping").await.unwrap();
+            write_half.close().await.unwrap();
+
+            let mut buf = String::new();
+            peer.read_to_string(&mut buf).await.unwrap();
+            assert_eq!(buf, "ping");
+            peer.write_all(b"pong").await.unwrap();
+            peer.close().await.unwrap();
+        };
+        pin_mut!(write_and_reply);
+
+        let (s, ()) = complete2(read, write_and_reply).unwrap();
+        assert_eq!(s, "pong");
+    }
+}
@@ -2,5 +2,142 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+/// Implements `TryFrom<T>` for `AsyncFd<T>`, for each of the given types.
+///
+/// This must be defined before the submodules that use it.
+macro_rules! async_fd_try_from {
+    ($($t:ty),* $(,)?) => {
+        $(
+            impl std::convert::TryFrom<$t> for crate::async_core::AsyncFd<$t> {
+                type Error = crate::async_core::Error;
+
+                fn try_from(inner: $t) -> crate::async_core::Result<Self> {
+                    crate::async_core::AsyncFd::new(inner)
+                }
+            }
+        )*
+    };
+}
+
+pub mod fd;
+pub mod tcp;
+pub mod timer;
 pub mod unix;
 pub mod vsock;
+
+pub use self::fd::{AsyncFd, ReadHalf, Split, WriteHalf};
+
+use std::fmt::{self, Display};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use futures::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+/// Backlog of pending connections for listening sockets created by vsh. This
+/// matches what std uses for its listeners.
+pub(crate) const LISTEN_BACKLOG: libc::c_int = 128;
+
+/// Errors generated while wrapping fds for use with the executor.
+#[derive(Debug)]
+pub enum Error {
+    /// An error occurred while setting the fd as nonblocking.
+    SetNonblocking(std::io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl std::error::Error for Error {}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        match self {
+            SetNonblocking(e) => write!(
+                f,
+                "An error occurred while setting the fd as nonblocking: {}.",
+                e
+            ),
+        }
+    }
+}
+
+impl From<Error> for IoError {
+    fn from(e: Error) -> IoError {
+        match e {
+            Error::SetNonblocking(e) => e,
+        }
+    }
+}
+
+/// Connects a nonblocking socket to `addr`, waiting for the connection to be
+/// established if it can't complete immediately.
+pub(crate) async fn connect<T: AsRawFd>(
+    sock: &AsyncFd<T>,
+    addr: *const libc::sockaddr,
+    len: libc::socklen_t,
+) -> std::result::Result<(), IoError> {
+    let fd = sock.as_raw_fd();
+
+    // Safe because the caller provides a valid address of len bytes, and the
+    // return value is checked.
+    if unsafe { libc::connect(fd, addr, len) } == 0 {
+        return Ok(());
+    }
+
+    let err = IoError::last_os_error();
+    if err.raw_os_error() != Some(libc::EINPROGRESS) {
+        return Err(err);
+    }
+
+    sock.write_with(|_| {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLOUT,
+            revents: 0,
+        };
+
+        // Safe because poll only modifies the provided pollfd and the return
+        // value is checked.
+        match unsafe { libc::poll(&mut pollfd, 1, 0) } {
+            ret if ret < 0 => return Err(IoError::last_os_error()),
+            0 => return Err(IoError::from(IoErrorKind::WouldBlock)),
+            _ => {}
+        }
+
+        let mut sock_err: libc::c_int = 0;
+        let mut sock_err_len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+        // Safe because getsockopt writes at most sock_err_len bytes to
+        // sock_err, and the return value is checked.
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_ERROR,
+                &mut sock_err as *mut libc::c_int as *mut libc::c_void,
+                &mut sock_err_len,
+            )
+        };
+        if ret < 0 {
+            return Err(IoError::last_os_error());
+        }
+        if sock_err != 0 {
+            return Err(IoError::from_raw_os_error(sock_err));
+        }
+
+        Ok(())
+    })
+    .await
+}
+
+/// Creates a new nonblocking socket.
+pub(crate) fn nonblocking_socket(domain: libc::c_int, kind: libc::c_int) -> std::result::Result<RawFd, IoError> {
+    // Safe because socket modifies no memory and the return value is checked.
+    let fd = unsafe { libc::socket(domain, kind | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(IoError::last_os_error());
+    }
+
+    Ok(fd)
+}
@@ -0,0 +1,154 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::mem;
+use std::net::{self, SocketAddr};
+use std::os::unix::io::FromRawFd;
+
+use futures::io::Error as IoError;
+
+use crate::async_core::{connect, nonblocking_socket, AsyncFd};
+
+pub use crate::async_core::{Error, Result};
+
+pub type TcpStream = AsyncFd<net::TcpStream>;
+pub type TcpListener = AsyncFd<net::TcpListener>;
+
+async_fd_try_from!(net::TcpStream, net::TcpListener);
+
+impl AsyncFd<net::TcpStream> {
+    /// Connects to `addr` without blocking the executor.
+    pub async fn connect(addr: SocketAddr) -> std::result::Result<TcpStream, IoError> {
+        let domain = match addr {
+            SocketAddr::V4(_) => libc::AF_INET,
+            SocketAddr::V6(_) => libc::AF_INET6,
+        };
+        let fd = nonblocking_socket(domain, libc::SOCK_STREAM)?;
+
+        // Safe because the fd was just created and nothing else owns it.
+        let stream = AsyncFd::new(unsafe { net::TcpStream::from_raw_fd(fd) })?;
+
+        match addr {
+            SocketAddr::V4(addr) => {
+                // Safe because sockaddr_in is a plain C struct for which all
+                // zeroes is a valid value.
+                let mut sockaddr: libc::sockaddr_in = unsafe { mem::zeroed() };
+                sockaddr.sin_family = libc::AF_INET as libc::sa_family_t;
+                sockaddr.sin_port = addr.port().to_be();
+                sockaddr.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+
+                connect(
+                    &stream,
+                    &sockaddr as *const libc::sockaddr_in as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in>() as libc::socklen_t,
+                )
+                .await?;
+            }
+            SocketAddr::V6(addr) => {
+                // Safe because sockaddr_in6 is a plain C struct for which all
+                // zeroes is a valid value.
+                let mut sockaddr: libc::sockaddr_in6 = unsafe { mem::zeroed() };
+                sockaddr.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sockaddr.sin6_port = addr.port().to_be();
+                sockaddr.sin6_flowinfo = addr.flowinfo();
+                sockaddr.sin6_addr.s6_addr = addr.ip().octets();
+                sockaddr.sin6_scope_id = addr.scope_id();
+
+                connect(
+                    &stream,
+                    &sockaddr as *const libc::sockaddr_in6 as *const libc::sockaddr,
+                    mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t,
+                )
+                .await?;
+            }
+        }
+
+        Ok(stream)
+    }
+}
+
+impl AsyncFd<net::TcpListener> {
+    /// Waits for a new connection, returning it as a nonblocking stream.
+    pub async fn accept(&self) -> std::result::Result<(TcpStream, SocketAddr), IoError> {
+        let (stream, addr) = self.read_with(|l| l.accept()).await?;
+
+        Ok((AsyncFd::new(stream)?, addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use cros_async::complete2;
+    use futures::pin_mut;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn async_rw() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let s1 = net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (s2, _) = listener.accept().unwrap();
+        let stream1: TcpStream = s1.try_into().unwrap();
+        let stream2: TcpStream = s2.try_into().unwrap();
+
+        async fn read_buf(mut stream: TcpStream) -> String {
+            let mut buf = vec![0u8; 3];
+
+            let res = stream.read_exact(&mut buf).await;
+
+            match res {
+                Ok(_) => String::from_utf8(buf).unwrap(),
+                Err(e) => e.to_string(),
+            }
+        }
+
+        async fn write_buf(mut stream: TcpStream) -> std::result::Result<(), IoError> {
+            let foo = "foo";
+
+            stream.write_all(foo.as_bytes()).await
+        }
+
+        let r = read_buf(stream1);
+        pin_mut!(r);
+
+        let w = write_buf(stream2);
+        pin_mut!(w);
+
+        if let (s, Ok(_)) = complete2(r, w).unwrap()
+        {
+            assert_eq!(s, "foo");
+        } else {
+            panic!("wrong futures returned from complete2");
+        }
+    }
+
+    #[test]
+    fn listener_connect() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let listener: TcpListener = listener.try_into().unwrap();
+
+        async fn accept(listener: &TcpListener) -> String {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = String::new();
+            stream.read_to_string(&mut buf).await.unwrap();
+            buf
+        }
+
+        async fn connect(addr: SocketAddr) {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(b"foo").await.unwrap();
+            stream.close().await.unwrap();
+        }
+
+        let a = accept(&listener);
+        pin_mut!(a);
+        let c = connect(addr);
+        pin_mut!(c);
+
+        let (s, ()) = complete2(a, c).unwrap();
+        assert_eq!(s, "foo");
+    }
+}
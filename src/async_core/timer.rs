@@ -0,0 +1,102 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
+use std::time::Duration;
+
+use futures::io::Error as IoError;
+
+use crate::async_core::AsyncFd;
+
+/// A one-shot timer backed by a timerfd that can be awaited on the cros_async
+/// executor.
+pub struct Timer {
+    fd: AsyncFd<File>,
+}
+
+impl Timer {
+    /// Creates a new timer that is not armed.
+    pub fn new() -> Result<Timer, IoError> {
+        // Safe because timerfd_create modifies no memory and the return value
+        // is checked.
+        let fd = unsafe {
+            libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_NONBLOCK | libc::TFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(IoError::last_os_error());
+        }
+
+        // Safe because the fd was just created and nothing else owns it.
+        let file = unsafe { File::from_raw_fd(fd) };
+        Ok(Timer {
+            fd: AsyncFd::new(file)?,
+        })
+    }
+
+    /// Arms the timer to expire once after `timeout`, replacing any previous
+    /// expiry.
+    pub fn reset(&self, timeout: Duration) -> Result<(), IoError> {
+        // A zero it_value disarms the timer, so expire as soon as possible
+        // instead.
+        let timeout = timeout.max(Duration::from_nanos(1));
+
+        // Safe because all-zero is a valid itimerspec.
+        let mut spec: libc::itimerspec = unsafe { mem::zeroed() };
+        spec.it_value.tv_sec = timeout.as_secs() as libc::time_t;
+        spec.it_value.tv_nsec = timeout.subsec_nanos() as libc::c_long;
+
+        // Safe because timerfd_settime only reads the provided itimerspec and
+        // the return value is checked.
+        let ret = unsafe { libc::timerfd_settime(self.fd.as_raw_fd(), 0, &spec, ptr::null_mut()) };
+        if ret < 0 {
+            return Err(IoError::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Waits until the timer expires. If the timer has already expired since
+    /// the last wait, this returns immediately.
+    pub async fn wait(&self) -> Result<(), IoError> {
+        let mut count = [0u8; mem::size_of::<u64>()];
+        self.fd
+            .read_with(|mut file| file.read_exact(&mut count))
+            .await
+    }
+}
+
+impl AsRawFd for Timer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    use cros_async::run_one;
+    use futures::pin_mut;
+
+    #[test]
+    fn expires_after_timeout() {
+        let timer = Timer::new().unwrap();
+        let timeout = Duration::from_millis(50);
+
+        let start = Instant::now();
+        timer.reset(timeout).unwrap();
+        let wait = timer.wait();
+        pin_mut!(wait);
+        run_one(wait).unwrap().unwrap();
+        assert!(start.elapsed() >= timeout);
+    }
+}
@@ -2,109 +2,296 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use std::convert::TryFrom;
 use std::fmt::{self, Display};
-use std::io::{ErrorKind, Read, Write};
-use std::os::unix::io::AsRawFd;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::os::unix::net;
-use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::path::Path;
+use std::slice;
 
-use futures::io::{AsyncRead, AsyncWrite, Error as IoError, ErrorKind as IoErrorKind};
+use futures::io::{Error as IoError, ErrorKind as IoErrorKind};
 
-use cros_async::fd_executor::{add_read_waker, add_write_waker};
+use crate::async_core::{connect, nonblocking_socket, AsyncFd};
 
-/// Errors generated while polling for signals.
-#[derive(Debug)]
-pub enum Error {
-    /// An error occurred while setting the unix stream as nonblocking.
-    SetNonblocking(std::io::Error),
+pub use crate::async_core::{Error, Result};
+
+pub type UnixStream = AsyncFd<net::UnixStream>;
+pub type UnixListener = AsyncFd<net::UnixListener>;
+pub type UnixDatagram = AsyncFd<net::UnixDatagram>;
+
+async_fd_try_from!(net::UnixStream, net::UnixListener, net::UnixDatagram);
+
+impl AsyncFd<net::UnixStream> {
+    /// Connects to `addr` without blocking the executor.
+    pub async fn connect(addr: &UnixAddr) -> std::result::Result<UnixStream, IoError> {
+        let fd = nonblocking_socket(libc::AF_UNIX, libc::SOCK_STREAM)?;
+
+        // Safe because the fd was just created and nothing else owns it.
+        let stream = AsyncFd::new(unsafe { net::UnixStream::from_raw_fd(fd) })?;
+
+        let (sockaddr, len) = addr.as_sockaddr();
+        connect(&stream, sockaddr, len).await?;
+
+        Ok(stream)
+    }
 }
 
-pub type Result<T> = std::result::Result<T, Error>;
+impl AsyncFd<net::UnixListener> {
+    /// Waits for a new connection, returning it as a nonblocking stream.
+    pub async fn accept(&self) -> std::result::Result<(UnixStream, net::SocketAddr), IoError> {
+        let (stream, addr) = self.read_with(|l| l.accept()).await?;
+
+        Ok((AsyncFd::new(stream)?, addr))
+    }
+}
 
-impl std::error::Error for Error {}
+/// The address of a unix socket. This may be a filesystem path, a name in the
+/// abstract namespace, or unnamed if the socket was never bound.
+#[derive(Clone)]
+pub struct UnixAddr {
+    addr: libc::sockaddr_un,
+    len: libc::socklen_t,
+}
 
-impl Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use self::Error::*;
-
-        match self {
-            SetNonblocking(e) => write!(
-                f,
-                "An error occurred while setting the unix stream as nonblocking: {}.",
-                e
-            ),
+// Offset of sun_path within sockaddr_un.
+const SUN_PATH_OFFSET: usize = mem::size_of::<libc::sa_family_t>();
+
+impl UnixAddr {
+    /// Creates an address in the abstract namespace.
+    pub fn new_abstract(name: &[u8]) -> std::io::Result<UnixAddr> {
+        // The leading nul byte of sun_path is what places the socket in the
+        // abstract namespace, so it doesn't count against the name.
+        UnixAddr::from_path_bytes(&[&[0u8][..], name].concat())
+    }
+
+    /// Creates an address for a filesystem path.
+    pub fn new_path<P: AsRef<Path>>(path: P) -> std::io::Result<UnixAddr> {
+        UnixAddr::from_path_bytes(path.as_ref().as_os_str().as_bytes())
+    }
+
+    fn from_path_bytes(bytes: &[u8]) -> std::io::Result<UnixAddr> {
+        // Safe because sockaddr_un is a plain C struct for which all zeroes
+        // is a valid value.
+        let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+        // Leave room for a terminating nul for path names.
+        if bytes.len() >= addr.sun_path.len() {
+            return Err(IoError::new(IoErrorKind::InvalidInput, "unix socket address is too long"));
         }
+        for (dst, src) in addr.sun_path.iter_mut().zip(bytes) {
+            *dst = *src as libc::c_char;
+        }
+
+        Ok(UnixAddr {
+            addr,
+            len: (SUN_PATH_OFFSET + bytes.len()) as libc::socklen_t,
+        })
+    }
+
+    /// Returns the meaningful bytes of sun_path.
+    fn path_bytes(&self) -> &[u8] {
+        let len = (self.len as usize).saturating_sub(SUN_PATH_OFFSET).min(self.addr.sun_path.len());
+
+        // Safe because c_char and u8 have the same size and len is within sun_path.
+        unsafe { slice::from_raw_parts(self.addr.sun_path.as_ptr() as *const u8, len) }
+    }
+
+    /// Returns true if the address belongs to a socket that was never bound.
+    pub fn is_unnamed(&self) -> bool {
+        self.path_bytes().is_empty()
+    }
+
+    /// Returns a pointer to the underlying sockaddr and its length, for passing to libc.
+    pub fn as_sockaddr(&self) -> (*const libc::sockaddr, libc::socklen_t) {
+        (&self.addr as *const libc::sockaddr_un as *const libc::sockaddr, self.len)
     }
 }
 
-pub struct UnixStream {
-    inner: net::UnixStream,
+impl PartialEq for UnixAddr {
+    fn eq(&self, other: &UnixAddr) -> bool {
+        self.path_bytes() == other.path_bytes()
+    }
 }
 
-impl TryFrom<net::UnixStream> for UnixStream {
-    type Error = crate::async_core::unix::Error;
+impl Eq for UnixAddr {}
 
-    fn try_from(unix_stream: net::UnixStream) -> Result<UnixStream> {
-        unix_stream.set_nonblocking(true).map_err(Error::SetNonblocking)?;
-        Ok(UnixStream {
-            inner: unix_stream,
-        })
+impl Hash for UnixAddr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.path_bytes().hash(state);
     }
 }
 
-impl AsyncRead for UnixStream {
-    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<std::result::Result<usize, IoError>> {
-        let res = self.inner.read(buf);
-
-        match res {
-            Ok(n) => Poll::Ready(Ok(n)),
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                match add_read_waker(self.inner.as_raw_fd(), cx.waker().clone()) {
-                    Ok(_) => Poll::Pending,
-                    Err(_) => {
-                        // TODO(smbarber): convert fd_executor::Error here.
-                        Poll::Ready(Err(IoError::new(IoErrorKind::Other, "failed to add read waker")))
-                    },
-                }
+impl Display for UnixAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = self.path_bytes();
+        match path.split_first() {
+            None => write!(f, "(unnamed)"),
+            Some((0, name)) => write!(f, "@{}", String::from_utf8_lossy(name)),
+            Some(_) => {
+                // Path names may or may not include the terminating nul.
+                let path = path.split(|b| *b == 0).next().unwrap_or(path);
+                write!(f, "{}", String::from_utf8_lossy(path))
             },
-            Err(e) => Poll::Ready(Err(e)),
         }
     }
 }
 
-impl AsyncWrite for UnixStream {
-    fn poll_write(
-            mut self: Pin<&mut Self>,
-            cx: &mut Context,
-            buf: &[u8],
-            ) -> Poll<std::result::Result<usize, IoError>> {
-        let res = self.inner.write(buf);
-
-        match res {
-            Ok(n) => Poll::Ready(Ok(n)),
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                match add_write_waker(self.inner.as_raw_fd(), cx.waker().clone()) {
-                    Ok(_) => Poll::Pending,
-                    Err(_) => {
-                        // TODO(smbarber): convert fd_executor::Error here.
-                        Poll::Ready(Err(IoError::new(IoErrorKind::Other, "failed to add write waker")))
-                    },
-                }
-            },
-            Err(e) => Poll::Ready(Err(e)),
+impl fmt::Debug for UnixAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "UnixAddr({})", self)
+    }
+}
+
+impl AsyncFd<net::UnixDatagram> {
+    /// Receives a single datagram from the connected peer.
+    pub async fn recv(&self, buf: &mut [u8]) -> std::result::Result<usize, IoError> {
+        self.read_with(|s| s.recv(buf)).await
+    }
+
+    /// Sends a single datagram to the connected peer.
+    pub async fn send(&self, buf: &[u8]) -> std::result::Result<usize, IoError> {
+        self.write_with(|s| s.send(buf)).await
+    }
+
+    /// Receives a single datagram, returning its size and the address of the sender.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> std::result::Result<(usize, UnixAddr), IoError> {
+        self.read_with(|s| {
+            // Safe because sockaddr_un is a plain C struct for which all
+            // zeroes is a valid value.
+            let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+            let mut len = mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+
+            // Safe because recvfrom writes at most buf.len() bytes to buf
+            // and at most len bytes to addr, and the return value is checked.
+            let ret = unsafe {
+                libc::recvfrom(
+                    s.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                    &mut addr as *mut libc::sockaddr_un as *mut libc::sockaddr,
+                    &mut len,
+                )
+            };
+            if ret < 0 {
+                return Err(IoError::last_os_error());
+            }
+
+            Ok((ret as usize, UnixAddr { addr, len }))
+        })
+        .await
+    }
+
+    /// Has the kernel attach the credentials of the sender to each datagram
+    /// received, as reported by `recv_from_cred`.
+    pub fn set_pass_cred(&self) -> std::result::Result<(), IoError> {
+        let enable: libc::c_int = 1;
+
+        // Safe because setsockopt only reads size_of::<c_int>() bytes from
+        // enable and the return value is checked.
+        let ret = unsafe {
+            libc::setsockopt(
+                self.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PASSCRED,
+                &enable as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(IoError::last_os_error());
         }
+
+        Ok(())
     }
 
-    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::result::Result<(), IoError>> {
-        // Sockets don't need to flush writes.
-        Poll::Ready(Ok(()))
+    /// Receives a single datagram, returning its size, the address of the
+    /// sender and the pid of the process that sent it. The pid is 0 if the
+    /// kernel didn't report it, which happens unless `set_pass_cred` was called
+    /// before the datagram was sent.
+    pub async fn recv_from_cred(
+        &self,
+        buf: &mut [u8],
+    ) -> std::result::Result<(usize, UnixAddr, libc::pid_t), IoError> {
+        self.read_with(|s| {
+            // Safe because sockaddr_un is a plain C struct for which all
+            // zeroes is a valid value.
+            let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+            let mut iov = libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            };
+
+            // Only leave room for the credentials, so that the kernel discards
+            // any file descriptors sent along instead of installing them here.
+            let mut control = [0u64; 4];
+            // Safe because CMSG_SPACE only does arithmetic.
+            let control_len = unsafe { libc::CMSG_SPACE(mem::size_of::<libc::ucred>() as libc::c_uint) };
+            assert!(control_len as usize <= mem::size_of_val(&control));
+
+            // Safe because msghdr is a plain C struct for which all zeroes is a
+            // valid value.
+            let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+            msg.msg_name = &mut addr as *mut libc::sockaddr_un as *mut libc::c_void;
+            msg.msg_namelen = mem::size_of::<libc::sockaddr_un>() as libc::socklen_t;
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = control_len as _;
+
+            // Safe because recvmsg writes at most buf.len() bytes to buf, at
+            // most msg_namelen bytes to addr and at most msg_controllen bytes
+            // to control, and the return value is checked.
+            let ret = unsafe { libc::recvmsg(s.as_raw_fd(), &mut msg, 0) };
+            if ret < 0 {
+                return Err(IoError::last_os_error());
+            }
+
+            let mut pid = 0;
+            // Safe because msg was filled in by recvmsg, so the control
+            // messages it points to are within control.
+            unsafe {
+                let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+                while !cmsg.is_null() {
+                    if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_CREDENTIALS {
+                        let cred = (libc::CMSG_DATA(cmsg) as *const libc::ucred).read_unaligned();
+                        pid = cred.pid;
+                    }
+                    cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+                }
+            }
+
+            Ok((
+                ret as usize,
+                UnixAddr {
+                    addr,
+                    len: msg.msg_namelen,
+                },
+                pid,
+            ))
+        })
+        .await
     }
 
-    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::result::Result<(), IoError>> {
-        // We don't expect closing to block. Closing is performed when the socket is dropped.
-        self.poll_flush(cx)
+    /// Sends a single datagram to `addr`.
+    pub async fn send_to(&self, buf: &[u8], addr: &UnixAddr) -> std::result::Result<usize, IoError> {
+        let (sockaddr, len) = addr.as_sockaddr();
+        self.write_with(|s| {
+            // Safe because sendto only reads buf.len() bytes from buf and len
+            // bytes from sockaddr, and the return value is checked.
+            let ret = unsafe {
+                libc::sendto(s.as_raw_fd(), buf.as_ptr() as *const libc::c_void, buf.len(), 0, sockaddr, len)
+            };
+            if ret < 0 {
+                return Err(IoError::last_os_error());
+            }
+
+            Ok(ret as usize)
+        })
+        .await
     }
 }
 
@@ -112,7 +299,7 @@ impl AsyncWrite for UnixStream {
 mod tests {
     use super::*;
     use std::convert::TryInto;
-    use cros_async::complete2;
+    use cros_async::{complete2, run_one};
     use futures::pin_mut;
     use futures::io::{AsyncReadExt, AsyncWriteExt};
 
@@ -152,4 +339,107 @@ mod tests {
             panic!("wrong futures returned from complete2");
         }
     }
+
+    #[test]
+    fn datagram_send_to() {
+        let (s1, s2) = net::UnixDatagram::pair().unwrap();
+        let sock1: UnixDatagram = s1.try_into().unwrap();
+        let sock2: UnixDatagram = s2.try_into().unwrap();
+
+        async fn exchange(sock1: &UnixDatagram, sock2: &UnixDatagram) -> UnixAddr {
+            // Message boundaries must be preserved.
+            sock1.send(b"foo").await.unwrap();
+            sock1.send(b"").await.unwrap();
+
+            let mut buf = [0u8; 16];
+            let (count, addr) = sock2.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..count], b"foo");
+            assert_eq!(sock2.recv(&mut buf).await.unwrap(), 0);
+
+            addr
+        }
+
+        let fut = exchange(&sock1, &sock2);
+        pin_mut!(fut);
+        let addr = run_one(fut).unwrap();
+
+        // Socket pairs are unnamed.
+        assert!(addr.is_unnamed());
+    }
+
+    #[test]
+    fn datagram_recv_cred() {
+        let (s1, s2) = net::UnixDatagram::pair().unwrap();
+        let sock1: UnixDatagram = s1.try_into().unwrap();
+        let sock2: UnixDatagram = s2.try_into().unwrap();
+        sock2.set_pass_cred().unwrap();
+
+        async fn exchange(sock1: &UnixDatagram, sock2: &UnixDatagram) -> (UnixAddr, libc::pid_t) {
+            sock1.send(b"foo").await.unwrap();
+
+            let mut buf = [0u8; 16];
+            let (count, addr, pid) = sock2.recv_from_cred(&mut buf).await.unwrap();
+            assert_eq!(&buf[..count], b"foo");
+
+            (addr, pid)
+        }
+
+        let fut = exchange(&sock1, &sock2);
+        pin_mut!(fut);
+        let (addr, pid) = run_one(fut).unwrap();
+
+        assert!(addr.is_unnamed());
+        assert_eq!(pid as u32, std::process::id());
+    }
+
+    #[test]
+    fn abstract_addr() {
+        let addr = UnixAddr::new_abstract(b"foo").unwrap();
+        assert!(!addr.is_unnamed());
+        assert_eq!(addr.to_string(), "@foo");
+        assert_eq!(addr, UnixAddr::new_abstract(b"foo").unwrap());
+        assert_ne!(addr, UnixAddr::new_path("foo").unwrap());
+        assert_eq!(UnixAddr::new_path("/run/foo").unwrap().to_string(), "/run/foo");
+    }
+
+    #[test]
+    fn listener_connect() {
+        let name = format!("vsh-async-unix-test-{}", std::process::id());
+        let addr = UnixAddr::new_abstract(name.as_bytes()).unwrap();
+
+        // Safe because the fd was just created and nothing else owns it.
+        let listener = unsafe {
+            net::UnixListener::from_raw_fd(
+                nonblocking_socket(libc::AF_UNIX, libc::SOCK_STREAM).unwrap(),
+            )
+        };
+        let (sockaddr, len) = addr.as_sockaddr();
+        // Safe because sockaddr is a valid address of len bytes.
+        unsafe {
+            assert_eq!(libc::bind(listener.as_raw_fd(), sockaddr, len), 0);
+            assert_eq!(libc::listen(listener.as_raw_fd(), 1), 0);
+        }
+        let listener: UnixListener = listener.try_into().unwrap();
+
+        async fn accept(listener: &UnixListener) -> String {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = String::new();
+            stream.read_to_string(&mut buf).await.unwrap();
+            buf
+        }
+
+        async fn connect(addr: &UnixAddr) {
+            let mut stream = UnixStream::connect(addr).await.unwrap();
+            stream.write_all(b"foo").await.unwrap();
+            stream.close().await.unwrap();
+        }
+
+        let a = accept(&listener);
+        pin_mut!(a);
+        let c = connect(&addr);
+        pin_mut!(c);
+
+        let (s, ()) = complete2(a, c).unwrap();
+        assert_eq!(s, "foo");
+    }
 }
@@ -2,109 +2,275 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-use std::convert::TryFrom;
-use std::fmt::{self, Display};
-use std::io::{ErrorKind, Read, Write};
-use std::os::unix::io::AsRawFd;
-use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::mem;
+use std::os::raw::c_uint;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 
-use futures::io::{AsyncRead, AsyncWrite, Error as IoError, ErrorKind as IoErrorKind};
+use futures::io::Error as IoError;
 
 use libchromeos::vsock;
 
-use cros_async::fd_executor::{add_read_waker, add_write_waker};
+use crate::async_core::{connect, nonblocking_socket, AsyncFd, LISTEN_BACKLOG};
 
-/// Errors generated while polling for signals.
-#[derive(Debug)]
-pub enum Error {
-    /// An error occurred while setting the vsock stream as nonblocking.
-    SetNonblocking(std::io::Error),
+pub use crate::async_core::{Error, Result};
+
+pub type VsockStream = AsyncFd<vsock::VsockStream>;
+pub type VsockListener = AsyncFd<vsock::VsockListener>;
+
+async_fd_try_from!(vsock::VsockStream, vsock::VsockListener);
+
+impl AsyncFd<vsock::VsockStream> {
+    /// Connects to the given cid and port without blocking the executor.
+    pub async fn connect(cid: c_uint, port: c_uint) -> std::result::Result<VsockStream, IoError> {
+        let fd = AsyncFd::new(SocketFd::new(libc::SOCK_STREAM)?)?;
+        connect_vsock(&fd, cid, port).await?;
+
+        let fd = fd.into_inner().into_raw_fd();
+        // Safe because the fd is a connected vsock stream socket that nothing else owns.
+        let stream = unsafe { vsock::VsockStream::from_raw_fd(fd) };
+
+        Ok(AsyncFd::new(stream)?)
+    }
 }
 
-pub type Result<T> = std::result::Result<T, Error>;
+impl AsyncFd<vsock::VsockListener> {
+    /// Waits for a new connection, returning it as a nonblocking stream along
+    /// with the address of the peer.
+    pub async fn accept(&self) -> std::result::Result<(VsockStream, vsock::SocketAddr), IoError> {
+        let (fd, addr) = self.read_with(|l| accept_nonblocking(l.as_raw_fd())).await?;
+
+        // Safe because the fd is a connected vsock stream socket that nothing else owns.
+        let stream = unsafe { vsock::VsockStream::from_raw_fd(fd.into_raw_fd()) };
+        let addr = vsock::SocketAddr {
+            cid: vsock::VsockCid::from(addr.svm_cid),
+            port: addr.svm_port,
+        };
 
-impl std::error::Error for Error {}
+        Ok((AsyncFd::new(stream)?, addr))
+    }
+}
 
-impl Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use self::Error::*;
+/// Owns a socket fd, closing it when dropped.
+struct SocketFd(RawFd);
 
-        match self {
-            SetNonblocking(e) => write!(
-                f,
-                "An error occurred while setting the vsock stream as nonblocking: {}.",
-                e
-            ),
-        }
+impl AsRawFd for SocketFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
     }
 }
 
-pub struct VsockStream {
-    inner: vsock::VsockStream,
+impl IntoRawFd for SocketFd {
+    fn into_raw_fd(self) -> RawFd {
+        let fd = self.0;
+        mem::forget(self);
+        fd
+    }
 }
 
-impl TryFrom<vsock::VsockStream> for VsockStream {
-    type Error = crate::async_core::vsock::Error;
+impl SocketFd {
+    /// Creates a new nonblocking vsock socket of the given type.
+    fn new(kind: libc::c_int) -> std::result::Result<SocketFd, IoError> {
+        nonblocking_socket(libc::AF_VSOCK, kind).map(SocketFd)
+    }
+}
 
-    fn try_from(mut vsock_stream: vsock::VsockStream) -> Result<VsockStream> {
-        vsock_stream.set_nonblocking(true).map_err(Error::SetNonblocking)?;
-        Ok(VsockStream {
-            inner: vsock_stream,
-        })
+impl Drop for SocketFd {
+    fn drop(&mut self) {
+        // Safe because this struct owns the fd. Nothing useful can be done
+        // if close fails.
+        unsafe { libc::close(self.0) };
     }
 }
 
-impl AsyncRead for VsockStream {
-    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<std::result::Result<usize, IoError>> {
-        let res = self.inner.read(buf);
+/// Accepts a connection on a listening socket, returning a nonblocking fd and
+/// the address of the peer.
+fn accept_nonblocking(fd: RawFd) -> std::result::Result<(SocketFd, libc::sockaddr_vm), IoError> {
+    // Safe because sockaddr_vm is a plain C struct for which all zeroes is a
+    // valid value.
+    let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t;
 
-        match res {
-            Ok(n) => Poll::Ready(Ok(n)),
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                match add_read_waker(self.inner.as_raw_fd(), cx.waker().clone()) {
-                    Ok(_) => Poll::Pending,
-                    Err(_) => {
-                        // TODO(smbarber): convert fd_executor::Error here.
-                        Poll::Ready(Err(IoError::new(IoErrorKind::Other, "failed to add read waker")))
-                    },
-                }
-            },
-            Err(e) => Poll::Ready(Err(e)),
-        }
+    // Safe because accept4 writes at most len bytes to addr, and the return
+    // value is checked.
+    let fd = unsafe {
+        libc::accept4(
+            fd,
+            &mut addr as *mut libc::sockaddr_vm as *mut libc::sockaddr,
+            &mut len,
+            libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(IoError::last_os_error());
     }
+
+    Ok((SocketFd(fd), addr))
+}
+
+async fn connect_vsock(sock: &AsyncFd<SocketFd>, cid: c_uint, port: c_uint) -> std::result::Result<(), IoError> {
+    let addr = vsock_addr(cid, port);
+    connect(
+        sock,
+        &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+        mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+    )
+    .await
+}
+
+fn vsock_addr(cid: c_uint, port: c_uint) -> libc::sockaddr_vm {
+    // Safe because sockaddr_vm is a plain C struct for which all zeroes is a
+    // valid value.
+    let mut addr: libc::sockaddr_vm = unsafe { mem::zeroed() };
+    addr.svm_family = libc::AF_VSOCK as libc::sa_family_t;
+    addr.svm_cid = cid;
+    addr.svm_port = port;
+    addr
+}
+
+/// A connected vsock SOCK_SEQPACKET socket, which preserves message boundaries.
+///
+/// virtio-vsock doesn't implement SOCK_DGRAM, so seqpacket is the only way to
+/// pass datagrams over vsock intact.
+pub struct VsockSeqpacket {
+    fd: AsyncFd<SocketFd>,
 }
 
-impl AsyncWrite for VsockStream {
-    fn poll_write(
-            mut self: Pin<&mut Self>,
-            cx: &mut Context,
-            buf: &[u8],
-            ) -> Poll<std::result::Result<usize, IoError>> {
-        let res = self.inner.write(buf);
-
-        match res {
-            Ok(n) => Poll::Ready(Ok(n)),
-            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
-                match add_write_waker(self.inner.as_raw_fd(), cx.waker().clone()) {
-                    Ok(_) => Poll::Pending,
-                    Err(_) => {
-                        // TODO(smbarber): convert fd_executor::Error here.
-                        Poll::Ready(Err(IoError::new(IoErrorKind::Other, "failed to add write waker")))
-                    },
+impl VsockSeqpacket {
+    /// Connects to the given cid and port without blocking the executor.
+    pub async fn connect(cid: c_uint, port: c_uint) -> std::result::Result<VsockSeqpacket, IoError> {
+        let fd = AsyncFd::new(SocketFd::new(libc::SOCK_SEQPACKET)?)?;
+        connect_vsock(&fd, cid, port).await?;
+
+        Ok(VsockSeqpacket { fd })
+    }
+
+    /// Receives a single message. Returns None once the peer has shut down.
+    pub async fn recv(&self, buf: &mut [u8]) -> std::result::Result<Option<usize>, IoError> {
+        self.fd
+            .read_with(|fd| {
+                // Safe because recv writes at most buf.len() bytes to buf and the
+                // return value is checked.
+                let ret = unsafe { libc::recv(fd.0, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+                if ret < 0 {
+                    return Err(IoError::last_os_error());
                 }
-            },
-            Err(e) => Poll::Ready(Err(e)),
+
+                // recv returns 0 both for an empty message and once the peer
+                // has shut down.
+                if ret == 0 && peer_shut_down(fd.0)? {
+                    return Ok(None);
+                }
+
+                Ok(Some(ret as usize))
+            })
+            .await
+    }
+
+    /// Sends a single message.
+    pub async fn send(&self, buf: &[u8]) -> std::result::Result<usize, IoError> {
+        self.fd
+            .write_with(|fd| {
+                // Safe because send reads at most buf.len() bytes from buf and the
+                // return value is checked.
+                let ret = unsafe {
+                    libc::send(fd.0, buf.as_ptr() as *const libc::c_void, buf.len(), libc::MSG_NOSIGNAL)
+                };
+                if ret < 0 {
+                    return Err(IoError::last_os_error());
+                }
+
+                Ok(ret as usize)
+            })
+            .await
+    }
+}
+
+impl AsRawFd for VsockSeqpacket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// Returns true if the peer of the connected socket `fd` has shut down writes.
+fn peer_shut_down(fd: RawFd) -> std::result::Result<bool, IoError> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLRDHUP,
+        revents: 0,
+    };
+
+    // Safe because poll only modifies the one pollfd it is given, it doesn't
+    // block with a zero timeout, and the return value is checked.
+    let ret = unsafe { libc::poll(&mut pollfd, 1, 0) };
+    if ret < 0 {
+        return Err(IoError::last_os_error());
+    }
+
+    Ok(pollfd.revents & (libc::POLLRDHUP | libc::POLLHUP) != 0)
+}
+
+/// A vsock SOCK_SEQPACKET socket listening for connections on any cid.
+pub struct VsockSeqpacketListener {
+    fd: AsyncFd<SocketFd>,
+}
+
+impl VsockSeqpacketListener {
+    /// Binds to the given port and starts listening.
+    pub fn bind(port: c_uint) -> std::result::Result<VsockSeqpacketListener, IoError> {
+        let fd = SocketFd::new(libc::SOCK_SEQPACKET)?;
+        let addr = vsock_addr(libc::VMADDR_CID_ANY, port);
+
+        // Safe because addr is a valid sockaddr_vm and the return values are checked.
+        unsafe {
+            if libc::bind(
+                fd.0,
+                &addr as *const libc::sockaddr_vm as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_vm>() as libc::socklen_t,
+            ) < 0
+            {
+                return Err(IoError::last_os_error());
+            }
+            if libc::listen(fd.0, LISTEN_BACKLOG) < 0 {
+                return Err(IoError::last_os_error());
+            }
         }
+
+        Ok(VsockSeqpacketListener { fd: AsyncFd::new(fd)? })
     }
 
-    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<std::result::Result<(), IoError>> {
-        // Sockets don't need to flush writes.
-        Poll::Ready(Ok(()))
+    /// Waits for a new connection, returning it as a nonblocking socket.
+    pub async fn accept(&self) -> std::result::Result<VsockSeqpacket, IoError> {
+        let (fd, _) = self.fd.read_with(|fd| accept_nonblocking(fd.0)).await?;
+
+        Ok(VsockSeqpacket { fd: AsyncFd::new(fd)? })
     }
+}
+
+impl AsRawFd for VsockSeqpacketListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_message_is_not_shutdown() {
+        let mut fds = [0; 2];
+        // Safe because socketpair writes at most two fds to fds and the return
+        // value is checked.
+        let ret = unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0, fds.as_mut_ptr()) };
+        assert_eq!(ret, 0);
+        let (sock1, sock2) = (SocketFd(fds[0]), SocketFd(fds[1]));
+
+        // Safe because send reads no bytes from the empty buffer.
+        assert_eq!(unsafe { libc::send(sock1.0, [].as_ptr(), 0, 0) }, 0);
+        assert!(!peer_shut_down(sock2.0).unwrap());
 
-    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<std::result::Result<(), IoError>> {
-        // We don't expect closing to block. Closing is performed when the socket is dropped.
-        self.poll_flush(cx)
+        // Safe because shutdown modifies no memory.
+        assert_eq!(unsafe { libc::shutdown(sock1.0, libc::SHUT_WR) }, 0);
+        assert!(peer_shut_down(sock2.0).unwrap());
     }
 }
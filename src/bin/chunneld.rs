@@ -0,0 +1,229 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Forwards TCP ports on the host's loopback interface into VMs over vsock.
+//!
+//! The set of ports is controlled over D-Bus with chunneld's
+//! UpdateListeningPorts method. Each connection to 127.0.0.1:PORT is forwarded
+//! to vsock port PORT of the target VM, where a forwarder such as
+//! `vsh --local vsock:any:PORT --remote tcp:localhost:PORT` passes it on.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::env;
+use std::ffi::CStr;
+use std::fmt;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::process;
+use std::rc::Rc;
+use std::result;
+use std::time::Duration;
+
+use cros_async::run_one;
+use dbus::blocking::LocalConnection;
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::MatchRule;
+use dbus::Message;
+use futures::pin_mut;
+use getopts::Options;
+use libchromeos::syslog;
+use log::{error, info, warn};
+use protobuf::Message as ProtoMessage;
+use sys_util::{self, block_signal};
+use system_api::chunneld_service::{
+    UpdateListeningPortsRequest, UpdateListeningPortsResponse, UpdateListeningPortsResponse_Status,
+};
+use vsh::async_core::{self, AsyncFd};
+use vsh::port_forwarder::PortForwarder;
+use vsh::sockaddr::SocketAddr;
+
+// Program name.
+const IDENT: &[u8] = b"chunneld\0";
+
+const CHUNNELD_SERVICE_NAME: &str = "org.chromium.Chunneld";
+const CHUNNELD_SERVICE_PATH: &str = "/org/chromium/Chunneld";
+const CHUNNELD_INTERFACE: &str = "org.chromium.Chunneld";
+const UPDATE_LISTENING_PORTS_METHOD: &str = "UpdateListeningPorts";
+
+// Address that forwarded ports are listened on.
+const LISTEN_HOST: &str = "127.0.0.1";
+
+#[remain::sorted]
+#[derive(Debug)]
+enum Error {
+    BlockSigpipe(sys_util::signal::Error),
+    ConnectDbus(dbus::Error),
+    ProcessDbus(io::Error),
+    RequestName(dbus::Error),
+    RunExecutor(cros_async::Error),
+    Syslog(log::SetLoggerError),
+    WatchDbus(async_core::Error),
+}
+
+type Result<T> = result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    #[remain::check]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Error::*;
+
+        #[remain::sorted]
+        match self {
+            BlockSigpipe(e) => write!(f, "failed to block SIGPIPE: {}", e),
+            ConnectDbus(e) => write!(f, "failed to connect to system bus: {}", e),
+            ProcessDbus(e) => write!(f, "failed to process D-Bus messages: {}", e),
+            RequestName(e) => write!(f, "failed to request D-Bus service name: {}", e),
+            RunExecutor(e) => write!(f, "failed to run executor: {}", e),
+            Syslog(e) => write!(f, "failed to initialize syslog: {}", e),
+            WatchDbus(e) => write!(f, "failed to watch D-Bus connection: {}", e),
+        }
+    }
+}
+
+/// The fd of a D-Bus connection, which remains owned by the connection.
+struct DbusFd(RawFd);
+
+impl AsRawFd for DbusFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+/// Converts an UpdateListeningPortsRequest into the forwards for a
+/// PortForwarder. Ports that don't fit in a u16 are skipped.
+fn requested_forwards(req: &UpdateListeningPortsRequest) -> HashMap<SocketAddr, SocketAddr> {
+    let mut forwards = HashMap::new();
+    for (&port, target) in req.get_tcp4_forward_targets() {
+        let tcp_port = match u16::try_from(port) {
+            Ok(p) => p,
+            Err(_) => {
+                warn!("ignoring invalid port {}", port);
+                continue;
+            }
+        };
+
+        let local = SocketAddr::Tcp {
+            host: LISTEN_HOST.to_string(),
+            port: tcp_port,
+        };
+        let remote = SocketAddr::Vsock {
+            cid: target.get_vsock_cid(),
+            port,
+        };
+        forwards.insert(local, remote);
+    }
+
+    forwards
+}
+
+/// Handles a call to UpdateListeningPorts, returning the response.
+fn update_listening_ports(
+    forwarder: &mut PortForwarder,
+    msg: &Message,
+) -> UpdateListeningPortsResponse {
+    let mut resp = UpdateListeningPortsResponse::new();
+    resp.set_status(UpdateListeningPortsResponse_Status::FAILED);
+
+    let req_bytes: Vec<u8> = match msg.read1() {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            warn!("failed to read UpdateListeningPorts argument: {}", e);
+            return resp;
+        }
+    };
+    let mut req = UpdateListeningPortsRequest::new();
+    if let Err(e) = req.merge_from_bytes(&req_bytes) {
+        warn!("failed to parse UpdateListeningPortsRequest: {}", e);
+        return resp;
+    }
+
+    // The forwarder logs each port that failed.
+    if forwarder.update(requested_forwards(&req)).is_ok() {
+        resp.set_status(UpdateListeningPortsResponse_Status::SUCCESS);
+    }
+
+    resp
+}
+
+/// Dispatches D-Bus messages as they arrive on the current executor. Only
+/// returns on error.
+async fn serve_dbus(conn: &LocalConnection) -> Result<()> {
+    let fd = AsyncFd::new(DbusFd(conn.channel().watch().fd)).map_err(Error::WatchDbus)?;
+
+    loop {
+        fd.read_with(|_| match conn.process(Duration::from_millis(0)) {
+            Ok(true) => Ok(()),
+            // Nothing is queued, so wait for the connection to be readable.
+            Ok(false) => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+            Err(e) => Err(io::Error::new(io::ErrorKind::Other, e.to_string())),
+        })
+        .await
+        .map_err(Error::ProcessDbus)?;
+    }
+}
+
+fn print_usage(program: &str, opts: &Options) {
+    let brief = format!("Usage: {} [options]", program);
+    print!("{}", opts.usage(&brief));
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("failed to parse arg: {}", e);
+            print_usage(&program, &opts);
+            process::exit(1);
+        }
+    };
+    if matches.opt_present("h") {
+        print_usage(&program, &opts);
+        return Ok(());
+    }
+
+    // Safe because this string is defined above in this file and it contains exactly
+    // one nul byte, which appears at the end.
+    let ident = CStr::from_bytes_with_nul(IDENT).unwrap();
+    syslog::init(ident).map_err(Error::Syslog)?;
+
+    // Block SIGPIPE so the process doesn't exit when writing to a socket that's been shutdown.
+    block_signal(libc::SIGPIPE).map_err(Error::BlockSigpipe)?;
+
+    let conn = LocalConnection::new_system().map_err(Error::ConnectDbus)?;
+    conn.request_name(CHUNNELD_SERVICE_NAME, false, true, false)
+        .map_err(Error::RequestName)?;
+
+    let forwarder = Rc::new(RefCell::new(PortForwarder::new()));
+    conn.start_receive(
+        MatchRule::new_method_call()
+            .with_path(CHUNNELD_SERVICE_PATH)
+            .with_interface(CHUNNELD_INTERFACE)
+            .with_member(UPDATE_LISTENING_PORTS_METHOD),
+        Box::new(move |msg, conn| {
+            let resp = update_listening_ports(&mut forwarder.borrow_mut(), &msg);
+            match resp.write_to_bytes() {
+                Ok(resp_bytes) => {
+                    if conn.send(msg.method_return().append1(resp_bytes)).is_err() {
+                        error!("failed to send UpdateListeningPorts response");
+                    }
+                }
+                Err(e) => error!("failed to serialize UpdateListeningPortsResponse: {}", e),
+            }
+            true
+        }),
+    );
+    info!("serving {}", CHUNNELD_SERVICE_NAME);
+
+    let server = serve_dbus(&conn);
+    pin_mut!(server);
+    run_one(server).map_err(Error::RunExecutor)?
+}
@@ -5,22 +5,95 @@
 use std::env;
 use std::ffi::CStr;
 use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::raw::c_uint;
+use std::os::unix::io::{FromRawFd, RawFd};
 use std::process;
 use std::result;
 
+use cros_async::fd_executor::{self, add_future};
+use cros_async::run_one;
+use dbus::blocking::Connection;
+use futures::future::Future;
+use futures::pin_mut;
 use getopts::Options;
 use libchromeos::syslog;
-use log::warn;
-use sys_util::{self, block_signal};
+use libchromeos::vsock::{VsockCid, VsockStream};
+use log::{error, info, warn};
+use sys_util::{self, block_signal, PollContext, PollToken, SignalFd};
+use vsh::async_core::vsock::VsockSeqpacket;
+use vsh::env_policy::{select_env, DEFAULT_ENV_PATTERNS};
+use vsh::forwarder::{forward_datagrams, forward_messages, ForwarderError, ForwarderSession};
+use vsh::pty;
+use vsh::signal;
+use vsh::sockaddr::{AsyncDatagram, AsyncStream, DatagramListener, SockAddrError, SocketAddr};
+use vsh::termios::{self, Termios};
+use vsh::vm_lookup::{self, LookupError};
+use vsh::vsh_wire::{
+    local_capabilities, Negotiated, VshWire, VshWireError, PROTOCOL_VERSION, VM_SHELL_TARGET,
+    VSH_PORT,
+};
+use vsh_proto::vsh::{
+    ConnectionStatus, GuestMessage, HostMessage, HostMessage_oneof_msg, SetupConnectionRequest,
+    SetupConnectionResponse, StdioStream,
+};
 
 // Program name.
 const IDENT: &[u8] = b"vsh\0";
 
+// Environment variable holding the cryptohome id of the logged in user, used
+// when --owner_id isn't given.
+const OWNER_ID_ENV: &str = "CROS_USER_ID_HASH";
+
+// Signals that cause the client to close the connection and exit. In nopty
+// mode these are forwarded to the target program instead.
+const TERMINATION_SIGNALS: &[libc::c_int] =
+    &[libc::SIGHUP, libc::SIGINT, libc::SIGQUIT, libc::SIGTERM];
+
+// Signals that are always forwarded to the target program. SIGWINCH is
+// forwarded in nopty mode, while SIGKILL and SIGSTOP can't be caught.
+const FORWARDED_SIGNALS: &[libc::c_int] = &[libc::SIGUSR1, libc::SIGUSR2, libc::SIGCONT];
+
 #[remain::sorted]
 #[derive(Debug)]
 enum Error {
+    AddFuture(fd_executor::Error),
     BlockSigpipe(sys_util::signal::Error),
+    ConnectDbus(dbus::Error),
+    ConnectRemote(io::Error),
+    ConnectVsock(io::Error),
+    CreatePollContext(sys_util::Error),
+    CreateSignalFd(sys_util::signalfd::Error),
+    DupStdin(io::Error),
+    Forward(ForwarderError),
+    GetTermios(io::Error),
+    GetWindowSize(io::Error),
+    InvalidCid(String),
+    InvalidPort(String),
+    InvalidSocketAddr(SockAddrError),
+    InvalidType(String),
+    Listen(io::Error),
+    LookupContainer(LookupError),
+    LookupVm(LookupError),
+    MissingCid,
+    MissingForwardAddress,
+    MissingOwnerId,
+    PollAdd(sys_util::Error),
+    PollDelete(sys_util::Error),
+    PollWait(sys_util::Error),
+    ReadSignalFd(sys_util::signalfd::Error),
+    ReadStdin(io::Error),
+    ReceiveHostMessage(VshWireError),
+    ReceiveSetupResponse(VshWireError),
+    RemoteFailed(String),
+    RunExecutor(cros_async::Error),
+    SendGuestMessage(VshWireError),
+    SendSetupRequest(VshWireError),
+    SetRawMode(io::Error),
+    SetupFailed(String),
     Syslog(log::SetLoggerError),
+    WriteOutput(io::Error),
 }
 
 type Result<T> = result::Result<T, Error>;
@@ -32,26 +105,470 @@ impl fmt::Display for Error {
 
         #[remain::sorted]
         match self {
+            AddFuture(e) => write!(f, "failed to add future to executor: {}", e),
             BlockSigpipe(e) => write!(f, "failed to block SIGPIPE: {}", e),
+            ConnectDbus(e) => write!(f, "failed to connect to system bus: {}", e),
+            ConnectRemote(e) => write!(f, "failed to connect to remote socket: {}", e),
+            ConnectVsock(e) => write!(f, "failed to connect to vshd: {}", e),
+            CreatePollContext(e) => write!(f, "failed to create poll context: {}", e),
+            CreateSignalFd(e) => write!(f, "failed to create signalfd: {}", e),
+            DupStdin(e) => write!(f, "failed to duplicate stdin: {}", e),
+            Forward(e) => write!(f, "failed to forward: {}", e),
+            GetTermios(e) => write!(f, "failed to get terminal modes: {}", e),
+            GetWindowSize(e) => write!(f, "failed to get window size: {}", e),
+            InvalidCid(c) => write!(f, "invalid cid: {}", c),
+            InvalidPort(p) => write!(f, "invalid port: {}", p),
+            InvalidSocketAddr(e) => write!(f, "invalid socket address: {}", e),
+            InvalidType(t) => write!(f, "invalid forwarding type: {}", t),
+            Listen(e) => write!(f, "failed to listen on local socket: {}", e),
+            LookupContainer(e) => write!(f, "failed to look up container: {}", e),
+            LookupVm(e) => write!(f, "failed to look up VM: {}", e),
+            MissingCid => write!(f, "either --cid or --vm_name must be specified"),
+            MissingForwardAddress => write!(f, "both --local and --remote must be specified"),
+            MissingOwnerId => write!(f, "--owner_id or {} must be set", OWNER_ID_ENV),
+            PollAdd(e) => write!(f, "failed to add fd to poll context: {}", e),
+            PollDelete(e) => write!(f, "failed to delete fd from poll context: {}", e),
+            PollWait(e) => write!(f, "failed to wait for poll events: {}", e),
+            ReadSignalFd(e) => write!(f, "failed to read signalfd: {}", e),
+            ReadStdin(e) => write!(f, "failed to read from stdin: {}", e),
+            ReceiveHostMessage(e) => write!(f, "failed to receive host message: {}", e),
+            ReceiveSetupResponse(e) => write!(f, "failed to receive setup response: {}", e),
+            RemoteFailed(d) => write!(f, "remote connection failed: {}", d),
+            RunExecutor(e) => write!(f, "failed to run executor: {}", e),
+            SendGuestMessage(e) => write!(f, "failed to send guest message: {}", e),
+            SendSetupRequest(e) => write!(f, "failed to send setup request: {}", e),
+            SetRawMode(e) => write!(f, "failed to set terminal to raw mode: {}", e),
+            SetupFailed(d) => write!(f, "failed to set up connection: {}", d),
             Syslog(e) => write!(f, "failed to initialize syslog: {}", e),
+            WriteOutput(e) => write!(f, "failed to write output: {}", e),
+        }
+    }
+}
+
+/// Returns true if the fd refers to a terminal.
+fn is_tty(fd: RawFd) -> bool {
+    // Safe because isatty modifies no memory.
+    unsafe { libc::isatty(fd) == 1 }
+}
+
+/// Options for an interactive or scripted connection to vshd.
+struct ShellOptions {
+    cid: c_uint,
+    port: c_uint,
+    target: String,
+    user: String,
+    nopty: bool,
+    send_env: Vec<String>,
+    argv: Vec<String>,
+}
+
+fn send_setup_request(wire: &mut VshWire<VsockStream>, opts: &ShellOptions) -> Result<()> {
+    let mut req = SetupConnectionRequest::new();
+    req.set_target(opts.target.clone());
+    req.set_user(opts.user.clone());
+    req.set_nopty(opts.nopty);
+    req.set_argv(opts.argv.clone().into());
+    req.set_protocol_version(PROTOCOL_VERSION);
+    req.set_capabilities(local_capabilities());
+
+    // Variables that aren't valid unicode can't be sent in the request.
+    let vars = env::vars_os()
+        .filter_map(|(key, val)| Some((key.into_string().ok()?, val.into_string().ok()?)));
+    req.set_env(select_env(vars, &opts.send_env));
+
+    if !opts.nopty && is_tty(libc::STDOUT_FILENO) {
+        let dims = pty::get_dimensions(&io::stdout()).map_err(Error::GetWindowSize)?;
+        req.set_window_rows(i32::from(dims.rows));
+        req.set_window_cols(i32::from(dims.cols));
+        req.set_window_width_px(i32::from(dims.width_px));
+        req.set_window_height_px(i32::from(dims.height_px));
+    }
+
+    // Forward the local terminal's modes so that keys like erase behave the
+    // same way in the remote pty. This must happen before stdin is made raw.
+    if !opts.nopty && is_tty(libc::STDIN_FILENO) {
+        let termios = Termios::get(&io::stdin()).map_err(Error::GetTermios)?;
+        req.set_terminal_modes(termios.to_modes());
+    }
+
+    wire.send_message(&req).map_err(Error::SendSetupRequest)
+}
+
+/// Waits for the server to accept the connection, then switches the wire to
+/// the protocol parameters negotiated with the server.
+fn receive_setup_response(wire: &mut VshWire<VsockStream>) -> Result<()> {
+    let mut resp = SetupConnectionResponse::new();
+    wire.receive_message(&mut resp)
+        .map_err(Error::ReceiveSetupResponse)?;
+
+    if resp.get_status() != ConnectionStatus::READY {
+        return Err(Error::SetupFailed(resp.get_description().to_string()));
+    }
+
+    let negotiated = Negotiated::with_peer(resp.get_protocol_version(), resp.get_capabilities());
+    wire.set_max_send_frame_size(negotiated.max_send_frame_size);
+    wire.set_max_receive_frame_size(negotiated.max_receive_frame_size);
+
+    Ok(())
+}
+
+/// Sends a chunk of stdin to the server. An empty chunk signals EOF.
+fn send_stdin(wire: &mut VshWire<VsockStream>, data: &[u8]) -> Result<()> {
+    wire.send_data::<GuestMessage>(StdioStream::STDIN_STREAM, data)
+        .map_err(Error::SendGuestMessage)
+}
+
+/// Tells the server that the client is going away.
+fn send_client_exited(wire: &mut VshWire<VsockStream>) -> Result<()> {
+    let mut guest_msg = GuestMessage::new();
+    let status_msg = guest_msg.mut_status_message();
+    status_msg.set_status(ConnectionStatus::EXITED);
+    status_msg.set_description("client exited".to_string());
+
+    wire.send_message(&guest_msg)
+        .map_err(Error::SendGuestMessage)
+}
+
+/// Forwards a signal to the target program.
+fn send_signal(wire: &mut VshWire<VsockStream>, signo: libc::c_int) -> Result<()> {
+    let signal = match signal::to_proto(signo) {
+        Some(signal) => signal,
+        None => {
+            warn!("not forwarding unsupported signal {}", signo);
+            return Ok(());
+        }
+    };
+
+    let mut guest_msg = GuestMessage::new();
+    guest_msg.set_signal(signal);
+
+    wire.send_message(&guest_msg)
+        .map_err(Error::SendGuestMessage)
+}
+
+fn send_window_resize(wire: &mut VshWire<VsockStream>) -> Result<()> {
+    let dims = pty::get_dimensions(&io::stdout()).map_err(Error::GetWindowSize)?;
+
+    let mut guest_msg = GuestMessage::new();
+    let resize_msg = guest_msg.mut_resize_message();
+    resize_msg.set_rows(i32::from(dims.rows));
+    resize_msg.set_cols(i32::from(dims.cols));
+    resize_msg.set_width_px(i32::from(dims.width_px));
+    resize_msg.set_height_px(i32::from(dims.height_px));
+
+    wire.send_message(&guest_msg)
+        .map_err(Error::SendGuestMessage)
+}
+
+/// Handles one message from the server. Returns the remote exit code once the
+/// target program has exited.
+fn handle_host_message(wire: &mut VshWire<VsockStream>) -> Result<Option<i32>> {
+    let mut host_msg = HostMessage::new();
+    wire.receive_message(&mut host_msg)
+        .map_err(Error::ReceiveHostMessage)?;
+
+    match host_msg.msg {
+        Some(HostMessage_oneof_msg::data_message(data_msg)) => {
+            let data = data_msg.get_data();
+            match data_msg.get_stream() {
+                StdioStream::STDOUT_STREAM => {
+                    let mut stdout = io::stdout();
+                    stdout.write_all(data).map_err(Error::WriteOutput)?;
+                    stdout.flush().map_err(Error::WriteOutput)?;
+                }
+                StdioStream::STDERR_STREAM => {
+                    io::stderr().write_all(data).map_err(Error::WriteOutput)?;
+                }
+                stream => warn!("ignoring data for invalid stream: {:?}", stream),
+            }
+        }
+        Some(HostMessage_oneof_msg::status_message(status_msg)) => match status_msg.get_status() {
+            ConnectionStatus::READY => {}
+            ConnectionStatus::EXITED => return Ok(Some(status_msg.get_code())),
+            _ => {
+                return Err(Error::RemoteFailed(
+                    status_msg.get_description().to_string(),
+                ))
+            }
+        },
+        None => warn!("received empty host message"),
+    }
+
+    Ok(None)
+}
+
+#[derive(PollToken)]
+enum Token {
+    Forward,
+    Socket,
+    Stdin,
+    Terminate,
+    WindowResize,
+}
+
+/// Forwards stdio between the local terminal and the server until the remote
+/// target program exits. Returns the exit code of the target program.
+fn forward_stdio(wire: &mut VshWire<VsockStream>, opts: &ShellOptions) -> Result<i32> {
+    // Read stdin without going through the std buffering, which would hide
+    // pending input from poll.
+    // Safe because the dup'd fd is immediately owned by the File.
+    let mut stdin = unsafe {
+        let fd = libc::fcntl(libc::STDIN_FILENO, libc::F_DUPFD_CLOEXEC, 0);
+        if fd < 0 {
+            return Err(Error::DupStdin(io::Error::last_os_error()));
+        }
+        File::from_raw_fd(fd)
+    };
+
+    let poll_ctx: PollContext<Token> =
+        PollContext::build_with(&[(wire, Token::Socket), (&stdin, Token::Stdin)])
+            .map_err(Error::CreatePollContext)?;
+
+    let mut terminate_fds = Vec::new();
+    for signal in TERMINATION_SIGNALS {
+        let signal_fd = SignalFd::new(*signal).map_err(Error::CreateSignalFd)?;
+        poll_ctx
+            .add(&signal_fd, Token::Terminate)
+            .map_err(Error::PollAdd)?;
+        terminate_fds.push(signal_fd);
+    }
+
+    let mut forward_fds = Vec::new();
+    for signal in FORWARDED_SIGNALS {
+        let signal_fd = SignalFd::new(*signal).map_err(Error::CreateSignalFd)?;
+        poll_ctx
+            .add(&signal_fd, Token::Forward)
+            .map_err(Error::PollAdd)?;
+        forward_fds.push(signal_fd);
+    }
+
+    let winch_fd = SignalFd::new(libc::SIGWINCH).map_err(Error::CreateSignalFd)?;
+    poll_ctx
+        .add(&winch_fd, Token::WindowResize)
+        .map_err(Error::PollAdd)?;
+
+    let mut buf = vec![0u8; wire.max_data_size()];
+    loop {
+        let events = poll_ctx.wait().map_err(Error::PollWait)?;
+
+        for event in events.iter_readable().chain(events.iter_hungup()) {
+            match event.token() {
+                Token::Forward => {
+                    for signal_fd in &forward_fds {
+                        if let Some(siginfo) = signal_fd.read().map_err(Error::ReadSignalFd)? {
+                            send_signal(wire, siginfo.ssi_signo as i32)?;
+                        }
+                    }
+                }
+                Token::Socket => {
+                    if let Some(code) = handle_host_message(wire)? {
+                        return Ok(code);
+                    }
+                }
+                Token::Stdin => {
+                    let count = match stdin.read(&mut buf) {
+                        Ok(count) => count,
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => return Err(Error::ReadStdin(e)),
+                    };
+                    if count == 0 {
+                        poll_ctx.delete(&stdin).map_err(Error::PollDelete)?;
+                    }
+                    send_stdin(wire, &buf[..count])?;
+                }
+                Token::Terminate => {
+                    let mut signals = Vec::new();
+                    for signal_fd in &terminate_fds {
+                        if let Some(siginfo) = signal_fd.read().map_err(Error::ReadSignalFd)? {
+                            signals.push(siginfo.ssi_signo as i32);
+                        }
+                    }
+
+                    // Without a pty there's no line discipline on the remote
+                    // side to generate signals, so pass them along and let
+                    // the target decide whether to exit.
+                    if opts.nopty {
+                        for signo in signals {
+                            send_signal(wire, signo)?;
+                        }
+                        continue;
+                    }
+
+                    // The connection is going away anyway, so a failure to
+                    // notify the server isn't interesting.
+                    let _ = send_client_exited(wire);
+
+                    // Match the shell convention for reporting death by signal.
+                    return Ok(128 + signals.first().copied().unwrap_or(0));
+                }
+                Token::WindowResize => {
+                    winch_fd.read().map_err(Error::ReadSignalFd)?;
+
+                    // Without a pty there is no window size to pass along, so
+                    // pass the signal itself.
+                    if opts.nopty {
+                        send_signal(wire, libc::SIGWINCH)?;
+                    } else {
+                        send_window_resize(wire)?;
+                    }
+                }
+            }
         }
     }
 }
 
+/// Runs a shell or command on the remote side, returning its exit code.
+fn run_shell(opts: ShellOptions) -> Result<i32> {
+    let stream =
+        VsockStream::connect(VsockCid::from(opts.cid), opts.port).map_err(Error::ConnectVsock)?;
+    let mut wire = VshWire::new(stream);
+
+    send_setup_request(&mut wire, &opts)?;
+    receive_setup_response(&mut wire)?;
+
+    let _raw_terminal = if !opts.nopty && is_tty(libc::STDIN_FILENO) {
+        Some(termios::make_raw(&io::stdin()).map_err(Error::SetRawMode)?)
+    } else {
+        None
+    };
+
+    forward_stdio(&mut wire, &opts)
+}
+
+/// Forwards one accepted connection to the remote address until both sides
+/// have closed.
+async fn forward_connection(local: AsyncStream, remote: SocketAddr) -> Result<()> {
+    let remote = remote.connect().await.map_err(Error::ConnectRemote)?;
+
+    ForwarderSession::new(local, remote)
+        .run()
+        .await
+        .map_err(Error::Forward)
+}
+
+/// Forwards datagrams between one accepted seqpacket connection and the
+/// remote address until either side shuts down.
+async fn forward_seqpacket_connection(local: VsockSeqpacket, remote: SocketAddr) -> Result<()> {
+    let remote = remote
+        .connect_datagram()
+        .await
+        .map_err(Error::ConnectRemote)?;
+    let local = AsyncDatagram::Vsock(local);
+
+    forward_messages(&local, &remote)
+        .await
+        .map_err(Error::Forward)
+}
+
+/// Runs `forward` as its own task on the executor, logging any failure.
+fn spawn_connection<F>(remote: &SocketAddr, forward: F) -> Result<()>
+where
+    F: Future<Output = Result<()>> + 'static,
+{
+    let remote = remote.clone();
+    add_future(Box::pin(async move {
+        if let Err(e) = forward.await {
+            error!("forwarding to {} failed: {}", remote, e);
+        }
+    }))
+    .map_err(Error::AddFuture)
+}
+
+/// Listens on the local address and forwards each accepted connection to the
+/// remote address. Only returns on error.
+fn run_forwarder(local: SocketAddr, remote: SocketAddr) -> Result<i32> {
+    let listener = local.listen().map_err(Error::Listen)?;
+    info!("forwarding {} to {}", local, remote);
+
+    let accept_loop = async {
+        loop {
+            let stream = match listener.accept().await {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("failed to accept connection on {}: {}", local, e);
+                    continue;
+                }
+            };
+
+            let forward = forward_connection(stream, remote.clone());
+            if let Err(e) = spawn_connection(&remote, forward) {
+                error!("{}", e);
+            }
+        }
+    };
+    pin_mut!(accept_loop);
+    run_one(accept_loop).map_err(Error::RunExecutor)
+}
+
+/// Receives datagrams on the local address and forwards them to the remote
+/// address, preserving message boundaries. Only returns on error.
+fn run_datagram_forwarder(local: SocketAddr, remote: SocketAddr) -> Result<i32> {
+    let listener = local.listen_datagram().map_err(Error::Listen)?;
+    info!("forwarding datagrams from {} to {}", local, remote);
+
+    let listener = match listener {
+        DatagramListener::Unix(sock) => {
+            let forwarder = forward_datagrams(sock, remote);
+            pin_mut!(forwarder);
+            run_one(forwarder)
+                .map_err(Error::RunExecutor)?
+                .map_err(Error::Forward)?;
+            return Ok(0);
+        }
+        DatagramListener::Vsock(listener) => listener,
+    };
+
+    let accept_loop = async {
+        loop {
+            let conn = match listener.accept().await {
+                Ok(c) => c,
+                Err(e) => {
+                    error!("failed to accept connection on {}: {}", local, e);
+                    continue;
+                }
+            };
+
+            let forward = forward_seqpacket_connection(conn, remote.clone());
+            if let Err(e) = spawn_connection(&remote, forward) {
+                error!("{}", e);
+            }
+        }
+    };
+    pin_mut!(accept_loop);
+    run_one(accept_loop).map_err(Error::RunExecutor)
+}
+
 fn print_usage(program: &str, opts: &Options) {
-    let brief = format!("Usage: {} [options]", program);
+    let brief = format!("Usage: {} [options] [-- ARGV...]", program);
     print!("{}", opts.usage(&brief));
 }
 
-fn main() -> Result<()> {
+fn run() -> Result<i32> {
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
 
     let mut opts = Options::new();
     opts.optflag("h", "help", "print this help menu");
-    opts.reqopt("l", "local", "local socket to forward", "SOCKADDR");
-    opts.reqopt("r", "remote", "remote socket to forward to", "SOCKADDR");
+    opts.optopt("l", "local", "local socket to forward", "SOCKADDR");
+    opts.optopt("r", "remote", "remote socket to forward to", "SOCKADDR");
     opts.optopt("t", "type", "type of traffic to forward", "stream|datagram");
+    opts.optopt("", "cid", "vsock cid of the VM to connect to", "CID");
+    opts.optopt("", "port", "vsock port of vshd", "PORT");
+    opts.optopt("", "vm_name", "name of the VM to connect to, instead of --cid", "NAME");
+    opts.optopt("", "owner_id", "cryptohome id of the VM's owner", "ID");
+    opts.optopt("", "target", "container to connect to", "TARGET");
+    opts.optopt("", "target_container", "same as --target", "TARGET");
+    opts.optopt("", "user", "user to run the target program as", "USER");
+    opts.optflag("", "nopty", "don't allocate a pty for the target program");
+    opts.optmulti(
+        "",
+        "send-env",
+        &format!(
+            "local environment variables to send, with * and ? wildcards (default: {})",
+            DEFAULT_ENV_PATTERNS.join(" ")
+        ),
+        "PATTERN",
+    );
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -63,7 +580,7 @@ fn main() -> Result<()> {
     };
     if matches.opt_present("h") {
         print_usage(&program, &opts);
-        return Ok(());
+        return Ok(0);
     }
 
     // Safe because this string is defined above in this file and it contains exactly
@@ -74,5 +591,84 @@ fn main() -> Result<()> {
     // Block SIGPIPE so the process doesn't exit when writing to a socket that's been shutdown.
     block_signal(libc::SIGPIPE).map_err(Error::BlockSigpipe)?;
 
-    Ok(())
+    if matches.opt_present("local") || matches.opt_present("remote") {
+        let (local, remote) = match (matches.opt_str("local"), matches.opt_str("remote")) {
+            (Some(local), Some(remote)) => (local, remote),
+            _ => return Err(Error::MissingForwardAddress),
+        };
+        let local = local
+            .parse::<SocketAddr>()
+            .map_err(Error::InvalidSocketAddr)?;
+        let remote = remote
+            .parse::<SocketAddr>()
+            .map_err(Error::InvalidSocketAddr)?;
+
+        return match matches.opt_str("type").as_ref().map(String::as_str) {
+            None | Some("stream") => run_forwarder(local, remote),
+            Some("datagram") => run_datagram_forwarder(local, remote),
+            Some(t) => Err(Error::InvalidType(t.to_string())),
+        };
+    }
+
+    let target = matches
+        .opt_str("target_container")
+        .or_else(|| matches.opt_str("target"))
+        .unwrap_or_else(|| VM_SHELL_TARGET.to_string());
+    let mut user = matches.opt_str("user");
+
+    let cid = match (matches.opt_str("cid"), matches.opt_str("vm_name")) {
+        (Some(cid), _) => cid.parse::<c_uint>().map_err(|_| Error::InvalidCid(cid))?,
+        (None, Some(vm_name)) => {
+            let owner_id = matches
+                .opt_str("owner_id")
+                .or_else(|| env::var(OWNER_ID_ENV).ok())
+                .ok_or(Error::MissingOwnerId)?;
+            let conn = Connection::new_system().map_err(Error::ConnectDbus)?;
+
+            // Check the container first, so a stopped container is reported
+            // before trying to connect. Its primary user is the default.
+            if target != VM_SHELL_TARGET {
+                let info = vm_lookup::get_container_info(&conn, &vm_name, &target, &owner_id)
+                    .map_err(Error::LookupContainer)?;
+                user.get_or_insert(info.username);
+            }
+
+            vm_lookup::get_vm_cid(&conn, &vm_name, &owner_id).map_err(Error::LookupVm)?
+        }
+        (None, None) => return Err(Error::MissingCid),
+    };
+    let port = match matches.opt_str("port") {
+        Some(port) => port
+            .parse::<c_uint>()
+            .map_err(|_| Error::InvalidPort(port))?,
+        None => VSH_PORT,
+    };
+
+    let shell_opts = ShellOptions {
+        cid,
+        port,
+        target,
+        user: user.unwrap_or_default(),
+        // Without a terminal on stdin there is nothing to gain from a pty,
+        // and its line discipline would mangle binary data.
+        nopty: matches.opt_present("nopty") || !is_tty(libc::STDIN_FILENO),
+        send_env: if matches.opt_present("send-env") {
+            matches.opt_strs("send-env")
+        } else {
+            DEFAULT_ENV_PATTERNS.iter().map(|p| p.to_string()).collect()
+        },
+        argv: matches.free,
+    };
+
+    run_shell(shell_opts)
+}
+
+fn main() {
+    match run() {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("vsh: {}", e);
+            process::exit(1);
+        }
+    }
 }
@@ -2,24 +2,94 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::convert::TryFrom;
+use std::env;
 use std::ffi::CStr;
 use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::raw::c_ushort;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
+use std::process;
+use std::rc::Rc;
 use std::result;
 
+use cros_async::fd_executor::{self, add_future};
+use cros_async::run_one;
+use futures::channel::mpsc;
+use futures::future::{pending, select, try_join, Either};
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use futures::lock::Mutex;
+use futures::pin_mut;
+use futures::stream::StreamExt;
+use getopts::Options;
 use libchromeos::syslog;
-//use libchromeos::vsock::{VsockListener, VMADDR_PORT_ANY};
-//use log::{error, warn};
-//use protobuf::{self, Message as ProtoMessage, ProtobufError};
+use libchromeos::vsock::{self, SocketAddr, VsockListener};
+use log::{error, info, warn};
 use sys_util::{self, block_signal};
+use vsh::async_core::vsock::VsockStream;
+use vsh::async_core::{self, AsyncFd, ReadHalf, WriteHalf};
+use vsh::command::{Child, ChildStdio, Command, CommandError};
+use vsh::container::{Container, ContainerError};
+use vsh::env_policy::{EnvPolicy, DEFAULT_ENV_PATTERNS};
+use vsh::pty::{Dimensions, PtyError, PtyParent};
+use vsh::signal;
+use vsh::user::{User, UserError};
+use vsh::vsh_wire::{
+    local_capabilities, Negotiated, VshAsyncRead, VshAsyncWire, VshAsyncWrite, VshWireError,
+    PROTOCOL_VERSION, VM_SHELL_TARGET, VSH_PORT,
+};
+use vsh_proto::vsh::{
+    ConnectionStatus, GuestMessage, GuestMessage_oneof_msg, HostMessage, SetupConnectionRequest,
+    SetupConnectionResponse, Signal, StdioStream,
+};
 
 // Program name.
 const IDENT: &[u8] = b"vshd\0";
 
+// Shell to run when the client doesn't request a specific program.
+const DEFAULT_SHELL: &str = "/bin/sh";
+
+// PATH for the target program, unless overridden with --set-env.
+const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+// Halves of a client connection, so that messages from the client are
+// received while output from the target is being sent.
+type ClientReader = VshAsyncRead<ReadHalf<vsock::VsockStream>>;
+type ClientWriter = VshAsyncWrite<WriteHalf<vsock::VsockStream>>;
+
 #[remain::sorted]
 #[derive(Debug)]
 enum Error {
+    AddFuture(fd_executor::Error),
+    BindVsock(io::Error),
     BlockSigpipe(sys_util::signal::Error),
+    DupPtyParent(PtyError),
+    FindContainer(ContainerError),
+    InvalidSetEnv(String),
+    LookupUser(UserError),
+    MapContainerId(ContainerError),
+    OpenContainerCgroups(ContainerError),
+    OpenContainerNamespaces(ContainerError),
+    OpenPtyChild(PtyError),
+    OpenPtyParent(PtyError),
+    ReadTarget(io::Error),
+    ReceiveGuestMessage(VshWireError),
+    ReceiveSetupRequest(VshWireError),
+    RunExecutor(cros_async::Error),
+    SendHostMessage(VshWireError),
+    SendSetupResponse(VshWireError),
+    SetNonblocking(async_core::Error),
+    SetPtyDimensions(PtyError),
+    SetPtyOwner(PtyError),
+    SetPtyTermios(PtyError),
+    SpawnTarget(CommandError),
     Syslog(log::SetLoggerError),
+    UnsupportedTarget(String),
+    WaitTarget(CommandError),
+    WriteTarget(io::Error),
 }
 
 type Result<T> = result::Result<T, Error>;
@@ -31,13 +101,710 @@ impl fmt::Display for Error {
 
         #[remain::sorted]
         match self {
+            AddFuture(e) => write!(f, "failed to add future to executor: {}", e),
+            BindVsock(e) => write!(f, "failed to bind vsock listener: {}", e),
             BlockSigpipe(e) => write!(f, "failed to block SIGPIPE: {}", e),
+            DupPtyParent(e) => write!(f, "failed to duplicate pty parent: {}", e),
+            FindContainer(e) => write!(f, "failed to find container: {}", e),
+            InvalidSetEnv(s) => write!(f, "invalid environment variable, expected KEY=VALUE: {}", s),
+            LookupUser(e) => write!(f, "failed to look up target user: {}", e),
+            MapContainerId(e) => write!(f, "failed to map container id: {}", e),
+            OpenContainerCgroups(e) => write!(f, "failed to open container cgroups: {}", e),
+            OpenContainerNamespaces(e) => write!(f, "failed to open container namespaces: {}", e),
+            OpenPtyChild(e) => write!(f, "failed to open pty child: {}", e),
+            OpenPtyParent(e) => write!(f, "failed to open pty parent: {}", e),
+            ReadTarget(e) => write!(f, "failed to read from target: {}", e),
+            ReceiveGuestMessage(e) => write!(f, "failed to receive guest message: {}", e),
+            ReceiveSetupRequest(e) => write!(f, "failed to receive setup request: {}", e),
+            RunExecutor(e) => write!(f, "failed to run executor: {}", e),
+            SendHostMessage(e) => write!(f, "failed to send host message: {}", e),
+            SendSetupResponse(e) => write!(f, "failed to send setup response: {}", e),
+            SetNonblocking(e) => write!(f, "failed to make fd nonblocking: {}", e),
+            SetPtyDimensions(e) => write!(f, "failed to set pty dimensions: {}", e),
+            SetPtyOwner(e) => write!(f, "failed to set pty owner: {}", e),
+            SetPtyTermios(e) => write!(f, "failed to set pty terminal modes: {}", e),
+            SpawnTarget(e) => write!(f, "failed to spawn target program: {}", e),
             Syslog(e) => write!(f, "failed to initialize syslog: {}", e),
+            UnsupportedTarget(t) => write!(f, "unsupported target: {}", t),
+            WaitTarget(e) => write!(f, "failed to wait for target program: {}", e),
+            WriteTarget(e) => write!(f, "failed to write to target: {}", e),
+        }
+    }
+}
+
+/// Checks that a SetupConnectionRequest can be serviced by this vshd.
+fn validate_setup_request(req: &SetupConnectionRequest) -> Result<()> {
+    let target = req.get_target();
+    if target.is_empty() {
+        return Err(Error::UnsupportedTarget(target.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Finds the container requested by the client. Returns None if the target
+/// is the VM itself.
+fn target_container(req: &SetupConnectionRequest) -> Result<Option<Container>> {
+    match req.get_target() {
+        VM_SHELL_TARGET => Ok(None),
+        name => Container::find(name).map(Some).map_err(Error::FindContainer),
+    }
+}
+
+/// Sends a SetupConnectionResponse with the given status and description.
+async fn send_setup_response(
+    writer: &mut ClientWriter,
+    status: ConnectionStatus,
+    description: &str,
+) -> Result<()> {
+    let mut resp = SetupConnectionResponse::new();
+    resp.set_status(status);
+    resp.set_description(description.to_string());
+    resp.set_protocol_version(PROTOCOL_VERSION);
+    resp.set_capabilities(local_capabilities());
+
+    writer
+        .send_message(&resp)
+        .await
+        .map_err(Error::SendSetupResponse)
+}
+
+/// Looks up the user to run the target program as. An empty user name means
+/// the user that vshd is running as, or root in a container.
+///
+/// Users in a container are looked up in the container's own passwd file.
+fn target_user(req: &SetupConnectionRequest, container: Option<&Container>) -> Result<User> {
+    let name = req.get_user();
+    match container {
+        Some(container) if name.is_empty() => User::from_uid_in(&container.root(), 0),
+        Some(container) => User::from_name_in(&container.root(), name),
+        // Safe because geteuid modifies no memory.
+        None if name.is_empty() => User::from_uid(unsafe { libc::geteuid() }),
+        None => User::from_name(name),
+    }
+    .map_err(Error::LookupUser)
+}
+
+/// Builds the Command for the target program requested by the client, to be
+/// run as `user` from their home directory, inside `container` if given.
+///
+/// The target gets a fresh environment containing only what `policy` allows
+/// from the client's request, plus variables describing the user.
+fn target_command(
+    req: &SetupConnectionRequest,
+    user: &User,
+    container: Option<&Container>,
+    policy: &EnvPolicy,
+) -> Result<Command> {
+    let shell = if user.shell.as_os_str().is_empty() {
+        Path::new(DEFAULT_SHELL)
+    } else {
+        user.shell.as_path()
+    };
+
+    let argv = req.get_argv();
+    let mut command = match argv.split_first() {
+        Some((program, args)) => {
+            let mut command = Command::new(program);
+            command.args(args);
+            command
         }
+        None => {
+            // By convention, a shell is a login shell if argv[0] starts with
+            // a dash.
+            let shell_name = shell
+                .file_name()
+                .map(|name| name.to_string_lossy())
+                .unwrap_or_default();
+            let mut command = Command::new(shell);
+            command.arg0(format!("-{}", shell_name));
+            command
+        }
+    };
+
+    let (env, rejected) = policy.apply(req.get_env());
+    if !rejected.is_empty() {
+        warn!("ignoring environment variables from client: {}", rejected.join(", "));
     }
+
+    command.env_clear().env("PATH", DEFAULT_PATH);
+    for (key, val) in env {
+        command.env(key, val);
+    }
+
+    // These describe the user, so they take precedence over anything else.
+    command
+        .env("HOME", &user.home)
+        .env("USER", &user.name)
+        .env("LOGNAME", &user.name)
+        .env("SHELL", shell)
+        .current_dir(&user.home);
+
+    match container {
+        Some(container) => {
+            // Ids are always set in a container, since vshd's own ids mean
+            // nothing inside its user namespace.
+            let groups = user
+                .groups_in(&container.root())
+                .map_err(Error::LookupUser)?;
+            command
+                .cgroups(
+                    container
+                        .open_cgroups()
+                        .map_err(Error::OpenContainerCgroups)?,
+                )
+                .namespaces(
+                    container
+                        .open_namespaces()
+                        .map_err(Error::OpenContainerNamespaces)?,
+                )
+                .uid(user.uid)
+                .gid(user.gid)
+                .groups(&groups);
+        }
+        // Safe because geteuid modifies no memory.
+        None if user.uid != unsafe { libc::geteuid() } => {
+            let groups = user.groups().map_err(Error::LookupUser)?;
+            command.uid(user.uid).gid(user.gid).groups(&groups);
+        }
+        None => {}
+    }
+
+    Ok(command)
+}
+
+/// vshd's end of one of the target program's stdio streams.
+enum TargetFile {
+    /// A pipe, in nopty mode.
+    Pipe(File),
+    /// A copy of the pty parent, in pty mode.
+    Pty(PtyParent),
+}
+
+impl Read for TargetFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            TargetFile::Pipe(file) => file.read(buf),
+            TargetFile::Pty(pty_parent) => pty_parent.read(buf),
+        }
+    }
+}
+
+impl Write for TargetFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            TargetFile::Pipe(file) => file.write(buf),
+            TargetFile::Pty(pty_parent) => pty_parent.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TargetFile::Pipe(file) => file.flush(),
+            TargetFile::Pty(pty_parent) => pty_parent.flush(),
+        }
+    }
+}
+
+impl AsRawFd for TargetFile {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            TargetFile::Pipe(file) => file.as_raw_fd(),
+            TargetFile::Pty(pty_parent) => pty_parent.as_raw_fd(),
+        }
+    }
+}
+
+/// Parent side of the target program's stdio.
+///
+/// In pty mode stdin and stdout are both copies of the pty parent and stderr
+/// is unused, since the target program's stderr is the same pty.
+struct TargetStdio {
+    /// Pty parent for resizing the target's terminal. None in nopty mode.
+    pty_parent: Option<PtyParent>,
+    /// Target's stdin. Taken when forwarding starts.
+    stdin: Option<TargetFile>,
+    /// Target's stdout. Taken when forwarding starts.
+    stdout: Option<TargetFile>,
+    /// Target's stderr. Taken when forwarding starts.
+    stderr: Option<TargetFile>,
+}
+
+/// Converts a window size from the client into pty Dimensions. Returns None if
+/// rows or cols is out of range. The pixel size is optional, so an invalid
+/// pixel size is treated as unknown.
+fn window_dimensions(rows: i32, cols: i32, width_px: i32, height_px: i32) -> Option<Dimensions> {
+    Some(Dimensions {
+        rows: c_ushort::try_from(rows).ok()?,
+        cols: c_ushort::try_from(cols).ok()?,
+        width_px: c_ushort::try_from(width_px).unwrap_or(0),
+        height_px: c_ushort::try_from(height_px).unwrap_or(0),
+    })
+}
+
+/// Spawns the target program in a new session with a pseudoterminal as its
+/// controlling tty and stdio.
+fn spawn_pty_target(
+    req: &SetupConnectionRequest,
+    user: &User,
+    container: Option<&Container>,
+    policy: &EnvPolicy,
+) -> Result<(Child, TargetStdio)> {
+    let mut pty_parent = PtyParent::new().map_err(Error::OpenPtyParent)?;
+
+    // A zero-sized window is the default for a new pty, so only resize if
+    // the client gave a valid size.
+    if let Some(dims) = window_dimensions(
+        req.get_window_rows(),
+        req.get_window_cols(),
+        req.get_window_width_px(),
+        req.get_window_height_px(),
+    ) {
+        pty_parent
+            .set_dimensions(dims)
+            .map_err(Error::SetPtyDimensions)?;
+    }
+
+    // The pty lives outside any container, so it must be owned by the uid
+    // that the user's uid maps to on the host.
+    let uid = match container {
+        Some(container) => container
+            .host_uid(user.uid)
+            .map_err(Error::MapContainerId)?,
+        None => user.uid,
+    };
+    let pty_child = pty_parent.open_child().map_err(Error::OpenPtyChild)?;
+    pty_child.set_owner(uid).map_err(Error::SetPtyOwner)?;
+
+    // Apply the client's terminal modes before the target program can see
+    // the pty.
+    let modes = req.get_terminal_modes();
+    if !modes.is_empty() {
+        let mut termios = pty_child.get_termios().map_err(Error::SetPtyTermios)?;
+        termios.apply_modes(modes);
+        pty_child.set_termios(&termios).map_err(Error::SetPtyTermios)?;
+    }
+    let stdin = pty_child.try_clone().map_err(Error::OpenPtyChild)?;
+    let stdout = pty_child.try_clone().map_err(Error::OpenPtyChild)?;
+    let stderr = pty_child.try_clone().map_err(Error::OpenPtyChild)?;
+
+    // The Command gives up its copies of the pty child once the target
+    // program is spawned, so the pty parent sees EOF when the target exits.
+    let child = target_command(req, user, container, policy)?
+        .stdin(stdin)
+        .stdout(stdout)
+        .stderr(stderr)
+        .controlling_tty(pty_child)
+        .spawn()
+        .map_err(Error::SpawnTarget)?;
+
+    let stdio = TargetStdio {
+        stdin: Some(TargetFile::Pty(
+            pty_parent.try_clone().map_err(Error::DupPtyParent)?,
+        )),
+        stdout: Some(TargetFile::Pty(
+            pty_parent.try_clone().map_err(Error::DupPtyParent)?,
+        )),
+        stderr: None,
+        pty_parent: Some(pty_parent),
+    };
+
+    Ok((child, stdio))
+}
+
+/// Spawns the target program in a new session with pipes for its stdio.
+fn spawn_pipe_target(
+    req: &SetupConnectionRequest,
+    user: &User,
+    container: Option<&Container>,
+    policy: &EnvPolicy,
+) -> Result<(Child, TargetStdio)> {
+    // A new session makes the target a process group leader, so signals can
+    // be delivered to the whole group.
+    let mut child = target_command(req, user, container, policy)?
+        .stdin(ChildStdio::Piped)
+        .stdout(ChildStdio::Piped)
+        .stderr(ChildStdio::Piped)
+        .new_session()
+        .spawn()
+        .map_err(Error::SpawnTarget)?;
+
+    let stdio = TargetStdio {
+        pty_parent: None,
+        stdin: child.stdin.take().map(TargetFile::Pipe),
+        stdout: child.stdout.take().map(TargetFile::Pipe),
+        stderr: child.stderr.take().map(TargetFile::Pipe),
+    };
+
+    Ok((child, stdio))
+}
+
+/// Spawns the target program as the requested user in the requested
+/// container, with the type of stdio requested by the client.
+fn spawn_target(req: &SetupConnectionRequest, policy: &EnvPolicy) -> Result<(Child, TargetStdio)> {
+    let container = target_container(req)?;
+    let user = target_user(req, container.as_ref())?;
+    if req.get_nopty() {
+        spawn_pipe_target(req, &user, container.as_ref(), policy)
+    } else {
+        spawn_pty_target(req, &user, container.as_ref(), policy)
+    }
+}
+
+/// Sends a HostMessage indicating that the target program has exited.
+async fn send_exited(writer: &mut ClientWriter, code: i32) -> Result<()> {
+    let mut host_msg = HostMessage::new();
+    let status_msg = host_msg.mut_status_message();
+    status_msg.set_status(ConnectionStatus::EXITED);
+    status_msg.set_description("target exited".to_string());
+    status_msg.set_code(code);
+
+    writer
+        .send_message(&host_msg)
+        .await
+        .map_err(Error::SendHostMessage)
+}
+
+/// Forwards output from the target to the client until the target closes it.
+async fn forward_output(
+    output: Option<TargetFile>,
+    stream: StdioStream,
+    writer: &Mutex<ClientWriter>,
+) -> Result<()> {
+    let mut output = match output {
+        Some(file) => AsyncFd::new(file).map_err(Error::SetNonblocking)?,
+        None => return Ok(()),
+    };
+
+    let mut buf = vec![0u8; writer.lock().await.max_data_size()];
+    loop {
+        let count = output.read(&mut buf).await.map_err(Error::ReadTarget)?;
+        if count == 0 {
+            return Ok(());
+        }
+
+        writer
+            .lock()
+            .await
+            .send_data::<HostMessage>(stream, &buf[..count])
+            .await
+            .map_err(Error::SendHostMessage)?;
+    }
+}
+
+/// Delivers a signal from the client to the target program's process group.
+///
+/// With a pty the signal goes to the terminal's foreground process group, as
+/// if it had been generated by the line discipline.
+fn deliver_signal(target_pid: libc::pid_t, pty_parent: Option<&PtyParent>, signal: Signal) {
+    let signo = match signal::from_proto(signal) {
+        Some(signo) => signo,
+        None => {
+            warn!("ignoring unknown signal: {:?}", signal);
+            return;
+        }
+    };
+
+    let pgid = match pty_parent {
+        Some(pty_parent) => {
+            // Safe because tcgetpgrp modifies no memory and the return value
+            // is checked.
+            let pgid = unsafe { libc::tcgetpgrp(pty_parent.as_raw_fd()) };
+            if pgid > 0 {
+                pgid
+            } else {
+                target_pid
+            }
+        }
+        // The target program is a session leader, so its pid is also its
+        // process group id.
+        None => target_pid,
+    };
+
+    // Safe because kill modifies no memory and the return value is checked.
+    // The process group may have already exited, so failure isn't fatal.
+    if unsafe { libc::kill(-pgid, signo) } < 0 {
+        warn!(
+            "failed to deliver signal {}: {}",
+            signo,
+            io::Error::last_os_error()
+        );
+    }
+}
+
+/// Handles one message from the client. Data for the target's stdin is passed
+/// on through `stdin`, which is set to None once the client sends EOF. Returns
+/// false if the client has requested that the connection be closed.
+fn handle_guest_message(
+    guest_msg: GuestMessage,
+    target_pid: libc::pid_t,
+    pty_parent: Option<&PtyParent>,
+    stdin: &mut Option<mpsc::UnboundedSender<Vec<u8>>>,
+) -> Result<bool> {
+    match guest_msg.msg {
+        Some(GuestMessage_oneof_msg::data_message(mut data_msg)) => {
+            if data_msg.get_stream() != StdioStream::STDIN_STREAM {
+                warn!(
+                    "ignoring data for invalid stream: {:?}",
+                    data_msg.get_stream()
+                );
+                return Ok(true);
+            }
+
+            // An empty message means the client's stdin is at EOF, so stdin is
+            // closed once everything before it has been written. If the target
+            // has already closed its stdin, the data is discarded.
+            let data = data_msg.take_data();
+            if data.is_empty() {
+                *stdin = None;
+            } else if let Some(stdin) = stdin {
+                let _ = stdin.unbounded_send(data);
+            }
+        }
+        Some(GuestMessage_oneof_msg::status_message(status_msg)) => {
+            if status_msg.get_status() != ConnectionStatus::READY {
+                info!("client closed connection: {}", status_msg.get_description());
+                return Ok(false);
+            }
+        }
+        Some(GuestMessage_oneof_msg::resize_message(resize_msg)) => {
+            if let (Some(pty_parent), Some(dims)) = (
+                pty_parent,
+                window_dimensions(
+                    resize_msg.get_rows(),
+                    resize_msg.get_cols(),
+                    resize_msg.get_width_px(),
+                    resize_msg.get_height_px(),
+                ),
+            ) {
+                pty_parent
+                    .set_dimensions(dims)
+                    .map_err(Error::SetPtyDimensions)?;
+            }
+        }
+        Some(GuestMessage_oneof_msg::signal(signal)) => {
+            deliver_signal(target_pid, pty_parent, signal);
+        }
+        None => warn!("received empty guest message"),
+    }
+
+    Ok(true)
+}
+
+/// Handles messages from the client until it requests that the connection be
+/// closed.
+async fn receive_messages(
+    reader: &mut ClientReader,
+    target_pid: libc::pid_t,
+    pty_parent: Option<&PtyParent>,
+    stdin: mpsc::UnboundedSender<Vec<u8>>,
+) -> Result<()> {
+    let mut stdin = Some(stdin);
+    loop {
+        let mut guest_msg = GuestMessage::new();
+        reader
+            .receive_message(&mut guest_msg)
+            .await
+            .map_err(Error::ReceiveGuestMessage)?;
+
+        if !handle_guest_message(guest_msg, target_pid, pty_parent, &mut stdin)? {
+            return Ok(());
+        }
+    }
+}
+
+/// Writes data from the client to the target's stdin. Stdin is closed once the
+/// client has sent EOF and all of its data has been written, or if the target
+/// closed its end, in which case the rest of the data is discarded.
+async fn write_stdin(
+    stdin: Option<TargetFile>,
+    mut data: mpsc::UnboundedReceiver<Vec<u8>>,
+) -> Result<()> {
+    let mut stdin = match stdin {
+        Some(file) => AsyncFd::new(file).map_err(Error::SetNonblocking)?,
+        None => return Ok(()),
+    };
+
+    while let Some(chunk) = data.next().await {
+        match stdin.write_all(&chunk).await {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                info!("target closed its stdin");
+                return Ok(());
+            }
+            Err(e) => return Err(Error::WriteTarget(e)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Forwards stdio between the client and the target program until the target
+/// closes its output or the client closes the connection. Returns true if the
+/// target closed its output.
+async fn forward_stdio(
+    reader: &mut ClientReader,
+    writer: &Mutex<ClientWriter>,
+    target_pid: libc::pid_t,
+    stdio: &mut TargetStdio,
+) -> Result<bool> {
+    // Data from the client is buffered until the target's stdin takes it, so
+    // that vshd keeps draining the target's output and handling signals and
+    // window size changes while the target isn't reading its input.
+    let (stdin_sender, stdin_receiver) = mpsc::unbounded();
+    let stdin = stdio.stdin.take();
+    let stdin = async {
+        write_stdin(stdin, stdin_receiver).await?;
+        pending::<Result<()>>().await
+    };
+    let messages = receive_messages(reader, target_pid, stdio.pty_parent.as_ref(), stdin_sender);
+    let output = try_join(
+        forward_output(stdio.stdout.take(), StdioStream::STDOUT_STREAM, writer),
+        forward_output(stdio.stderr.take(), StdioStream::STDERR_STREAM, writer),
+    );
+    pin_mut!(stdin, messages, output);
+
+    match select(output, select(messages, stdin)).await {
+        Either::Left((result, _)) => result.map(|_| true),
+        Either::Right((Either::Left((result, _)), _)) => result.map(|_| false),
+        Either::Right((Either::Right((result, _)), _)) => result.map(|_| false),
+    }
+}
+
+/// Performs the vsh handshake with a newly connected client, then runs the
+/// requested target program until it exits.
+async fn handle_connection(stream: VsockStream, policy: &EnvPolicy) -> Result<()> {
+    let (mut reader, mut writer) = VshAsyncWire::new(stream).split();
+
+    let mut req = SetupConnectionRequest::new();
+    reader
+        .receive_message(&mut req)
+        .await
+        .map_err(Error::ReceiveSetupRequest)?;
+
+    let (mut child, mut stdio) = match validate_setup_request(&req).and_then(|_| spawn_target(&req, policy))
+    {
+        Ok(target) => target,
+        Err(e) => {
+            // Let the client know why the connection is going away. If this
+            // fails there's nothing more that can be done, so report the
+            // original error.
+            let _ = send_setup_response(&mut writer, ConnectionStatus::FAILED, &e.to_string()).await;
+            return Err(e);
+        }
+    };
+
+    send_setup_response(&mut writer, ConnectionStatus::READY, "vsh ready").await?;
+
+    // Both sides switch to the negotiated parameters once the response has
+    // been sent, which is always within the original frame size.
+    let negotiated = Negotiated::with_peer(req.get_protocol_version(), req.get_capabilities());
+    writer.set_max_send_frame_size(negotiated.max_send_frame_size);
+    reader.set_max_receive_frame_size(negotiated.max_receive_frame_size);
+
+    let target_pid = child.id();
+    let writer = Mutex::new(writer);
+    let forward_result = forward_stdio(&mut reader, &writer, target_pid, &mut stdio).await;
+
+    // If the client went away first, hang up the target's process group as a
+    // pty would. Closing a pty also does this, but pipes don't.
+    if forward_result
+        .as_ref()
+        .map_or(true, |target_done| !target_done)
+    {
+        // Safe because kill modifies no memory. The target may have already
+        // exited, so the return value is ignored.
+        unsafe { libc::kill(-target_pid, libc::SIGHUP) };
+    }
+    drop(stdio);
+
+    let status = child.wait_async().await.map_err(Error::WaitTarget)?;
+
+    // Match the shell convention for reporting death by signal.
+    let code = status
+        .code()
+        .unwrap_or_else(|| 128 + status.signal().unwrap_or(0));
+
+    // Report the exit code even if forwarding stopped early, since the client
+    // may still be listening. The forwarding error takes precedence.
+    let exited_result = send_exited(&mut writer.into_inner(), code).await;
+    forward_result?;
+    exited_result
+}
+
+/// Handles a connection as its own task on the executor so the accept loop
+/// isn't blocked.
+fn spawn_connection(stream: VsockStream, addr: SocketAddr, policy: Rc<EnvPolicy>) -> Result<()> {
+    add_future(Box::pin(async move {
+        if let Err(e) = handle_connection(stream, &policy).await {
+            error!(
+                "connection from {} port {} failed: {}",
+                addr.cid, addr.port, e
+            );
+        }
+    }))
+    .map_err(Error::AddFuture)
+}
+
+/// Builds the environment policy from command line options.
+fn env_policy(accept: Vec<String>, set: Vec<String>) -> Result<EnvPolicy> {
+    let mut policy = if accept.is_empty() {
+        EnvPolicy::default()
+    } else {
+        EnvPolicy::new(accept)
+    };
+
+    for var in set {
+        let mut parts = var.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(val)) if !key.is_empty() => policy.set(key, val),
+            _ => return Err(Error::InvalidSetEnv(var)),
+        };
+    }
+
+    Ok(policy)
+}
+
+fn print_usage(program: &str, opts: &Options) {
+    let brief = format!("Usage: {} [options]", program);
+    print!("{}", opts.usage(&brief));
 }
 
 fn main() -> Result<()> {
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = Options::new();
+    opts.optflag("h", "help", "print this help menu");
+    opts.optmulti(
+        "",
+        "accept-env",
+        &format!(
+            "environment variables to accept from clients, with * and ? wildcards (default: {})",
+            DEFAULT_ENV_PATTERNS.join(" ")
+        ),
+        "PATTERN",
+    );
+    opts.optmulti("", "set-env", "environment variable to always set for the target", "KEY=VALUE");
+
+    let matches = match opts.parse(&args[1..]) {
+        Ok(m) => m,
+        Err(e) => {
+            eprintln!("failed to parse arg: {}", e);
+            print_usage(&program, &opts);
+            process::exit(1);
+        }
+    };
+    if matches.opt_present("h") {
+        print_usage(&program, &opts);
+        return Ok(());
+    }
+
+    let policy = Rc::new(env_policy(
+        matches.opt_strs("accept-env"),
+        matches.opt_strs("set-env"),
+    )?);
+
     // Safe because this string is defined above in this file and it contains exactly
     // one nul byte, which appears at the end.
     let ident = CStr::from_bytes_with_nul(IDENT).unwrap();
@@ -45,5 +812,26 @@ fn main() -> Result<()> {
 
     // Block SIGPIPE so the process doesn't exit when writing to a socket that's been shutdown.
     block_signal(libc::SIGPIPE).map_err(Error::BlockSigpipe)?;
-    Ok(())
+
+    let listener = VsockListener::bind(VSH_PORT).map_err(Error::BindVsock)?;
+    let listener = AsyncFd::new(listener).map_err(Error::SetNonblocking)?;
+    info!("listening on vsock port {}", VSH_PORT);
+
+    let accept_loop = async {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(s) => s,
+                Err(e) => {
+                    error!("failed to accept vsock connection: {}", e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = spawn_connection(stream, addr, policy.clone()) {
+                error!("{}", e);
+            }
+        }
+    };
+    pin_mut!(accept_loop);
+    run_one(accept_loop).map_err(Error::RunExecutor)
 }
@@ -2,40 +2,1203 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+//! A fork/exec process builder.
+//!
+//! Unlike std::process::Command, this allows argv[0] to be set independently
+//! of the program path and a controlling tty to be attached to the child, both
+//! of which are needed to start login shells.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::{CString, OsStr, OsString};
 use std::fmt;
-use std::io::{self, Read, Write};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::mem;
+use std::os::raw::{c_char, c_int};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use std::ptr;
 use std::result;
+use std::thread;
+
+use crate::async_core::AsyncFd;
+use crate::pty::PtyChild;
 
-/// Errors that can be encountered by a ForwarderSession.
+// Syscall number of close_range, on architectures that use the common syscall
+// table. Others always close fds one at a time.
+#[cfg(any(
+    target_arch = "aarch64",
+    target_arch = "arm",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "riscv64",
+    target_arch = "s390x",
+    target_arch = "x86",
+    target_arch = "x86_64"
+))]
+const SYS_CLOSE_RANGE: Option<libc::c_long> = Some(436);
+#[cfg(not(any(
+    target_arch = "aarch64",
+    target_arch = "arm",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "riscv64",
+    target_arch = "s390x",
+    target_arch = "x86",
+    target_arch = "x86_64"
+)))]
+const SYS_CLOSE_RANGE: Option<libc::c_long> = None;
+
+/// Errors that can be encountered by a Command.
 #[remain::sorted]
 #[derive(Debug)]
-pub enum ForwarderError {
-    /// An io::Error was encountered while reading from a stream.
-    ReadFromStream(io::Error),
-    /// An io::Error was encountered while shutting down writes on a stream.
-    ShutDownStream(io::Error),
-    /// An io::Error was encountered while writing to a stream.
-    WriteToStream(io::Error),
+pub enum CommandError {
+    /// The child failed to change to the working directory.
+    ChangeDirectory(io::Error),
+    /// Failed to create a pipe.
+    CreatePipe(io::Error),
+    /// The child failed to set up its stdio.
+    DupStdio(io::Error),
+    /// The child failed to exec the program.
+    Exec(io::Error),
+    /// Failed to fork the child process.
+    Fork(io::Error),
+    /// The child failed to move into its cgroups.
+    JoinCgroups(io::Error),
+    /// The child failed to enter its namespaces.
+    JoinNamespaces(io::Error),
+    /// The program, an argument, or an environment variable contained a nul byte.
+    NulInArgument,
+    /// Failed to open /dev/null for the child's stdio.
+    OpenDevNull(io::Error),
+    /// Failed to read the setup status or exit status of the child.
+    ReadChildStatus(io::Error),
+    /// The child failed to reset its signal mask and dispositions.
+    ResetSignals(io::Error),
+    /// The child failed to set its controlling tty.
+    SetControllingTty(io::Error),
+    /// The child failed to change its user, group, or supplementary groups.
+    SetCredentials(io::Error),
+    /// The child failed to create a new session.
+    SetSession(io::Error),
+    /// Failed to send a signal to the child.
+    Signal(io::Error),
+    /// Failed to spawn a thread to wait for the child to exit.
+    SpawnWaitThread(io::Error),
+    /// Failed to wait for the child.
+    Wait(io::Error),
 }
 
-type Result<T> = result::Result<T, ForwarderError>;
+type Result<T> = result::Result<T, CommandError>;
 
-impl fmt::Display for ForwarderError {
+impl fmt::Display for CommandError {
     #[remain::check]
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use self::ForwarderError::*;
+        use self::CommandError::*;
 
         #[remain::sorted]
         match self {
-            ReadFromStream(e) => write!(f, "failed to read from stream: {}", e),
-            ShutDownStream(e) => write!(f, "failed to shut down stream: {}", e),
-            WriteToStream(e) => write!(f, "failed to write to stream: {}", e),
+            ChangeDirectory(e) => write!(f, "failed to change working directory: {}", e),
+            CreatePipe(e) => write!(f, "failed to create pipe: {}", e),
+            DupStdio(e) => write!(f, "failed to set up stdio: {}", e),
+            Exec(e) => write!(f, "failed to exec program: {}", e),
+            Fork(e) => write!(f, "failed to fork: {}", e),
+            JoinCgroups(e) => write!(f, "failed to join cgroups: {}", e),
+            JoinNamespaces(e) => write!(f, "failed to enter namespaces: {}", e),
+            NulInArgument => write!(f, "argument contains a nul byte"),
+            OpenDevNull(e) => write!(f, "failed to open /dev/null: {}", e),
+            ReadChildStatus(e) => write!(f, "failed to read child status: {}", e),
+            ResetSignals(e) => write!(f, "failed to reset signals: {}", e),
+            SetControllingTty(e) => write!(f, "failed to set controlling tty: {}", e),
+            SetCredentials(e) => write!(f, "failed to set user and groups: {}", e),
+            SetSession(e) => write!(f, "failed to create new session: {}", e),
+            Signal(e) => write!(f, "failed to signal child: {}", e),
+            SpawnWaitThread(e) => write!(f, "failed to spawn wait thread: {}", e),
+            Wait(e) => write!(f, "failed to wait for child: {}", e),
         }
     }
 }
 
+/// Steps of child setup that can fail after fork. These are reported to the
+/// parent over a pipe along with the errno.
+#[derive(Clone, Copy)]
+#[repr(u32)]
+enum ChildStep {
+    ResetSignals = 1,
+    SetSession,
+    DupStdio,
+    SetControllingTty,
+    JoinCgroups,
+    JoinNamespaces,
+    SetCredentials,
+    ChangeDirectory,
+    Exec,
+}
+
+impl ChildStep {
+    fn from_u32(step: u32) -> Option<ChildStep> {
+        use self::ChildStep::*;
+
+        [
+            ResetSignals,
+            SetSession,
+            DupStdio,
+            SetControllingTty,
+            JoinCgroups,
+            JoinNamespaces,
+            SetCredentials,
+            ChangeDirectory,
+            Exec,
+        ]
+            .iter()
+            .copied()
+            .find(|s| *s as u32 == step)
+    }
+
+    fn into_error(self, e: io::Error) -> CommandError {
+        match self {
+            ChildStep::ResetSignals => CommandError::ResetSignals(e),
+            ChildStep::SetSession => CommandError::SetSession(e),
+            ChildStep::DupStdio => CommandError::DupStdio(e),
+            ChildStep::SetControllingTty => CommandError::SetControllingTty(e),
+            ChildStep::JoinCgroups => CommandError::JoinCgroups(e),
+            ChildStep::JoinNamespaces => CommandError::JoinNamespaces(e),
+            ChildStep::SetCredentials => CommandError::SetCredentials(e),
+            ChildStep::ChangeDirectory => CommandError::ChangeDirectory(e),
+            ChildStep::Exec => CommandError::Exec(e),
+        }
+    }
+}
+
+/// Describes what one of a child's stdio fds should be connected to.
+pub enum ChildStdio {
+    /// Inherit the parent's fd.
+    Inherit,
+    /// Connect the fd to /dev/null.
+    Null,
+    /// Create a pipe, with the parent's end available from the Child.
+    Piped,
+    /// Connect the fd to the given file.
+    File(File),
+}
+
+impl From<File> for ChildStdio {
+    fn from(file: File) -> Self {
+        ChildStdio::File(file)
+    }
+}
+
+impl From<PtyChild> for ChildStdio {
+    fn from(pty_child: PtyChild) -> Self {
+        // Safe because the PtyChild's fd is given to the File, which then
+        // owns it exclusively.
+        ChildStdio::File(unsafe { File::from_raw_fd(pty_child.into_raw_fd()) })
+    }
+}
+
+/// Creates a pipe with both ends marked close-on-exec.
+fn pipe() -> Result<(File, File)> {
+    let mut fds: [c_int; 2] = [-1; 2];
+
+    // Safe because pipe2 only writes two fds into the given array and the
+    // return value is checked.
+    let ret = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+    if ret < 0 {
+        return Err(CommandError::CreatePipe(io::Error::last_os_error()));
+    }
+
+    // Safe because both fds were just created and are owned by nothing else.
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+/// Converts a string-like value into a CString, returning None if it
+/// contains a nul byte.
+fn to_cstring<S: AsRef<OsStr>>(s: S) -> Option<CString> {
+    CString::new(s.as_ref().as_bytes()).ok()
+}
+
+/// A builder for spawning a child process with fork and exec.
 pub struct Command {
-    args: Vector<CString>,
+    program: CString,
+    args: Vec<CString>,
     env: BTreeMap<CString, Option<CString>>,
     arg0: Option<CString>,
+    env_clear: bool,
+    cwd: Option<CString>,
+    uid: Option<libc::uid_t>,
+    gid: Option<libc::gid_t>,
+    groups: Option<Vec<libc::gid_t>>,
+    cgroups: Vec<File>,
+    namespaces: Vec<File>,
+    stdin: ChildStdio,
+    stdout: ChildStdio,
+    stderr: ChildStdio,
+    new_session: bool,
+    controlling_tty: Option<PtyChild>,
+    saw_nul: bool,
+}
+
+impl Command {
+    /// Creates a Command for running `program`, which is searched for in
+    /// PATH if it doesn't contain a slash.
+    pub fn new<S: AsRef<OsStr>>(program: S) -> Command {
+        let (program, saw_nul) = match to_cstring(program) {
+            Some(program) => (program, false),
+            None => (CString::default(), true),
+        };
+
+        Command {
+            program,
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            arg0: None,
+            env_clear: false,
+            cwd: None,
+            uid: None,
+            gid: None,
+            groups: None,
+            cgroups: Vec::new(),
+            namespaces: Vec::new(),
+            stdin: ChildStdio::Inherit,
+            stdout: ChildStdio::Inherit,
+            stderr: ChildStdio::Inherit,
+            new_session: false,
+            controlling_tty: None,
+            saw_nul,
+        }
+    }
+
+    /// Stores a converted string, remembering if the conversion failed so
+    /// spawn() can report it.
+    fn cstring<S: AsRef<OsStr>>(&mut self, s: S) -> CString {
+        to_cstring(s).unwrap_or_else(|| {
+            self.saw_nul = true;
+            CString::default()
+        })
+    }
+
+    /// Overrides argv[0], which otherwise defaults to the program.
+    pub fn arg0<S: AsRef<OsStr>>(&mut self, arg0: S) -> &mut Command {
+        self.arg0 = Some(self.cstring(arg0));
+        self
+    }
+
+    /// Adds an argument to pass to the program.
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Command {
+        let arg = self.cstring(arg);
+        self.args.push(arg);
+        self
+    }
+
+    /// Adds multiple arguments to pass to the program.
+    pub fn args<I, S>(&mut self, args: I) -> &mut Command
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        for arg in args {
+            self.arg(arg);
+        }
+        self
+    }
+
+    /// Sets an environment variable for the child.
+    pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, val: V) -> &mut Command {
+        let key = self.cstring(key);
+        let val = self.cstring(val);
+        self.env.insert(key, Some(val));
+        self
+    }
+
+    /// Removes an environment variable from the child's environment.
+    pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Command {
+        let key = self.cstring(key);
+        self.env.insert(key, None);
+        self
+    }
+
+    /// Starts the child with an empty environment, aside from any variables
+    /// set with env().
+    pub fn env_clear(&mut self) -> &mut Command {
+        self.env_clear = true;
+        self.env.clear();
+        self
+    }
+
+    /// Sets the working directory of the child.
+    pub fn current_dir<P: AsRef<OsStr>>(&mut self, dir: P) -> &mut Command {
+        self.cwd = Some(self.cstring(dir));
+        self
+    }
+
+    /// Sets the user id of the child.
+    ///
+    /// Supplementary groups are inherited unless they are also set with
+    /// groups(), so a privileged parent should always set both.
+    pub fn uid(&mut self, uid: libc::uid_t) -> &mut Command {
+        self.uid = Some(uid);
+        self
+    }
+
+    /// Sets the group id of the child.
+    pub fn gid(&mut self, gid: libc::gid_t) -> &mut Command {
+        self.gid = Some(gid);
+        self
+    }
+
+    /// Sets the supplementary groups of the child.
+    pub fn groups(&mut self, groups: &[libc::gid_t]) -> &mut Command {
+        self.groups = Some(groups.to_vec());
+        self
+    }
+
+    /// Moves the child into cgroups, given their open cgroup.procs files.
+    pub fn cgroups(&mut self, cgroup_procs: Vec<File>) -> &mut Command {
+        self.cgroups = cgroup_procs;
+        self
+    }
+
+    /// Enters the given namespaces, in order, before changing credentials.
+    ///
+    /// A pid namespace only applies to new children, so the program is run
+    /// in a grandchild. The child waits for it and exits with the same
+    /// status, so the Child still reports the program's exit status.
+    pub fn namespaces(&mut self, namespaces: Vec<File>) -> &mut Command {
+        self.namespaces = namespaces;
+        self
+    }
+
+    /// Sets the child's stdin.
+    pub fn stdin<T: Into<ChildStdio>>(&mut self, stdin: T) -> &mut Command {
+        self.stdin = stdin.into();
+        self
+    }
+
+    /// Sets the child's stdout.
+    pub fn stdout<T: Into<ChildStdio>>(&mut self, stdout: T) -> &mut Command {
+        self.stdout = stdout.into();
+        self
+    }
+
+    /// Sets the child's stderr.
+    pub fn stderr<T: Into<ChildStdio>>(&mut self, stderr: T) -> &mut Command {
+        self.stderr = stderr.into();
+        self
+    }
+
+    /// Runs the child in a new session, making it the leader of a new
+    /// process group.
+    pub fn new_session(&mut self) -> &mut Command {
+        self.new_session = true;
+        self
+    }
+
+    /// Makes the given pty the controlling tty of the child. This implies
+    /// new_session().
+    pub fn controlling_tty(&mut self, tty: PtyChild) -> &mut Command {
+        self.new_session = true;
+        self.controlling_tty = Some(tty);
+        self
+    }
+
+    /// Builds the "KEY=VALUE" strings for the child's environment.
+    fn build_envp(&self) -> Result<Vec<CString>> {
+        let mut vars = BTreeMap::new();
+        if !self.env_clear {
+            for (key, val) in env::vars_os() {
+                if let (Some(key), Some(val)) = (to_cstring(key), to_cstring(val)) {
+                    vars.insert(key, val);
+                }
+            }
+        }
+
+        for (key, val) in &self.env {
+            match val {
+                Some(val) => vars.insert(key.clone(), val.clone()),
+                None => vars.remove(key),
+            };
+        }
+
+        vars.into_iter()
+            .map(|(key, val)| {
+                let mut var = key.into_bytes();
+                var.push(b'=');
+                var.extend_from_slice(val.as_bytes());
+                CString::new(var).map_err(|_| CommandError::NulInArgument)
+            })
+            .collect()
+    }
+
+    /// Returns the paths to try exec'ing, in order. As with execvp, a program
+    /// without a slash is searched for in PATH, which defaults to
+    /// "/bin:/usr/bin".
+    fn program_paths(&self) -> Vec<CString> {
+        let program = self.program.as_bytes();
+        if program.is_empty() {
+            return Vec::new();
+        }
+        if program.contains(&b'/') {
+            return vec![self.program.clone()];
+        }
+
+        let path = env::var_os("PATH").unwrap_or_else(|| OsString::from("/bin:/usr/bin"));
+        path.as_bytes()
+            .split(|b| *b == b':')
+            .filter_map(|dir| {
+                // An empty entry means the current directory.
+                let mut candidate = dir.to_vec();
+                if !candidate.is_empty() {
+                    candidate.push(b'/');
+                }
+                candidate.extend_from_slice(program);
+                CString::new(candidate).ok()
+            })
+            .collect()
+    }
+
+    /// Resolves a ChildStdio into the fd the child should use, and the
+    /// parent's end of a pipe if one was requested.
+    fn setup_stdio(
+        stdio: &mut ChildStdio,
+        child_reads: bool,
+    ) -> Result<(Option<File>, Option<File>)> {
+        match mem::replace(stdio, ChildStdio::Inherit) {
+            ChildStdio::Inherit => Ok((None, None)),
+            ChildStdio::Null => {
+                let null = OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .open("/dev/null")
+                    .map_err(CommandError::OpenDevNull)?;
+                Ok((Some(null), None))
+            }
+            ChildStdio::Piped => {
+                let (read_end, write_end) = pipe()?;
+                if child_reads {
+                    Ok((Some(read_end), Some(write_end)))
+                } else {
+                    Ok((Some(write_end), Some(read_end)))
+                }
+            }
+            ChildStdio::File(file) => Ok((Some(file), None)),
+        }
+    }
+
+    /// Spawns the child process. The Command's stdio and controlling tty are
+    /// consumed, so they must be set again before spawning another child.
+    pub fn spawn(&mut self) -> Result<Child> {
+        if self.saw_nul {
+            return Err(CommandError::NulInArgument);
+        }
+
+        // Everything the child needs is allocated before forking, since
+        // allocating after fork in a multithreaded process is unsafe.
+        let envp_strings = self.build_envp()?;
+        let mut envp: Vec<*const c_char> = envp_strings.iter().map(|s| s.as_ptr()).collect();
+        envp.push(ptr::null());
+
+        // execvpe isn't async-signal-safe, so the PATH search is prepared here
+        // and the child only has to try each path with execve.
+        let program_paths = self.program_paths();
+
+        let arg0 = self.arg0.as_ref().unwrap_or(&self.program);
+        let mut argv: Vec<*const c_char> = vec![arg0.as_ptr()];
+        argv.extend(self.args.iter().map(|s| s.as_ptr()));
+        argv.push(ptr::null());
+
+        let (child_stdin, parent_stdin) = Command::setup_stdio(&mut self.stdin, true)?;
+        let (child_stdout, parent_stdout) = Command::setup_stdio(&mut self.stdout, false)?;
+        let (child_stderr, parent_stderr) = Command::setup_stdio(&mut self.stderr, false)?;
+        let controlling_tty = self.controlling_tty.take();
+
+        let (mut status_read, status_write) = pipe()?;
+
+        // Safe because the child only calls async-signal-safe functions before
+        // exec or _exit, and the parent checks the return value.
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(CommandError::Fork(io::Error::last_os_error()));
+        }
+
+        if pid == 0 {
+            let stdio_fds = [
+                child_stdin.as_ref().map(|f| f.as_raw_fd()),
+                child_stdout.as_ref().map(|f| f.as_raw_fd()),
+                child_stderr.as_ref().map(|f| f.as_raw_fd()),
+            ];
+            let (step, errno) = self.exec_child(
+                &program_paths,
+                &argv,
+                &envp,
+                &stdio_fds,
+                controlling_tty.as_ref().map(|t| t.as_raw_fd()),
+            );
+
+            let mut report = [0u8; 8];
+            report[..4].copy_from_slice(&(step as u32).to_le_bytes());
+            report[4..].copy_from_slice(&errno.to_le_bytes());
+
+            // Safe because write and _exit are async-signal-safe and only read
+            // from the report buffer.
+            unsafe {
+                libc::write(
+                    status_write.as_raw_fd(),
+                    report.as_ptr() as *const libc::c_void,
+                    report.len(),
+                );
+                libc::_exit(127);
+            }
+        }
+
+        // Close the parent's copy of the write end so reading sees EOF once
+        // the child execs successfully.
+        drop(status_write);
+        drop(child_stdin);
+        drop(child_stdout);
+        drop(child_stderr);
+        drop(controlling_tty);
+
+        let mut child = Child {
+            pid,
+            stdin: parent_stdin,
+            stdout: parent_stdout,
+            stderr: parent_stderr,
+            status: None,
+            exit_pipe: None,
+        };
+
+        let mut report = Vec::with_capacity(8);
+        status_read
+            .read_to_end(&mut report)
+            .map_err(CommandError::ReadChildStatus)?;
+        if report.is_empty() {
+            return Ok(child);
+        }
+
+        // Reap the child, which exited after reporting the failure.
+        let _ = child.wait();
+
+        if report.len() != 8 {
+            return Err(CommandError::ReadChildStatus(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "child sent an incomplete status report",
+            )));
+        }
+
+        let mut step = [0u8; 4];
+        let mut errno = [0u8; 4];
+        step.copy_from_slice(&report[..4]);
+        errno.copy_from_slice(&report[4..]);
+        let e = io::Error::from_raw_os_error(i32::from_le_bytes(errno));
+
+        Err(match ChildStep::from_u32(u32::from_le_bytes(step)) {
+            Some(step) => step.into_error(e),
+            None => CommandError::ReadChildStatus(e),
+        })
+    }
+
+    /// Sets up the child after fork and execs the first of `program_paths`
+    /// that can be run. Only returns on failure, with the step that failed and
+    /// its errno. If the child forks
+    /// again to enter namespaces, the intermediate process closes every fd it
+    /// inherited, including its copy of the status pipe, and never returns.
+    ///
+    /// This must only call async-signal-safe functions.
+    fn exec_child(
+        &self,
+        program_paths: &[CString],
+        argv: &[*const c_char],
+        envp: &[*const c_char],
+        stdio_fds: &[Option<RawFd>; 3],
+        controlling_tty: Option<RawFd>,
+    ) -> (ChildStep, i32) {
+        let errno = || io::Error::last_os_error().raw_os_error().unwrap_or(0);
+
+        // Safe because all of these functions are async-signal-safe, only
+        // modify memory they're given, and their return values are checked.
+        unsafe {
+            // Signals blocked by the parent (e.g. for signalfds) would
+            // otherwise stay blocked in the new program.
+            let mut sigset: libc::sigset_t = mem::zeroed();
+            libc::sigemptyset(&mut sigset);
+            if libc::pthread_sigmask(libc::SIG_SETMASK, &sigset, ptr::null_mut()) != 0 {
+                return (ChildStep::ResetSignals, errno());
+            }
+            if libc::signal(libc::SIGPIPE, libc::SIG_DFL) == libc::SIG_ERR {
+                return (ChildStep::ResetSignals, errno());
+            }
+
+            if self.new_session && libc::setsid() < 0 {
+                return (ChildStep::SetSession, errno());
+            }
+
+            // Move sources that are already stdio fds out of the way first, so
+            // that putting one fd in place can't overwrite the source of
+            // another. A source that is already in place only needs to
+            // survive exec.
+            let mut sources = *stdio_fds;
+            for (target_fd, source) in sources.iter_mut().enumerate() {
+                if let Some(fd) = source {
+                    if *fd <= 2 && *fd != target_fd as c_int {
+                        *fd = libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, 3);
+                        if *fd < 0 {
+                            return (ChildStep::DupStdio, errno());
+                        }
+                    }
+                }
+            }
+            for (target_fd, source) in sources.iter().enumerate() {
+                let ret = match source {
+                    Some(fd) if *fd == target_fd as c_int => libc::fcntl(*fd, libc::F_SETFD, 0),
+                    Some(fd) => libc::dup2(*fd, target_fd as c_int),
+                    None => 0,
+                };
+                if ret < 0 {
+                    return (ChildStep::DupStdio, errno());
+                }
+            }
+
+            if let Some(tty) = controlling_tty {
+                if libc::ioctl(tty, libc::TIOCSCTTY, 0) < 0 {
+                    return (ChildStep::SetControllingTty, errno());
+                }
+            }
+
+            // Writing 0 to cgroup.procs moves the writing process.
+            for cgroup in &self.cgroups {
+                if libc::write(cgroup.as_raw_fd(), b"0".as_ptr() as *const libc::c_void, 1) < 0 {
+                    return (ChildStep::JoinCgroups, errno());
+                }
+            }
+
+            if !self.namespaces.is_empty() {
+                for ns in &self.namespaces {
+                    if libc::setns(ns.as_raw_fd(), 0) < 0 {
+                        return (ChildStep::JoinNamespaces, errno());
+                    }
+                }
+
+                let pid = libc::fork();
+                if pid < 0 {
+                    return (ChildStep::JoinNamespaces, errno());
+                }
+                if pid > 0 {
+                    // This process never execs, so it must close the fds it
+                    // inherited, including O_CLOEXEC fds that other threads of
+                    // the parent opened, or their owners would miss EOF for as
+                    // long as the target runs. That includes the status pipe:
+                    // only the grandchild reports its setup status, so the
+                    // parent sees EOF once it execs.
+                    close_fds_from(3);
+                    relay_exit(pid);
+                }
+            }
+
+            // Groups must be changed while still privileged, so uid is last.
+            if let Some(groups) = &self.groups {
+                if libc::setgroups(groups.len(), groups.as_ptr()) < 0 {
+                    return (ChildStep::SetCredentials, errno());
+                }
+            }
+            if let Some(gid) = self.gid {
+                if libc::setresgid(gid, gid, gid) < 0 {
+                    return (ChildStep::SetCredentials, errno());
+                }
+            }
+            if let Some(uid) = self.uid {
+                if libc::setresuid(uid, uid, uid) < 0 {
+                    return (ChildStep::SetCredentials, errno());
+                }
+            }
+
+            if let Some(cwd) = &self.cwd {
+                if libc::chdir(cwd.as_ptr()) < 0 {
+                    return (ChildStep::ChangeDirectory, errno());
+                }
+            }
+
+            // Like execvp, move on to the next path if this one doesn't
+            // exist, and prefer reporting EACCES over a later ENOENT.
+            let mut exec_errno = libc::ENOENT;
+            let mut saw_eacces = false;
+            for path in program_paths {
+                libc::execve(path.as_ptr(), argv.as_ptr(), envp.as_ptr());
+                match errno() {
+                    libc::EACCES => saw_eacces = true,
+                    e @ libc::ENOENT | e @ libc::ENOTDIR => exec_errno = e,
+                    e => return (ChildStep::Exec, e),
+                }
+            }
+            if saw_eacces {
+                exec_errno = libc::EACCES;
+            }
+
+            (ChildStep::Exec, exec_errno)
+        }
+    }
+}
+
+/// Closes every fd numbered `first` or higher.
+///
+/// This must only call async-signal-safe functions.
+unsafe fn close_fds_from(first: c_int) {
+    if let Some(nr) = SYS_CLOSE_RANGE {
+        if libc::syscall(nr, first, libc::c_uint::max_value(), 0) == 0 {
+            return;
+        }
+    }
+
+    // Kernels before 5.9 lack close_range, so close each fd that could be
+    // open instead. Without a limit, fall back to the kernel's default
+    // maximum.
+    let mut limit: libc::rlimit = mem::zeroed();
+    let max_fd = if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) == 0
+        && limit.rlim_cur != libc::RLIM_INFINITY
+    {
+        limit.rlim_cur.min(c_int::max_value() as libc::rlim_t) as c_int
+    } else {
+        1 << 20
+    };
+    for fd in first..max_fd {
+        libc::close(fd);
+    }
+}
+
+/// Waits for `pid` and exits with the same status, so that a parent waiting
+/// for this process sees the status of `pid`.
+///
+/// This must only call async-signal-safe functions.
+unsafe fn relay_exit(pid: libc::pid_t) -> ! {
+    // Signals sent to the process group also reach `pid`. This process must
+    // survive them to relay whatever they do to `pid`.
+    for &signo in &[
+        libc::SIGHUP,
+        libc::SIGINT,
+        libc::SIGQUIT,
+        libc::SIGTERM,
+        libc::SIGUSR1,
+        libc::SIGUSR2,
+        libc::SIGPIPE,
+        libc::SIGALRM,
+        libc::SIGTSTP,
+        libc::SIGTTIN,
+        libc::SIGTTOU,
+    ] {
+        libc::signal(signo, libc::SIG_IGN);
+    }
+
+    let mut status = 0;
+    while libc::waitpid(pid, &mut status, 0) < 0 {
+        if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            libc::_exit(127);
+        }
+    }
+
+    if libc::WIFSIGNALED(status) {
+        let signo = libc::WTERMSIG(status);
+        libc::signal(signo, libc::SIG_DFL);
+        libc::kill(libc::getpid(), signo);
+        libc::_exit(128 + signo);
+    }
+
+    libc::_exit(libc::WEXITSTATUS(status))
+}
+
+/// Converts a raw wait status into an ExitStatus.
+fn exit_status(status: c_int) -> ExitStatus {
+    ExitStatus::from_raw(status)
+}
+
+/// A handle to a spawned child process.
+pub struct Child {
+    pid: libc::pid_t,
+    /// The parent's end of the child's stdin, if it was piped.
+    pub stdin: Option<File>,
+    /// The parent's end of the child's stdout, if it was piped.
+    pub stdout: Option<File>,
+    /// The parent's end of the child's stderr, if it was piped.
+    pub stderr: Option<File>,
+    status: Option<ExitStatus>,
+    exit_pipe: Option<AsyncFd<File>>,
+}
+
+impl Child {
+    /// Returns the pid of the child.
+    pub fn id(&self) -> libc::pid_t {
+        self.pid
+    }
+
+    /// Sends a signal to the child.
+    pub fn signal(&self, signo: c_int) -> Result<()> {
+        if self.status.is_some() {
+            return Ok(());
+        }
+
+        // Safe because kill modifies no memory and the return value is checked.
+        if unsafe { libc::kill(self.pid, signo) } < 0 {
+            return Err(CommandError::Signal(io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    /// Blocks until the child exits, returning its exit status.
+    pub fn wait(&mut self) -> Result<ExitStatus> {
+        if let Some(status) = self.status {
+            return Ok(status);
+        }
+
+        let mut status = 0;
+        loop {
+            // Safe because waitpid only writes to status and the return value
+            // is checked.
+            let ret = unsafe { libc::waitpid(self.pid, &mut status, 0) };
+            if ret >= 0 {
+                break;
+            }
+
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(CommandError::Wait(e));
+            }
+        }
+
+        let status = exit_status(status);
+        self.status = Some(status);
+        Ok(status)
+    }
+
+    /// Returns the exit status of the child if it has exited.
+    pub fn try_wait(&mut self) -> Result<Option<ExitStatus>> {
+        if let Some(status) = self.status {
+            return Ok(Some(status));
+        }
+
+        let mut status = 0;
+        // Safe because waitpid only writes to status and the return value is
+        // checked.
+        let ret = unsafe { libc::waitpid(self.pid, &mut status, libc::WNOHANG) };
+        if ret < 0 {
+            return Err(CommandError::Wait(io::Error::last_os_error()));
+        }
+        if ret == 0 {
+            return Ok(None);
+        }
+
+        let status = exit_status(status);
+        self.status = Some(status);
+        Ok(Some(status))
+    }
+
+    /// Waits for the child to exit without blocking the executor.
+    ///
+    /// A helper thread waits for the child to exit without reaping it, then
+    /// closes a pipe to wake this up. The child is then reaped as by try_wait,
+    /// so it is safe to drop this future and call it, wait or try_wait later.
+    pub async fn wait_async(&mut self) -> Result<ExitStatus> {
+        if let Some(status) = self.status {
+            return Ok(status);
+        }
+
+        if self.exit_pipe.is_none() {
+            let (read_end, write_end) = pipe()?;
+            let read_end =
+                AsyncFd::new(read_end).map_err(|e| CommandError::CreatePipe(e.into()))?;
+
+            let pid = self.pid;
+            thread::Builder::new()
+                .name(format!("wait pid {}", pid))
+                .spawn(move || {
+                    // Safe because siginfo_t is a plain C struct for which all
+                    // zeroes is a valid value.
+                    let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+                    loop {
+                        // Safe because waitid only writes to info and the
+                        // return value is checked. WNOWAIT leaves the child to
+                        // be reaped by try_wait.
+                        let ret = unsafe {
+                            libc::waitid(
+                                libc::P_PID,
+                                pid as libc::id_t,
+                                &mut info,
+                                libc::WEXITED | libc::WNOWAIT,
+                            )
+                        };
+                        if ret == 0
+                            || io::Error::last_os_error().kind() != io::ErrorKind::Interrupted
+                        {
+                            break;
+                        }
+                    }
+
+                    // If waiting failed, try_wait reports why.
+                    drop(write_end);
+                })
+                .map_err(CommandError::SpawnWaitThread)?;
+
+            self.exit_pipe = Some(read_end);
+        }
+
+        let exit_pipe = self.exit_pipe.as_ref().unwrap();
+        let mut buf = [0u8; 1];
+        exit_pipe
+            .read_with(|mut pipe| pipe.read(&mut buf))
+            .await
+            .map_err(CommandError::ReadChildStatus)?;
+        self.exit_pipe = None;
+
+        match self.try_wait()? {
+            Some(status) => Ok(status),
+            None => Err(CommandError::Wait(io::Error::new(
+                io::ErrorKind::Other,
+                "wait thread finished before the child exited",
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+    use std::time::Duration;
+
+    use cros_async::run_one;
+    use futures::future::{select, Either};
+    use futures::pin_mut;
+
+    use crate::async_core::timer::Timer;
+
+    fn output(command: &mut Command) -> String {
+        let mut child = command
+            .stdout(ChildStdio::Piped)
+            .spawn()
+            .expect("failed to spawn child");
+
+        let mut out = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut out)
+            .expect("failed to read stdout");
+
+        assert!(child.wait().expect("failed to wait").success());
+        out
+    }
+
+    #[test]
+    fn args() {
+        let out = output(Command::new("echo").arg("foo").args(&["bar", "baz"]));
+        assert_eq!(out, "foo bar baz\n");
+    }
+
+    #[test]
+    fn arg0() {
+        let out = output(
+            Command::new("cat")
+                .arg0("-custom")
+                .arg("/proc/self/cmdline"),
+        );
+        assert_eq!(out, "-custom\0/proc/self/cmdline\0");
+    }
+
+    #[test]
+    fn env() {
+        let out = output(
+            Command::new("env")
+                .env_clear()
+                .env("FOO", "foo")
+                .env("BAR", "bar")
+                .env_remove("BAR"),
+        );
+        assert_eq!(out, "FOO=foo\n");
+    }
+
+    #[test]
+    fn current_dir() {
+        let out = output(Command::new("pwd").current_dir("/"));
+        assert_eq!(out, "/\n");
+    }
+
+    #[test]
+    fn credentials() {
+        // Changing to the current ids is allowed without privileges.
+        // Safe because getuid and getgid modify no memory.
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+
+        let out = output(Command::new("id").arg("-u").uid(uid).gid(gid));
+        assert_eq!(out, format!("{}\n", uid));
+    }
+
+    #[test]
+    fn namespaces() {
+        // Safe because geteuid modifies no memory.
+        if unsafe { libc::geteuid() } != 0 {
+            // setns requires CAP_SYS_ADMIN.
+            return;
+        }
+
+        // Re-entering the current namespaces runs the program in a
+        // grandchild, whose exit status must still reach the parent.
+        let ns = File::open("/proc/self/ns/uts").expect("failed to open namespace");
+        let mut child = Command::new("sh")
+            .args(&["-c", "exit 5"])
+            .namespaces(vec![ns])
+            .spawn()
+            .expect("failed to spawn child");
+        assert_eq!(child.wait().expect("failed to wait").code(), Some(5));
+    }
+
+    #[test]
+    fn namespaces_close_inherited_fds() {
+        // Safe because geteuid modifies no memory.
+        if unsafe { libc::geteuid() } != 0 {
+            // setns requires CAP_SYS_ADMIN.
+            return;
+        }
+
+        // A close-on-exec pipe, as another thread might have open while the
+        // child is spawned.
+        let mut fds = [0; 2];
+        // Safe because pipe2 only writes two fds to the array, and the result
+        // is checked.
+        assert_eq!(
+            unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) },
+            0
+        );
+        // Safe because the fds were just created and are owned by nothing
+        // else.
+        let (mut read_end, write_end) =
+            unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        let ns = File::open("/proc/self/ns/uts").expect("failed to open namespace");
+        let mut child = Command::new("cat")
+            .stdin(ChildStdio::Piped)
+            .namespaces(vec![ns])
+            .spawn()
+            .expect("failed to spawn child");
+
+        // The intermediate process that waits for the program must not keep
+        // the write end open.
+        drop(write_end);
+        let mut buf = [0u8; 1];
+        assert_eq!(read_end.read(&mut buf).expect("pipe still open"), 0);
+
+        drop(child.stdin.take());
+        assert_eq!(child.wait().expect("failed to wait").code(), Some(0));
+    }
+
+    #[test]
+    fn piped_stdin() {
+        let mut child = Command::new("cat")
+            .stdin(ChildStdio::Piped)
+            .stdout(ChildStdio::Piped)
+            .spawn()
+            .expect("failed to spawn child");
+
+        // Dropping stdin after writing lets cat see EOF.
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(b"hello")
+            .expect("failed to write stdin");
+
+        let mut out = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut out)
+            .expect("failed to read stdout");
+        assert_eq!(out, "hello");
+    }
+
+    #[test]
+    fn exit_code() {
+        let mut child = Command::new("sh")
+            .args(&["-c", "exit 3"])
+            .spawn()
+            .expect("failed to spawn child");
+        assert_eq!(child.wait().expect("failed to wait").code(), Some(3));
+    }
+
+    #[test]
+    fn exec_failure() {
+        match Command::new("/nonexistent/program").spawn() {
+            Err(CommandError::Exec(e)) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("spawned nonexistent program"),
+        }
+    }
+
+    #[test]
+    fn nul_in_argument() {
+        match Command::new("echo").arg("foo\0bar").spawn() {
+            Err(CommandError::NulInArgument) => {}
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("spawned with invalid argument"),
+        }
+    }
+
+    #[test]
+    fn controlling_tty() {
+        let mut pty_parent = crate::pty::PtyParent::new().expect("failed to create pty");
+        let pty_child = pty_parent.open_child().expect("failed to open pty child");
+
+        // Opening /dev/tty only succeeds if the child has a controlling tty.
+        let mut child = Command::new("sh")
+            .args(&["-c", ": </dev/tty"])
+            .controlling_tty(pty_child)
+            .spawn()
+            .expect("failed to spawn child");
+        assert!(child.wait().expect("failed to wait").success());
+    }
+
+    #[test]
+    fn wait_async() {
+        let mut child = Command::new("sh")
+            .args(&["-c", "exit 5"])
+            .spawn()
+            .expect("failed to spawn child");
+
+        let wait = child.wait_async();
+        pin_mut!(wait);
+        let status = run_one(wait)
+            .expect("failed to run executor")
+            .expect("failed to wait");
+        assert_eq!(status.code(), Some(5));
+    }
+
+    #[test]
+    fn wait_after_wait_async() {
+        let mut child = Command::new("sh")
+            .args(&["-c", "sleep 0.2; exit 6"])
+            .spawn()
+            .expect("failed to spawn child");
+
+        // Give up on waiting asynchronously before the child exits.
+        {
+            let timer = Timer::new().expect("failed to create timer");
+            timer
+                .reset(Duration::from_millis(10))
+                .expect("failed to set timer");
+            let timeout = timer.wait();
+            let wait = child.wait_async();
+            pin_mut!(timeout);
+            pin_mut!(wait);
+            match run_one(select(wait, timeout)).expect("failed to run executor") {
+                Either::Left((res, _)) => panic!("child exited early: {:?}", res),
+                Either::Right((res, _)) => res.expect("failed to wait for timer"),
+            }
+        }
+
+        // The abandoned wait must leave the child to be reaped here.
+        assert_eq!(child.wait().expect("failed to wait").code(), Some(6));
+    }
 }
@@ -0,0 +1,354 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Support for running programs inside running LXD/LXC containers.
+//!
+//! A container is found by the cgroups its processes belong to. Programs are
+//! run inside it by moving into the cgroups of its init process and entering
+//! that process's namespaces, much like `lxc-attach`.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::result;
+
+/// Namespaces to enter, in order. The user namespace comes first so that the
+/// capabilities it grants can be used to enter the namespaces it owns.
+const NAMESPACES: &[&str] = &["user", "mnt", "pid", "net", "uts", "ipc", "cgroup"];
+
+// Where cgroup hierarchies are mounted.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+// Where the unified hierarchy is mounted on hosts that also use cgroup v1.
+const CGROUP_UNIFIED_ROOT: &str = "/sys/fs/cgroup/unified";
+
+/// Errors that can be encountered while looking up or entering a container.
+#[remain::sorted]
+#[derive(Debug)]
+pub enum ContainerError {
+    /// The container name can't be used to find a container.
+    InvalidName(String),
+    /// No processes belong to the named container.
+    NotRunning(String),
+    /// Failed to open the cgroup.procs file of one of the container's cgroups.
+    OpenCgroup(PathBuf, io::Error),
+    /// Failed to open one of the container's namespaces.
+    OpenNamespace(&'static str, io::Error),
+    /// Failed to read the cgroups of the container's init process.
+    ReadCgroups(io::Error),
+    /// Failed to read a uid or gid map of the container.
+    ReadIdMap(io::Error),
+    /// Failed to list processes.
+    ReadProc(io::Error),
+    /// An id inside the container has no mapping on the host.
+    UnmappedId(u32),
+}
+
+type Result<T> = result::Result<T, ContainerError>;
+
+impl fmt::Display for ContainerError {
+    #[remain::check]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ContainerError::*;
+
+        #[remain::sorted]
+        match self {
+            InvalidName(name) => write!(f, "invalid container name '{}'", name),
+            NotRunning(name) => write!(f, "container '{}' is not running", name),
+            OpenCgroup(path, e) => write!(f, "failed to open {}: {}", path.display(), e),
+            OpenNamespace(ns, e) => write!(f, "failed to open {} namespace: {}", ns, e),
+            ReadCgroups(e) => write!(f, "failed to read container cgroups: {}", e),
+            ReadIdMap(e) => write!(f, "failed to read container id map: {}", e),
+            ReadProc(e) => write!(f, "failed to list processes: {}", e),
+            UnmappedId(id) => write!(f, "id {} is not mapped outside the container", id),
+        }
+    }
+}
+
+/// An entry from /proc/PID/cgroup.
+#[derive(Debug, PartialEq, Eq)]
+struct Cgroup {
+    /// Comma separated controllers of a v1 hierarchy, or empty for the
+    /// unified hierarchy.
+    controllers: String,
+    /// Path of the cgroup within its hierarchy.
+    path: String,
+}
+
+/// Parses the contents of /proc/PID/cgroup.
+fn parse_cgroups(contents: &str) -> Vec<Cgroup> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, ':');
+            let _id = fields.next()?;
+            let controllers = fields.next()?;
+            let path = fields.next()?;
+            Some(Cgroup {
+                controllers: controllers.to_string(),
+                path: path.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Returns the name of the container that a cgroup path belongs to, if any.
+///
+/// LXD places containers at `lxc.payload/NAME` or `lxc.payload.NAME`, and
+/// older versions of LXC at `lxc/NAME`.
+fn cgroup_container(path: &str) -> Option<&str> {
+    let mut components = path.split('/').filter(|c| !c.is_empty());
+    while let Some(component) = components.next() {
+        if component == "lxc.payload" || component == "lxc" {
+            return components.next();
+        }
+        if let Some(name) = component.strip_prefix("lxc.payload.") {
+            return Some(name);
+        }
+    }
+
+    None
+}
+
+/// Returns the mount point of the hierarchy with the given controllers.
+fn cgroup_mount(controllers: &str) -> PathBuf {
+    if controllers.is_empty() {
+        // On a host with only the unified hierarchy, it is mounted directly
+        // at the cgroup root.
+        if Path::new(CGROUP_ROOT).join("cgroup.controllers").exists() {
+            PathBuf::from(CGROUP_ROOT)
+        } else {
+            PathBuf::from(CGROUP_UNIFIED_ROOT)
+        }
+    } else {
+        // Named hierarchies such as name=systemd are mounted by their name.
+        let dir = controllers.strip_prefix("name=").unwrap_or(controllers);
+        Path::new(CGROUP_ROOT).join(dir)
+    }
+}
+
+/// Parses the parent pid from the contents of /proc/PID/stat.
+fn parse_ppid(stat: &str) -> Option<u32> {
+    // The command name may contain spaces or parentheses, so fields are
+    // counted from the last closing parenthesis.
+    let rest = &stat[stat.rfind(')')? + 1..];
+    rest.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Maps `id` through the contents of a uid_map or gid_map file.
+fn map_id(map: &str, id: u32) -> Option<u32> {
+    map.lines().find_map(|line| {
+        let fields: Vec<u32> = line
+            .split_whitespace()
+            .filter_map(|f| f.parse().ok())
+            .collect();
+        match fields.as_slice() {
+            &[inside, outside, count] if id >= inside && id - inside < count => {
+                Some(outside + (id - inside))
+            }
+            _ => None,
+        }
+    })
+}
+
+/// A running container.
+#[derive(Debug)]
+pub struct Container {
+    name: String,
+    init_pid: u32,
+    cgroups: Vec<Cgroup>,
+}
+
+impl Container {
+    /// Finds the running container with the given name.
+    pub fn find(name: &str) -> Result<Container> {
+        if name.is_empty() || name.starts_with('.') || name.contains('/') {
+            return Err(ContainerError::InvalidName(name.to_string()));
+        }
+
+        let mut members = HashSet::new();
+        for entry in fs::read_dir("/proc").map_err(ContainerError::ReadProc)? {
+            let entry = entry.map_err(ContainerError::ReadProc)?;
+            let pid: u32 = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+                Some(pid) => pid,
+                None => continue,
+            };
+
+            // Processes can exit while they're being listed, so failures to
+            // read one are ignored.
+            let contents = match fs::read_to_string(entry.path().join("cgroup")) {
+                Ok(contents) => contents,
+                Err(_) => continue,
+            };
+            if parse_cgroups(&contents)
+                .iter()
+                .any(|cgroup| cgroup_container(&cgroup.path) == Some(name))
+            {
+                members.insert(pid);
+            }
+        }
+
+        // The init process is the one whose parent is outside the container.
+        let init_pid = members
+            .iter()
+            .filter(|&pid| {
+                fs::read_to_string(format!("/proc/{}/stat", pid))
+                    .ok()
+                    .and_then(|stat| parse_ppid(&stat))
+                    .map_or(false, |ppid| !members.contains(&ppid))
+            })
+            .min()
+            .copied()
+            .ok_or_else(|| ContainerError::NotRunning(name.to_string()))?;
+
+        let contents = fs::read_to_string(format!("/proc/{}/cgroup", init_pid))
+            .map_err(ContainerError::ReadCgroups)?;
+
+        Ok(Container {
+            name: name.to_string(),
+            init_pid,
+            cgroups: parse_cgroups(&contents),
+        })
+    }
+
+    /// Returns the name of the container.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the pid of the container's init process.
+    pub fn init_pid(&self) -> u32 {
+        self.init_pid
+    }
+
+    /// Returns the path of the container's root filesystem, as seen from
+    /// outside the container.
+    pub fn root(&self) -> PathBuf {
+        PathBuf::from(format!("/proc/{}/root", self.init_pid))
+    }
+
+    /// Opens the namespaces of the container's init process, in the order
+    /// they should be entered. Namespaces shared with this process are
+    /// skipped, since entering the current user namespace fails.
+    pub fn open_namespaces(&self) -> Result<Vec<File>> {
+        let mut namespaces = Vec::new();
+        for &ns in NAMESPACES {
+            let path = format!("/proc/{}/ns/{}", self.init_pid, ns);
+            let target = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                // Kernels without support for a namespace type lack its file.
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(ContainerError::OpenNamespace(ns, e)),
+            };
+            if let Ok(current) = fs::metadata(format!("/proc/self/ns/{}", ns)) {
+                if current.dev() == target.dev() && current.ino() == target.ino() {
+                    continue;
+                }
+            }
+
+            namespaces.push(File::open(&path).map_err(|e| ContainerError::OpenNamespace(ns, e))?);
+        }
+
+        Ok(namespaces)
+    }
+
+    /// Opens the cgroup.procs files of the cgroups of the container's init
+    /// process. Hierarchies that aren't mounted are skipped.
+    pub fn open_cgroups(&self) -> Result<Vec<File>> {
+        let mut procs = Vec::new();
+        for cgroup in &self.cgroups {
+            let mount = cgroup_mount(&cgroup.controllers);
+            if !mount.exists() {
+                continue;
+            }
+
+            let path = mount
+                .join(cgroup.path.trim_start_matches('/'))
+                .join("cgroup.procs");
+            let file = OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .map_err(|e| ContainerError::OpenCgroup(path, e))?;
+            procs.push(file);
+        }
+
+        Ok(procs)
+    }
+
+    /// Maps a uid inside the container to the corresponding uid on the host.
+    pub fn host_uid(&self, uid: libc::uid_t) -> Result<libc::uid_t> {
+        self.map_id("uid_map", uid)
+    }
+
+    fn map_id(&self, map_file: &str, id: u32) -> Result<u32> {
+        let map = fs::read_to_string(format!("/proc/{}/{}", self.init_pid, map_file))
+            .map_err(ContainerError::ReadIdMap)?;
+        map_id(&map, id).ok_or(ContainerError::UnmappedId(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn container_cgroups() {
+        assert_eq!(cgroup_container("/lxc.payload/penguin"), Some("penguin"));
+        assert_eq!(
+            cgroup_container("/lxc.payload/penguin/init.scope"),
+            Some("penguin")
+        );
+        assert_eq!(
+            cgroup_container("/lxc.payload.penguin/user.slice"),
+            Some("penguin")
+        );
+        assert_eq!(cgroup_container("/lxc/penguin"), Some("penguin"));
+        assert_eq!(cgroup_container("/lxc.monitor.penguin"), None);
+        assert_eq!(cgroup_container("/system.slice/lxd.service"), None);
+        assert_eq!(cgroup_container("/"), None);
+
+        let cgroups = parse_cgroups("12:cpu,cpuacct:/lxc.payload/penguin\n0::/init.scope\n");
+        assert_eq!(
+            cgroups,
+            vec![
+                Cgroup {
+                    controllers: "cpu,cpuacct".to_string(),
+                    path: "/lxc.payload/penguin".to_string(),
+                },
+                Cgroup {
+                    controllers: "".to_string(),
+                    path: "/init.scope".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            cgroup_mount("name=systemd"),
+            PathBuf::from("/sys/fs/cgroup/systemd")
+        );
+    }
+
+    #[test]
+    fn stat_ppid() {
+        assert_eq!(parse_ppid("1234 (init) S 1 1234 1234 0 -1"), Some(1));
+        assert_eq!(parse_ppid("1234 (a) b) R 42 1234"), Some(42));
+        assert_eq!(parse_ppid("garbage"), None);
+    }
+
+    #[test]
+    fn id_maps() {
+        let map =
+            "         0    1000000 1000\n      1000       1000    1\n      1001    1001001 64535\n";
+        assert_eq!(map_id(map, 0), Some(1000000));
+        assert_eq!(map_id(map, 999), Some(1000999));
+        assert_eq!(map_id(map, 1000), Some(1000));
+        assert_eq!(map_id(map, 1001), Some(1001001));
+        assert_eq!(map_id(map, 65536), None);
+
+        let identity = "         0          0 4294967295\n";
+        assert_eq!(map_id(identity, 1000), Some(1000));
+    }
+}
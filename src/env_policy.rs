@@ -0,0 +1,215 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Policy for which environment variables are forwarded to the target
+//! program, modeled on sshd's AcceptEnv and ssh's SendEnv.
+
+use std::collections::{BTreeMap, HashMap};
+
+/// Variables that are accepted or sent when no patterns are configured.
+pub const DEFAULT_ENV_PATTERNS: &[&str] = &["TERM", "LANG", "LC_*"];
+
+/// Variables that are never accepted from a client, because they can change
+/// how the target program or the dynamic linker behaves.
+const DENIED_ENV_PATTERNS: &[&str] = &[
+    "BASH_ENV",
+    "BASH_FUNC_*",
+    "ENV",
+    "GCONV_PATH",
+    "GETCONF_DIR",
+    "HOSTALIASES",
+    "IFS",
+    "LD_*",
+    "LOCALDOMAIN",
+    "MALLOC_*",
+    "NLSPATH",
+    "PATH",
+    "PS4",
+    "RES_OPTIONS",
+    "SHELLOPTS",
+    "TMPDIR",
+];
+
+/// Returns true if `name` matches `pattern`, where `*` matches any sequence
+/// of characters and `?` matches any single character.
+pub fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let name = name.as_bytes();
+
+    let (mut p, mut n) = (0, 0);
+    // Position of the last `*` in the pattern, and the name position it was
+    // tried at, so the match can backtrack.
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+fn matches_any(patterns: &[String], name: &str) -> bool {
+    patterns.iter().any(|p| matches_pattern(p, name))
+}
+
+/// Returns true if `name` may never be set by a client.
+pub fn is_denied(name: &str) -> bool {
+    DENIED_ENV_PATTERNS.iter().any(|p| matches_pattern(p, name))
+}
+
+/// Returns true if `name` is usable as an environment variable name.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('=') && !name.contains('\0')
+}
+
+/// Decides which environment variables the target program gets.
+pub struct EnvPolicy {
+    accept: Vec<String>,
+    set: BTreeMap<String, String>,
+}
+
+impl Default for EnvPolicy {
+    fn default() -> Self {
+        EnvPolicy::new(DEFAULT_ENV_PATTERNS.iter().map(|p| p.to_string()).collect())
+    }
+}
+
+impl EnvPolicy {
+    /// Creates a policy that accepts client variables matching any of the
+    /// given patterns.
+    pub fn new(accept: Vec<String>) -> EnvPolicy {
+        EnvPolicy {
+            accept,
+            set: BTreeMap::new(),
+        }
+    }
+
+    /// Always sets `key` to `val`, overriding anything sent by the client.
+    pub fn set<K: Into<String>, V: Into<String>>(&mut self, key: K, val: V) -> &mut EnvPolicy {
+        self.set.insert(key.into(), val.into());
+        self
+    }
+
+    /// Returns true if a client may set `name`.
+    pub fn is_accepted(&self, name: &str) -> bool {
+        is_valid_name(name) && !is_denied(name) && matches_any(&self.accept, name)
+    }
+
+    /// Builds the environment for the target program from the variables the
+    /// client requested. Rejected variables are returned separately so they
+    /// can be logged.
+    pub fn apply(
+        &self,
+        requested: &HashMap<String, String>,
+    ) -> (BTreeMap<String, String>, Vec<String>) {
+        let mut env = BTreeMap::new();
+        let mut rejected = Vec::new();
+
+        for (key, val) in requested {
+            if self.is_accepted(key) && !val.contains('\0') {
+                env.insert(key.clone(), val.clone());
+            } else {
+                rejected.push(key.clone());
+            }
+        }
+        rejected.sort();
+
+        for (key, val) in &self.set {
+            env.insert(key.clone(), val.clone());
+        }
+
+        (env, rejected)
+    }
+}
+
+/// Selects the local variables that a client should send, like ssh's
+/// SendEnv. Denied variables are never sent since the server would reject
+/// them anyway.
+pub fn select_env<I>(vars: I, patterns: &[String]) -> HashMap<String, String>
+where
+    I: IntoIterator<Item = (String, String)>,
+{
+    vars.into_iter()
+        .filter(|(key, _)| is_valid_name(key) && !is_denied(key) && matches_any(patterns, key))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        assert!(matches_pattern("TERM", "TERM"));
+        assert!(!matches_pattern("TERM", "TERMINAL"));
+        assert!(matches_pattern("LC_*", "LC_ALL"));
+        assert!(matches_pattern("LC_*", "LC_"));
+        assert!(!matches_pattern("LC_*", "LANG"));
+        assert!(matches_pattern("*_PATH", "GCONV_PATH"));
+        assert!(matches_pattern("L?NG", "LANG"));
+        assert!(matches_pattern("*A*B*", "xxAyyAzzB"));
+        assert!(!matches_pattern("*A*B", "xxAyyBzz"));
+        assert!(matches_pattern("*", ""));
+    }
+
+    #[test]
+    fn policy() {
+        let mut policy = EnvPolicy::new(vec!["*".to_string()]);
+        policy.set("EDITOR", "vi");
+
+        let mut requested = HashMap::new();
+        requested.insert("TERM".to_string(), "xterm".to_string());
+        requested.insert("LD_PRELOAD".to_string(), "/tmp/evil.so".to_string());
+        requested.insert("PATH".to_string(), "/tmp".to_string());
+        requested.insert("EDITOR".to_string(), "emacs".to_string());
+        requested.insert("BAD=NAME".to_string(), "x".to_string());
+
+        let (env, rejected) = policy.apply(&requested);
+        assert_eq!(env.len(), 2);
+        assert_eq!(env["TERM"], "xterm");
+        assert_eq!(env["EDITOR"], "vi");
+        assert_eq!(rejected, vec!["BAD=NAME", "LD_PRELOAD", "PATH"]);
+    }
+
+    #[test]
+    fn default_policy() {
+        let policy = EnvPolicy::default();
+        assert!(policy.is_accepted("TERM"));
+        assert!(policy.is_accepted("LC_CTYPE"));
+        assert!(!policy.is_accepted("HOME"));
+    }
+
+    #[test]
+    fn select() {
+        let vars = vec![
+            ("TERM".to_string(), "xterm".to_string()),
+            ("LC_ALL".to_string(), "C".to_string()),
+            ("LD_PRELOAD".to_string(), "x".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ];
+        let patterns = vec!["TERM".to_string(), "LC_*".to_string(), "LD_*".to_string()];
+
+        let selected = select_env(vars, &patterns);
+        assert_eq!(selected.len(), 2);
+        assert_eq!(selected["TERM"], "xterm");
+        assert_eq!(selected["LC_ALL"], "C");
+    }
+}
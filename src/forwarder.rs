@@ -0,0 +1,830 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Forwards data between pairs of async streams or datagram sockets.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+use std::result;
+use std::time::{Duration, Instant};
+
+use cros_async::fd_executor::add_future;
+use futures::channel::mpsc;
+use futures::future::{abortable, select, try_join, AbortHandle, Either, FutureExt};
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::pin_mut;
+use futures::stream::StreamExt;
+use log::warn;
+
+use crate::async_core::timer::Timer;
+use crate::async_core::unix::{UnixAddr, UnixDatagram};
+use crate::async_core::Split;
+use crate::sockaddr::{AsyncDatagram, SocketAddr};
+
+// Size of the buffer used for each direction of a ForwarderSession.
+const FORWARDER_BUF_SIZE: usize = 4096;
+
+// Size of the buffer used to forward datagrams. Larger datagrams are truncated.
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+// How long a datagram association is kept without traffic in either direction.
+const ASSOCIATION_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Maximum number of datagram associations kept at once.
+const MAX_ASSOCIATIONS: usize = 256;
+
+// Number of datagrams queued for each association. Further datagrams are
+// dropped until the queue drains.
+const ASSOCIATION_QUEUE_LEN: usize = 16;
+
+/// Direction of data flow in a ForwarderSession.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    LocalToRemote,
+    RemoteToLocal,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Direction::LocalToRemote => write!(f, "local to remote"),
+            Direction::RemoteToLocal => write!(f, "remote to local"),
+        }
+    }
+}
+
+/// Errors that can be encountered by a ForwarderSession.
+#[remain::sorted]
+#[derive(Debug)]
+pub enum ForwarderError {
+    /// An io::Error was encountered while reading from a stream.
+    ReadFromStream(Direction, io::Error),
+    /// An io::Error was encountered while receiving a datagram.
+    ReceiveMessage(Direction, io::Error),
+    /// An io::Error was encountered while sending a datagram.
+    SendMessage(Direction, io::Error),
+    /// An io::Error was encountered while shutting down writes on a stream.
+    ShutDownStream(Direction, io::Error),
+    /// An io::Error was encountered while writing to a stream.
+    WriteToStream(Direction, io::Error),
+}
+
+type Result<T> = result::Result<T, ForwarderError>;
+
+impl ForwarderError {
+    /// Returns the direction of the forwarding that failed.
+    pub fn direction(&self) -> Direction {
+        use self::ForwarderError::*;
+
+        match self {
+            ReadFromStream(d, _)
+            | ReceiveMessage(d, _)
+            | SendMessage(d, _)
+            | ShutDownStream(d, _)
+            | WriteToStream(d, _) => *d,
+        }
+    }
+}
+
+impl fmt::Display for ForwarderError {
+    #[remain::check]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ForwarderError::*;
+
+        #[remain::sorted]
+        match self {
+            ReadFromStream(d, e) => write!(f, "failed to read from stream ({}): {}", d, e),
+            ReceiveMessage(d, e) => write!(f, "failed to receive datagram ({}): {}", d, e),
+            SendMessage(d, e) => write!(f, "failed to send datagram ({}): {}", d, e),
+            ShutDownStream(d, e) => write!(f, "failed to shut down stream ({}): {}", d, e),
+            WriteToStream(d, e) => write!(f, "failed to write to stream ({}): {}", d, e),
+        }
+    }
+}
+
+/// Copies from `from` to `to` until `from` reaches EOF, then shuts down
+/// writes on `to` so the half-close is passed along.
+async fn forward<R, W>(mut from: R, mut to: W, direction: Direction) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = [0u8; FORWARDER_BUF_SIZE];
+    loop {
+        let count = from
+            .read(&mut buf)
+            .await
+            .map_err(|e| ForwarderError::ReadFromStream(direction, e))?;
+
+        if count == 0 {
+            return to
+                .close()
+                .await
+                .map_err(|e| ForwarderError::ShutDownStream(direction, e));
+        }
+
+        to.write_all(&buf[..count])
+            .await
+            .map_err(|e| ForwarderError::WriteToStream(direction, e))?;
+    }
+}
+
+/// A ForwarderSession contains two streams that are forwarded to each other.
+pub struct ForwarderSession<L, R> {
+    local: L,
+    remote: R,
+}
+
+impl<L: Split, R: Split> ForwarderSession<L, R> {
+    pub fn new(local: L, remote: R) -> Self {
+        ForwarderSession { local, remote }
+    }
+
+    /// Forwards data in both directions until both streams have reached EOF.
+    ///
+    /// When one stream reaches EOF, writes on the other stream are shut down
+    /// and forwarding continues in the opposite direction.
+    pub async fn run(self) -> Result<()> {
+        let (local_read, local_write) = self.local.split();
+        let (remote_read, remote_write) = self.remote.split();
+
+        try_join(
+            forward(local_read, remote_write, Direction::LocalToRemote),
+            forward(remote_read, local_write, Direction::RemoteToLocal),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// Relays datagrams from `from` to `to` until `from` shuts down.
+async fn relay(from: &AsyncDatagram, to: &AsyncDatagram, direction: Direction) -> Result<()> {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    while let Some(count) = from
+        .recv(&mut buf)
+        .await
+        .map_err(|e| ForwarderError::ReceiveMessage(direction, e))?
+    {
+        to.send(&buf[..count])
+            .await
+            .map_err(|e| ForwarderError::SendMessage(direction, e))?;
+    }
+
+    Ok(())
+}
+
+/// Forwards datagrams between two connected sockets, preserving message
+/// boundaries, until either side shuts down.
+///
+/// Datagram sockets have no notion of a half-close, so forwarding stops in
+/// both directions as soon as it stops in one.
+pub async fn forward_messages(local: &AsyncDatagram, remote: &AsyncDatagram) -> Result<()> {
+    let to_remote = relay(local, remote, Direction::LocalToRemote);
+    let to_local = relay(remote, local, Direction::RemoteToLocal);
+    pin_mut!(to_remote);
+    pin_mut!(to_local);
+
+    match select(to_remote, to_local).await {
+        Either::Left((res, _)) | Either::Right((res, _)) => res,
+    }
+}
+
+/// A local peer sending datagrams to be forwarded.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Peer {
+    /// A socket bound to an address that replies can be sent to.
+    Bound(UnixAddr),
+    /// A socket that was never bound, known only by the pid of the process
+    /// that sent from it.
+    Unbound(libc::pid_t),
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Peer::Bound(addr) => write!(f, "{}", addr),
+            Peer::Unbound(pid) => write!(f, "(unnamed, pid {})", pid),
+        }
+    }
+}
+
+/// A socket connected to the remote address on behalf of one local peer,
+/// along with the queue of datagrams to send on it and a handle to stop the
+/// task serving it.
+struct Association {
+    remote: Rc<AsyncDatagram>,
+    queue: mpsc::Sender<Vec<u8>>,
+    abort: AbortHandle,
+    last_active: Rc<Cell<Instant>>,
+}
+
+type Associations = Rc<RefCell<HashMap<Peer, Association>>>;
+
+/// Removes the association for `peer` if it still uses `remote`.
+fn dissociate(associations: &Associations, peer: &Peer, remote: &Rc<AsyncDatagram>) {
+    let mut associations = associations.borrow_mut();
+    if associations
+        .get(peer)
+        .map_or(false, |a| Rc::ptr_eq(&a.remote, remote))
+    {
+        if let Some(association) = associations.remove(peer) {
+            association.abort.abort();
+        }
+    }
+}
+
+/// Removes the association that has gone the longest without traffic.
+fn evict_idlest(associations: &Associations) {
+    let mut associations = associations.borrow_mut();
+    let idlest = associations
+        .iter()
+        .min_by_key(|(_, a)| a.last_active.get())
+        .map(|(peer, _)| peer.clone());
+    if let Some(association) = idlest.and_then(|peer| associations.remove(&peer)) {
+        association.abort.abort();
+    }
+}
+
+/// Sends datagrams from `queue` to `remote` until sending fails.
+async fn send_queued(remote: &AsyncDatagram, peer: &Peer, mut queue: mpsc::Receiver<Vec<u8>>) {
+    while let Some(datagram) = queue.next().await {
+        if let Err(e) = remote.send(&datagram).await {
+            warn!("failed to forward datagram from {}: {}", peer, e);
+            return;
+        }
+    }
+}
+
+/// Sends replies from `remote` back to `peer` until receiving or sending fails.
+async fn return_replies(
+    local: &UnixDatagram,
+    peer: &Peer,
+    remote: &AsyncDatagram,
+    last_active: &Cell<Instant>,
+) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let count = match remote.recv(&mut buf).await {
+            Ok(Some(count)) => count,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("failed to receive reply for {}: {}", peer, e);
+                return;
+            }
+        };
+        last_active.set(Instant::now());
+
+        // Unbound peers have no address to reply to.
+        let addr = match peer {
+            Peer::Bound(addr) => addr,
+            Peer::Unbound(_) => continue,
+        };
+
+        if let Err(e) = local.send_to(&buf[..count], addr).await {
+            warn!("failed to return reply to {}: {}", peer, e);
+            return;
+        }
+    }
+}
+
+/// Waits until there has been no traffic for `idle_timeout` since `last_active`,
+/// or until waiting fails.
+async fn expire(peer: &Peer, last_active: &Cell<Instant>, idle_timeout: Duration) {
+    let timer = match Timer::new() {
+        Ok(timer) => timer,
+        Err(e) => {
+            warn!("failed to create expiry timer for {}: {}", peer, e);
+            return;
+        }
+    };
+
+    let mut timeout = idle_timeout;
+    loop {
+        if let Err(e) = timer.reset(timeout) {
+            warn!("failed to set expiry for association with {}: {}", peer, e);
+            return;
+        }
+        if let Err(e) = timer.wait().await {
+            warn!(
+                "failed to wait for association with {} to expire: {}",
+                peer, e
+            );
+            return;
+        }
+
+        let idle = last_active.get().elapsed();
+        if idle >= idle_timeout {
+            return;
+        }
+
+        // There was traffic since the timer was set, so wait out the rest of
+        // the timeout from the last datagram.
+        timeout = idle_timeout - idle;
+    }
+}
+
+/// Forwards datagrams queued by `peer` and returns replies to it until the
+/// association fails or goes idle, then removes it.
+async fn serve_association(
+    local: Rc<UnixDatagram>,
+    associations: Associations,
+    peer: Peer,
+    remote: Rc<AsyncDatagram>,
+    queue: mpsc::Receiver<Vec<u8>>,
+    last_active: Rc<Cell<Instant>>,
+    idle_timeout: Duration,
+) {
+    let requests = send_queued(&remote, &peer, queue);
+    let replies = return_replies(&local, &peer, &remote, &last_active);
+    let expiry = expire(&peer, &last_active, idle_timeout);
+    pin_mut!(requests);
+    pin_mut!(replies);
+    pin_mut!(expiry);
+    select(select(requests, replies), expiry).await;
+
+    dissociate(&associations, &peer, &remote);
+}
+
+/// Limits on the associations made by `forward_datagrams`.
+struct AssociationLimits {
+    idle_timeout: Duration,
+    max_associations: usize,
+}
+
+/// Connects a new socket to `remote` for `peer` and starts serving it. Returns
+/// the queue of datagrams to forward for `peer`.
+async fn associate(
+    local: &Rc<UnixDatagram>,
+    associations: &Associations,
+    peer: &Peer,
+    remote: &SocketAddr,
+    limits: &AssociationLimits,
+) -> Option<mpsc::Sender<Vec<u8>>> {
+    let remote_sock = match remote.connect_datagram().await {
+        Ok(sock) => Rc::new(sock),
+        Err(e) => {
+            warn!("failed to connect to {} for {}: {}", remote, peer, e);
+            return None;
+        }
+    };
+
+    let last_active = Rc::new(Cell::new(Instant::now()));
+    let (queue, queue_rx) = mpsc::channel(ASSOCIATION_QUEUE_LEN);
+    let (task, abort) = abortable(serve_association(
+        local.clone(),
+        associations.clone(),
+        peer.clone(),
+        remote_sock.clone(),
+        queue_rx,
+        last_active.clone(),
+        limits.idle_timeout,
+    ));
+    if let Err(e) = add_future(Box::pin(task.map(|_| ()))) {
+        warn!("failed to start serving {}: {}", peer, e);
+        return None;
+    }
+
+    if associations.borrow().len() >= limits.max_associations {
+        warn!(
+            "too many datagram associations, dropping the least recently used to make room for {}",
+            peer
+        );
+        evict_idlest(associations);
+    }
+
+    associations.borrow_mut().insert(
+        peer.clone(),
+        Association {
+            remote: remote_sock,
+            queue: queue.clone(),
+            abort,
+            last_active,
+        },
+    );
+
+    Some(queue)
+}
+
+/// Forwards datagrams received on `local` to `remote`.
+///
+/// Each peer sending to `local` is given its own socket connected to `remote`,
+/// so that replies can be routed back to the right peer. Peers that never
+/// bound their socket are told apart by the pid of the sending process, so
+/// unbound sockets in the same process share an association. Replies to
+/// unbound peers are dropped, since they have no address to send to.
+///
+/// Datagrams are queued for each peer so that a slow remote only holds up its
+/// own peer. Datagrams are dropped while a peer's queue is full.
+///
+/// Associations are removed after `ASSOCIATION_IDLE_TIMEOUT` without traffic
+/// in either direction. At most `MAX_ASSOCIATIONS` are kept, and the least
+/// recently used one is removed to make room for a new peer.
+///
+/// Tasks for each peer are spawned on the current cros_async executor. This
+/// only returns if receiving from `local` fails.
+pub async fn forward_datagrams(local: UnixDatagram, remote: SocketAddr) -> Result<()> {
+    let limits = AssociationLimits {
+        idle_timeout: ASSOCIATION_IDLE_TIMEOUT,
+        max_associations: MAX_ASSOCIATIONS,
+    };
+    forward_datagrams_with_limits(local, remote, Rc::new(RefCell::new(HashMap::new())), limits)
+        .await
+}
+
+/// Does the work of `forward_datagrams`, keeping associations in `associations`
+/// within `limits`.
+async fn forward_datagrams_with_limits(
+    local: UnixDatagram,
+    remote: SocketAddr,
+    associations: Associations,
+    limits: AssociationLimits,
+) -> Result<()> {
+    local
+        .set_pass_cred()
+        .map_err(|e| ForwarderError::ReceiveMessage(Direction::LocalToRemote, e))?;
+    let local = Rc::new(local);
+
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        let (count, addr, pid) = local
+            .recv_from_cred(&mut buf)
+            .await
+            .map_err(|e| ForwarderError::ReceiveMessage(Direction::LocalToRemote, e))?;
+        let peer = if addr.is_unnamed() {
+            Peer::Unbound(pid)
+        } else {
+            Peer::Bound(addr)
+        };
+
+        let existing = associations.borrow().get(&peer).map(|a| {
+            a.last_active.set(Instant::now());
+            a.queue.clone()
+        });
+        let mut queue = match existing {
+            Some(queue) => queue,
+            None => match associate(&local, &associations, &peer, &remote, &limits).await {
+                Some(queue) => queue,
+                None => continue,
+            },
+        };
+
+        if let Err(e) = queue.try_send(buf[..count].to_vec()) {
+            if e.is_full() {
+                warn!("dropping datagram from {}: too many queued", peer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use std::future::Future;
+    use std::io::{Read, Write};
+    use std::net::Shutdown;
+    use std::os::unix::net;
+    use std::process;
+    use std::thread;
+
+    use cros_async::run_one;
+    use futures::pin_mut;
+
+    use crate::async_core::unix::UnixStream;
+    use crate::sockaddr::DatagramListener;
+
+    #[test]
+    fn forward_both_directions() {
+        let (mut local_peer, local) = net::UnixStream::pair().unwrap();
+        let (remote, mut remote_peer) = net::UnixStream::pair().unwrap();
+        let local: UnixStream = local.try_into().unwrap();
+        let remote: UnixStream = remote.try_into().unwrap();
+
+        let peers = thread::spawn(move || {
+            local_peer.write_all(b"to remote").unwrap();
+            local_peer.shutdown(Shutdown::Write).unwrap();
+
+            let mut buf = String::new();
+            remote_peer.read_to_string(&mut buf).unwrap();
+            assert_eq!(buf, "to remote");
+
+            remote_peer.write_all(b"to local").unwrap();
+            remote_peer.shutdown(Shutdown::Write).unwrap();
+
+            buf.clear();
+            local_peer.read_to_string(&mut buf).unwrap();
+            assert_eq!(buf, "to local");
+        });
+
+        let session = ForwarderSession::new(local, remote).run();
+        pin_mut!(session);
+        run_one(session)
+            .expect("failed to run executor")
+            .expect("forwarding failed");
+
+        peers.join().unwrap();
+    }
+
+    #[test]
+    fn write_failure_direction() {
+        let (mut local_peer, local) = net::UnixStream::pair().unwrap();
+        let (remote, remote_peer) = net::UnixStream::pair().unwrap();
+        let local: UnixStream = local.try_into().unwrap();
+        let remote: UnixStream = remote.try_into().unwrap();
+
+        // With the remote peer gone, writes to the remote stream fail.
+        drop(remote_peer);
+        local_peer.write_all(b"lost").unwrap();
+
+        // The test process may not have SIGPIPE blocked, so ignore it to
+        // get EPIPE instead.
+        // Safe because SIG_IGN is a valid disposition for SIGPIPE.
+        unsafe { libc::signal(libc::SIGPIPE, libc::SIG_IGN) };
+
+        let session = ForwarderSession::new(local, remote).run();
+        pin_mut!(session);
+        let err = run_one(session)
+            .expect("failed to run executor")
+            .expect_err("forwarding to a closed stream succeeded");
+        assert_eq!(err.direction(), Direction::LocalToRemote);
+    }
+
+    /// Unix datagram sockets bound in the abstract namespace for a datagram
+    /// forwarding test.
+    struct DatagramSetup {
+        /// Address of the socket that the forwarder receives on.
+        local: SocketAddr,
+        local_sock: UnixDatagram,
+        /// Address of the server that the forwarder sends to.
+        remote: SocketAddr,
+        server: UnixDatagram,
+    }
+
+    /// Binds the sockets for a datagram forwarding test, using `name` to keep
+    /// their addresses apart from other tests.
+    fn datagram_setup(name: &str) -> DatagramSetup {
+        let bind = |addr: &SocketAddr| match addr.listen_datagram().unwrap() {
+            DatagramListener::Unix(s) => s,
+            _ => panic!("abstract address bound a non-unix socket"),
+        };
+
+        let local =
+            SocketAddr::UnixAbstract(format!("vsh-forwarder-{}-local-{}", name, process::id()));
+        let remote =
+            SocketAddr::UnixAbstract(format!("vsh-forwarder-{}-remote-{}", name, process::id()));
+        DatagramSetup {
+            local_sock: bind(&local),
+            server: bind(&remote),
+            local,
+            remote,
+        }
+    }
+
+    /// Runs `test` alongside `forwarder`, which must not stop first.
+    fn run_forwarder<F, T>(forwarder: F, test: T)
+    where
+        F: Future<Output = Result<()>>,
+        T: Future<Output = ()>,
+    {
+        pin_mut!(forwarder);
+        pin_mut!(test);
+
+        match run_one(select(forwarder, test)).expect("failed to run executor") {
+            Either::Left((res, _)) => panic!("forwarder exited early: {:?}", res),
+            Either::Right(((), _)) => {}
+        }
+    }
+
+    #[test]
+    fn datagram_associations() {
+        let DatagramSetup {
+            local,
+            local_sock,
+            remote,
+            server,
+        } = datagram_setup("associations");
+
+        async fn exchange(server: UnixDatagram, local: &SocketAddr) {
+            let client1 = local.connect_datagram().await.unwrap();
+            let client2 = local.connect_datagram().await.unwrap();
+
+            client1.send(b"one").await.unwrap();
+            client2.send(b"two").await.unwrap();
+
+            // Each client is forwarded from its own socket, so the server can
+            // reply to them separately.
+            let mut buf = [0u8; 16];
+            let (count, peer1) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..count], b"one");
+            let (count, peer2) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..count], b"two");
+            assert_ne!(peer1, peer2);
+
+            server.send_to(b"reply two", &peer2).await.unwrap();
+            server.send_to(b"reply one", &peer1).await.unwrap();
+
+            let count = client1.recv(&mut buf).await.unwrap().unwrap();
+            assert_eq!(&buf[..count], b"reply one");
+            let count = client2.recv(&mut buf).await.unwrap().unwrap();
+            assert_eq!(&buf[..count], b"reply two");
+        }
+
+        let forwarder = forward_datagrams(local_sock, remote);
+        run_forwarder(forwarder, exchange(server, &local));
+    }
+
+    #[test]
+    fn datagram_unbound_peers() {
+        let DatagramSetup {
+            local,
+            local_sock,
+            remote,
+            server,
+        } = datagram_setup("unbound");
+        let local = match local {
+            SocketAddr::UnixAbstract(name) => UnixAddr::new_abstract(name.as_bytes()).unwrap(),
+            _ => panic!("datagram test address isn't abstract"),
+        };
+
+        let associations: Associations = Rc::new(RefCell::new(HashMap::new()));
+
+        async fn exchange(server: UnixDatagram, local: &UnixAddr, associations: &Associations) {
+            let client: UnixDatagram = net::UnixDatagram::unbound().unwrap().try_into().unwrap();
+            client.send_to(b"parent", local).await.unwrap();
+
+            let (sockaddr, len) = local.as_sockaddr();
+            // Safe because the child only makes async-signal-safe calls before
+            // exiting.
+            let child = unsafe { libc::fork() };
+            assert!(child >= 0);
+            if child == 0 {
+                // Safe because sendto only reads from the given buffer and
+                // address, and the child exits right after.
+                unsafe {
+                    let fd = libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM, 0);
+                    let msg = b"child";
+                    let ret = libc::sendto(
+                        fd,
+                        msg.as_ptr() as *const libc::c_void,
+                        msg.len(),
+                        0,
+                        sockaddr,
+                        len,
+                    );
+                    libc::_exit(if ret < 0 { 1 } else { 0 });
+                }
+            }
+            let mut status = 0;
+            // Safe because waitpid only writes to status.
+            assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+            assert_eq!(status, 0);
+
+            // Each unbound peer is forwarded from its own socket. The two
+            // associations may forward in either order.
+            let mut buf = [0u8; 16];
+            let (count, peer1) = server.recv_from(&mut buf).await.unwrap();
+            let mut received = vec![buf[..count].to_vec()];
+            let (count, peer2) = server.recv_from(&mut buf).await.unwrap();
+            received.push(buf[..count].to_vec());
+            received.sort();
+            assert_eq!(received, [&b"child"[..], &b"parent"[..]]);
+            assert_ne!(peer1, peer2);
+
+            let associations = associations.borrow();
+            assert_eq!(associations.len(), 2);
+            assert!(associations.contains_key(&Peer::Unbound(process::id() as libc::pid_t)));
+            assert!(associations.contains_key(&Peer::Unbound(child)));
+        }
+
+        let forwarder = forward_datagrams_with_limits(
+            local_sock,
+            remote,
+            associations.clone(),
+            AssociationLimits {
+                idle_timeout: ASSOCIATION_IDLE_TIMEOUT,
+                max_associations: MAX_ASSOCIATIONS,
+            },
+        );
+        run_forwarder(forwarder, exchange(server, &local, &associations));
+    }
+
+    #[test]
+    fn datagram_idle_association_expires() {
+        let DatagramSetup {
+            local,
+            local_sock,
+            remote,
+            server,
+        } = datagram_setup("idle");
+
+        let idle_timeout = Duration::from_millis(200);
+        let associations: Associations = Rc::new(RefCell::new(HashMap::new()));
+
+        async fn exchange(
+            server: UnixDatagram,
+            local: &SocketAddr,
+            associations: &Associations,
+            idle_timeout: Duration,
+        ) {
+            let mut buf = [0u8; 16];
+
+            let quiet = local.connect_datagram().await.unwrap();
+            quiet.send(b"quiet").await.unwrap();
+            server.recv_from(&mut buf).await.unwrap();
+            let quiet_key = associations.borrow().keys().next().unwrap().clone();
+
+            let chatty = local.connect_datagram().await.unwrap();
+            chatty.send(b"chatty").await.unwrap();
+            let (_, chatty_peer) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(associations.borrow().len(), 2);
+
+            // The quiet peer goes away without ever getting a reply, while the
+            // chatty peer keeps its association alive with traffic in
+            // alternating directions for twice the idle timeout.
+            drop(quiet);
+            let timer = Timer::new().unwrap();
+            for i in 0..4 {
+                timer.reset(idle_timeout / 2).unwrap();
+                timer.wait().await.unwrap();
+
+                if i % 2 == 0 {
+                    chatty.send(b"ping").await.unwrap();
+                    let (count, peer) = server.recv_from(&mut buf).await.unwrap();
+                    assert_eq!(&buf[..count], b"ping");
+                    // Still forwarded from the same socket, so the chatty
+                    // peer's association was never replaced.
+                    assert_eq!(peer, chatty_peer);
+                } else {
+                    server.send_to(b"pong", &chatty_peer).await.unwrap();
+                    let count = chatty.recv(&mut buf).await.unwrap().unwrap();
+                    assert_eq!(&buf[..count], b"pong");
+                }
+            }
+
+            let associations = associations.borrow();
+            assert_eq!(associations.len(), 1);
+            assert!(!associations.contains_key(&quiet_key));
+        }
+
+        let limits = AssociationLimits {
+            idle_timeout,
+            max_associations: MAX_ASSOCIATIONS,
+        };
+        let forwarder =
+            forward_datagrams_with_limits(local_sock, remote, associations.clone(), limits);
+        run_forwarder(
+            forwarder,
+            exchange(server, &local, &associations, idle_timeout),
+        );
+    }
+
+    #[test]
+    fn datagram_association_limit() {
+        let DatagramSetup {
+            local,
+            local_sock,
+            remote,
+            server,
+        } = datagram_setup("limit");
+
+        let associations: Associations = Rc::new(RefCell::new(HashMap::new()));
+
+        async fn exchange(server: UnixDatagram, local: &SocketAddr, associations: &Associations) {
+            let mut buf = [0u8; 16];
+
+            let client1 = local.connect_datagram().await.unwrap();
+            let client2 = local.connect_datagram().await.unwrap();
+            let client3 = local.connect_datagram().await.unwrap();
+
+            client1.send(b"one").await.unwrap();
+            server.recv_from(&mut buf).await.unwrap();
+            client2.send(b"two").await.unwrap();
+            server.recv_from(&mut buf).await.unwrap();
+            client1.send(b"one again").await.unwrap();
+            server.recv_from(&mut buf).await.unwrap();
+
+            // Client 2 has been quiet the longest, so it makes room for
+            // client 3.
+            client3.send(b"three").await.unwrap();
+            let (_, peer3) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(associations.borrow().len(), 2);
+
+            server.send_to(b"reply three", &peer3).await.unwrap();
+            let count = client3.recv(&mut buf).await.unwrap().unwrap();
+            assert_eq!(&buf[..count], b"reply three");
+        }
+
+        let limits = AssociationLimits {
+            idle_timeout: ASSOCIATION_IDLE_TIMEOUT,
+            max_associations: 2,
+        };
+        let forwarder =
+            forward_datagrams_with_limits(local_sock, remote, associations.clone(), limits);
+        run_forwarder(forwarder, exchange(server, &local, &associations));
+    }
+}
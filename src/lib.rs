@@ -2,7 +2,16 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-mod async_core;
-
+pub mod async_core;
+pub mod command;
+pub mod container;
+pub mod env_policy;
+pub mod forwarder;
+pub mod port_forwarder;
 pub mod pty;
+pub mod signal;
+pub mod sockaddr;
+pub mod termios;
+pub mod user;
+pub mod vm_lookup;
 pub mod vsh_wire;
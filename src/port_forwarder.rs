@@ -0,0 +1,295 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Maintains a changing set of listening sockets, forwarding every
+//! connection accepted on each one to its own remote address.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::rc::{Rc, Weak};
+use std::result;
+use std::task::Poll;
+
+use cros_async::fd_executor::{self, add_future};
+use futures::future::{abortable, poll_fn, AbortHandle, FutureExt};
+use futures::pin_mut;
+use log::{info, warn};
+
+use crate::forwarder::ForwarderSession;
+use crate::sockaddr::{AsyncStream, Listener, SocketAddr};
+
+/// Errors that can be encountered while updating a PortForwarder.
+#[remain::sorted]
+#[derive(Debug)]
+pub enum PortForwarderError {
+    /// Failed to listen on a local address.
+    Listen(SocketAddr, io::Error),
+    /// Failed to start accepting connections on a local address.
+    SpawnListener(SocketAddr, fd_executor::Error),
+}
+
+type Result<T> = result::Result<T, PortForwarderError>;
+
+impl fmt::Display for PortForwarderError {
+    #[remain::check]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::PortForwarderError::*;
+
+        #[remain::sorted]
+        match self {
+            Listen(addr, e) => write!(f, "failed to listen on {}: {}", addr, e),
+            SpawnListener(addr, e) => write!(f, "failed to accept on {}: {}", addr, e),
+        }
+    }
+}
+
+/// Forwards one accepted connection to `remote` until both sides have closed.
+async fn forward_connection(local: AsyncStream, remote: SocketAddr) {
+    let remote_stream = match remote.connect().await {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("failed to connect to {}: {}", remote, e);
+            return;
+        }
+    };
+
+    if let Err(e) = ForwarderSession::new(local, remote_stream).run().await {
+        warn!("forwarding to {} failed: {}", remote, e);
+    }
+}
+
+/// Waits for a connection on `listener`. Returns None once the listener has
+/// been dropped.
+///
+/// The listener is only borrowed while it is being polled, so that whoever
+/// owns it can close it at any time. Accepting keeps no state between polls,
+/// so a new accept is started on each one.
+async fn accept(listener: &Weak<Listener>) -> Option<io::Result<AsyncStream>> {
+    poll_fn(|cx| {
+        let listener = match listener.upgrade() {
+            Some(listener) => listener,
+            None => return Poll::Ready(None),
+        };
+        let accept = listener.accept();
+        pin_mut!(accept);
+        accept.poll(cx).map(Some)
+    })
+    .await
+}
+
+/// Accepts connections on `listener` until it is dropped, forwarding each one
+/// to `remote` on its own task.
+async fn accept_connections(listener: Weak<Listener>, local: SocketAddr, remote: SocketAddr) {
+    while let Some(result) = accept(&listener).await {
+        let stream = match result {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("failed to accept connection on {}: {}", local, e);
+                continue;
+            }
+        };
+
+        if let Err(e) = add_future(Box::pin(forward_connection(stream, remote.clone()))) {
+            warn!("failed to start forwarding to {}: {}", remote, e);
+        }
+    }
+}
+
+/// A local address that is being listened on.
+///
+/// The accepting task only holds a weak reference to the listener, so
+/// dropping a Forward closes the listener immediately and the address can be
+/// listened on again right away.
+struct Forward {
+    remote: SocketAddr,
+    _listener: Rc<Listener>,
+    abort: AbortHandle,
+}
+
+/// Forwards connections on a set of local addresses, each to its own remote
+/// address, like chunneld does for TCP ports in VMs.
+///
+/// Each local address is served by a task on the current cros_async executor.
+#[derive(Default)]
+pub struct PortForwarder {
+    forwards: HashMap<SocketAddr, Forward>,
+}
+
+impl PortForwarder {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Replaces the set of forwarded addresses with `forwards`, which maps
+    /// local addresses to the remote addresses they forward to.
+    ///
+    /// Local addresses that are no longer present or now forward somewhere
+    /// else stop listening before any new addresses are listened on, but
+    /// connections that were already accepted on them are left running.
+    /// Addresses that fail to listen are skipped, and the first failure is
+    /// returned once the rest have been updated.
+    pub fn update(&mut self, mut forwards: HashMap<SocketAddr, SocketAddr>) -> Result<()> {
+        // Stop listening on addresses that were removed or now forward
+        // somewhere else.
+        self.forwards.retain(|local, forward| {
+            let keep = forwards.get(local) == Some(&forward.remote);
+            if !keep {
+                info!("stopped forwarding {} to {}", local, forward.remote);
+                forward.abort.abort();
+            }
+            keep
+        });
+        let existing = &self.forwards;
+        forwards.retain(|local, _| !existing.contains_key(local));
+
+        let mut result = Ok(());
+        for (local, remote) in forwards {
+            if let Err(e) = self.add(local, remote) {
+                warn!("{}", e);
+                if result.is_ok() {
+                    result = Err(e);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Starts listening on `local` and forwarding to `remote`.
+    fn add(&mut self, local: SocketAddr, remote: SocketAddr) -> Result<()> {
+        let listener = Rc::new(
+            local
+                .listen()
+                .map_err(|e| PortForwarderError::Listen(local.clone(), e))?,
+        );
+
+        let (task, abort) = abortable(accept_connections(
+            Rc::downgrade(&listener),
+            local.clone(),
+            remote.clone(),
+        ));
+        add_future(Box::pin(task.map(|_| ())))
+            .map_err(|e| PortForwarderError::SpawnListener(local.clone(), e))?;
+
+        info!("forwarding {} to {}", local, remote);
+        self.forwards.insert(
+            local,
+            Forward {
+                remote,
+                _listener: listener,
+                abort,
+            },
+        );
+        Ok(())
+    }
+
+    /// Returns the local addresses currently being forwarded.
+    pub fn local_addrs(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.forwards.keys()
+    }
+}
+
+impl Drop for PortForwarder {
+    fn drop(&mut self) {
+        for forward in self.forwards.values() {
+            forward.abort.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    use cros_async::run_one;
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn update_forwards() {
+        let local = SocketAddr::UnixAbstract(format!("vsh-port-forwarder-local-{}", process::id()));
+        let remote =
+            SocketAddr::UnixAbstract(format!("vsh-port-forwarder-remote-{}", process::id()));
+        let server = remote.listen().unwrap();
+
+        let test = async {
+            let mut forwarder = PortForwarder::new();
+            let mut forwards = HashMap::new();
+            forwards.insert(local.clone(), remote.clone());
+            forwarder.update(forwards.clone()).unwrap();
+
+            // Updating with the same forwards leaves the listener in place.
+            forwarder.update(forwards).unwrap();
+            assert_eq!(forwarder.local_addrs().collect::<Vec<_>>(), vec![&local]);
+
+            let mut client = local.connect().await.unwrap();
+            client.write_all(b"ping").await.unwrap();
+            client.close().await.unwrap();
+
+            let mut conn = server.accept().await.unwrap();
+            let mut buf = Vec::new();
+            conn.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, b"ping");
+
+            // Nothing is listening on the local address as soon as it is
+            // removed.
+            forwarder.update(HashMap::new()).unwrap();
+            assert_eq!(forwarder.local_addrs().count(), 0);
+            let err = local.connect().await.err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        };
+        pin_mut!(test);
+        run_one(test).expect("failed to run executor");
+    }
+
+    #[test]
+    fn change_remote() {
+        let local = SocketAddr::UnixAbstract(format!("vsh-port-forwarder-moved-{}", process::id()));
+        let old_remote =
+            SocketAddr::UnixAbstract(format!("vsh-port-forwarder-old-{}", process::id()));
+        let new_remote =
+            SocketAddr::UnixAbstract(format!("vsh-port-forwarder-new-{}", process::id()));
+        let server = new_remote.listen().unwrap();
+
+        let test = async {
+            let mut forwarder = PortForwarder::new();
+            let mut forwards = HashMap::new();
+            forwards.insert(local.clone(), old_remote.clone());
+            forwarder.update(forwards.clone()).unwrap();
+
+            // The old listener is closed before listening again, so the
+            // local address is free without running the executor first.
+            forwards.insert(local.clone(), new_remote.clone());
+            forwarder.update(forwards).unwrap();
+
+            let mut client = local.connect().await.unwrap();
+            client.write_all(b"moved").await.unwrap();
+            client.close().await.unwrap();
+
+            let mut conn = server.accept().await.unwrap();
+            let mut buf = Vec::new();
+            conn.read_to_end(&mut buf).await.unwrap();
+            assert_eq!(buf, b"moved");
+        };
+        pin_mut!(test);
+        run_one(test).expect("failed to run executor");
+    }
+
+    #[test]
+    fn listen_failure() {
+        let local = SocketAddr::Unix("/nonexistent/vsh-port-forwarder".into());
+        let remote = SocketAddr::UnixAbstract("unused".to_string());
+
+        let mut forwarder = PortForwarder::new();
+        let mut forwards = HashMap::new();
+        forwards.insert(local.clone(), remote);
+        match forwarder.update(forwards) {
+            Err(PortForwarderError::Listen(addr, _)) => assert_eq!(addr, local),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(forwarder.local_addrs().count(), 0);
+    }
+}
@@ -10,23 +10,32 @@
 
 use std::fmt;
 use std::fs::File;
-use std::io;
+use std::io::{self, Read, Write};
 use std::mem;
 use std::os::raw::{c_char, c_ushort};
 use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 use std::process::Stdio;
 use std::result;
 
+use crate::async_core::AsyncFd;
+use crate::termios::{SetAction, Termios};
+
 /// Errors that can be encountered by a Pty.
 #[remain::sorted]
 #[derive(Debug)]
 pub enum PtyError {
+    DupPtyChild(io::Error),
+    DupPtyParent(io::Error),
+    GetDimensions(io::Error),
     GetPtyName(io::Error),
+    GetTermios(io::Error),
     GrantPt(io::Error),
     OpenPtyChild(io::Error),
     OpenPtyParent(io::Error),
     SetControllingTty(io::Error),
     SetDimensions(io::Error),
+    SetOwner(io::Error),
+    SetTermios(io::Error),
     UnlockPt(io::Error),
 }
 
@@ -39,21 +48,87 @@ impl fmt::Display for PtyError {
 
         #[remain::sorted]
         match self {
+            DupPtyChild(e) => write!(f, "failed to duplicate pty child: {}", e),
+            DupPtyParent(e) => write!(f, "failed to duplicate pty parent: {}", e),
+            GetDimensions(e) => write!(f, "failed to get pty dimensions: {}", e),
             GetPtyName(e) => write!(f, "failed to get pt name: {}", e),
+            GetTermios(e) => write!(f, "failed to get pty termios: {}", e),
             GrantPt(e) => write!(f, "failed to grant pt: {}", e),
             OpenPtyChild(e) => write!(f, "failed to open pty child: {}", e),
             OpenPtyParent(e) => write!(f, "failed to open pty parent: {}", e),
             SetControllingTty(e) => write!(f, "failed to set controlling tty: {}", e),
             SetDimensions(e) => write!(f, "failed to set pty dimensions: {}", e),
+            SetOwner(e) => write!(f, "failed to set pty owner: {}", e),
+            SetTermios(e) => write!(f, "failed to set pty termios: {}", e),
             UnlockPt(e) => write!(f, "failed to unlock pt: {}", e),
         }
     }
 }
 
+/// The window size of a terminal. Pixel dimensions are 0 if unknown.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Dimensions {
+    pub rows: c_ushort,
+    pub cols: c_ushort,
+    pub width_px: c_ushort,
+    pub height_px: c_ushort,
+}
+
+impl Dimensions {
+    /// Creates Dimensions with the given rows and cols, and unknown pixel size.
+    pub fn new(rows: c_ushort, cols: c_ushort) -> Dimensions {
+        Dimensions {
+            rows,
+            cols,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<libc::winsize> for Dimensions {
+    fn from(winsize: libc::winsize) -> Self {
+        Dimensions {
+            rows: winsize.ws_row,
+            cols: winsize.ws_col,
+            width_px: winsize.ws_xpixel,
+            height_px: winsize.ws_ypixel,
+        }
+    }
+}
+
+impl From<Dimensions> for libc::winsize {
+    fn from(dims: Dimensions) -> Self {
+        libc::winsize {
+            ws_row: dims.rows,
+            ws_col: dims.cols,
+            ws_xpixel: dims.width_px,
+            ws_ypixel: dims.height_px,
+        }
+    }
+}
+
+/// Gets the window size of the terminal referred to by `fd`.
+pub fn get_dimensions(fd: &dyn AsRawFd) -> io::Result<Dimensions> {
+    // Safe because winsize is a plain C struct for which all zeroes is a
+    // valid value.
+    let mut winsize: libc::winsize = unsafe { mem::zeroed() };
+
+    // Safe because this ioctl only writes to the provided winsize struct and
+    // the return value is checked.
+    if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TIOCGWINSZ, &mut winsize) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(winsize.into())
+}
+
 pub struct PtyParent {
     fd: RawFd,
 }
 
+/// A pty parent in nonblocking mode, for use with the async executor.
+pub type AsyncPtyParent = AsyncFd<PtyParent>;
+
 impl PtyParent {
     pub fn new() -> Result<Self> {
         // Safe because the posix_openpt function modifies no memory and the
@@ -87,14 +162,26 @@ impl PtyParent {
         })
     }
 
+    /// Creates a new PtyParent that refers to the same pseudoterminal.
+    pub fn try_clone(&self) -> Result<PtyParent> {
+        // Safe because fcntl with F_DUPFD_CLOEXEC modifies no memory and the
+        // return value is checked.
+        let fd = unsafe { libc::fcntl(self.fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(PtyError::DupPtyParent(io::Error::last_os_error()));
+        }
+
+        Ok(PtyParent { fd })
+    }
+
+    /// Gets the dimensions of the pseudoterminal window.
+    pub fn get_dimensions(&self) -> Result<Dimensions> {
+        get_dimensions(self).map_err(PtyError::GetDimensions)
+    }
+
     /// Sets the dimensions of the pseudoterminal window.
-    pub fn set_dimensions(&mut self, rows: c_ushort, cols: c_ushort) -> Result<()> {
-        let winsize = libc::winsize {
-            ws_row: rows,
-            ws_col: cols,
-            ws_xpixel: 0,
-            ws_ypixel: 0,
-        };
+    pub fn set_dimensions(&self, dims: Dimensions) -> Result<()> {
+        let winsize = libc::winsize::from(dims);
         // Safe because this ioctl modifies no memory and the return value is
         // checked.
         let ret = unsafe { libc::ioctl(self.fd, libc::TIOCSWINSZ, &winsize) };
@@ -105,6 +192,16 @@ impl PtyParent {
         Ok(())
     }
 
+    /// Gets the terminal attributes of the pseudoterminal.
+    pub fn get_termios(&self) -> Result<Termios> {
+        Termios::get(self).map_err(PtyError::GetTermios)
+    }
+
+    /// Sets the terminal attributes of the pseudoterminal immediately.
+    pub fn set_termios(&self, termios: &Termios) -> Result<()> {
+        termios.set(self, SetAction::Now).map_err(PtyError::SetTermios)
+    }
+
     /// Opens a child pseudoterminal connected to this parent.
     pub fn open_child(&mut self) -> Result<PtyChild> {
         let mut pt_name = [0u8; 64];
@@ -143,11 +240,103 @@ impl AsRawFd for PtyParent {
     }
 }
 
+impl Read for PtyParent {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Read for &PtyParent {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Safe because read writes at most buf.len() bytes to buf and the
+        // return value is checked.
+        let ret = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+
+            // Reading from a pty parent fails with EIO once every copy of the
+            // pty child has been closed, which is equivalent to EOF.
+            if err.raw_os_error() == Some(libc::EIO) {
+                return Ok(0);
+            }
+            return Err(err);
+        }
+
+        Ok(ret as usize)
+    }
+}
+
+impl Write for PtyParent {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl Write for &PtyParent {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Safe because write reads at most buf.len() bytes from buf and the
+        // return value is checked.
+        let ret = unsafe { libc::write(self.fd, buf.as_ptr() as *const libc::c_void, buf.len()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(ret as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // Writes go straight to the pty, so there is nothing to flush.
+        Ok(())
+    }
+}
+
 pub struct PtyChild {
     fd: RawFd,
 }
 
 impl PtyChild {
+    /// Creates a new PtyChild that refers to the same pseudoterminal.
+    pub fn try_clone(&self) -> Result<PtyChild> {
+        // Safe because fcntl with F_DUPFD_CLOEXEC modifies no memory and the
+        // return value is checked.
+        let fd = unsafe { libc::fcntl(self.fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(PtyError::DupPtyChild(io::Error::last_os_error()));
+        }
+
+        Ok(PtyChild { fd })
+    }
+
+    /// Gets the terminal attributes of the pseudoterminal.
+    pub fn get_termios(&self) -> Result<Termios> {
+        Termios::get(self).map_err(PtyError::GetTermios)
+    }
+
+    /// Sets the terminal attributes of the pseudoterminal immediately.
+    pub fn set_termios(&self, termios: &Termios) -> Result<()> {
+        termios.set(self, SetAction::Now).map_err(PtyError::SetTermios)
+    }
+
+    /// Gives ownership of the pseudoterminal to the given user, so that only
+    /// they can read or write it. The group is left unchanged and gets no
+    /// access.
+    pub fn set_owner(&self, uid: libc::uid_t) -> Result<()> {
+        // A gid of -1 leaves the group unchanged.
+        // Safe because fchown and fchmod modify no memory and the return
+        // values are checked.
+        unsafe {
+            if libc::fchown(self.fd, uid, !0) < 0 || libc::fchmod(self.fd, 0o600) < 0 {
+                return Err(PtyError::SetOwner(io::Error::last_os_error()));
+            }
+        }
+
+        Ok(())
+    }
+
     /// Sets this pseudoterminal as the controlling tty for the current process.
     pub fn set_controlling_tty(&mut self) -> Result<()> {
         // Safe because this ioctl modifies no memory and the return value is
@@ -193,6 +382,23 @@ impl From<PtyChild> for Stdio {
 mod tests {
     use super::*;
 
+    use cros_async::{complete2, run_one};
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use futures::pin_mut;
+
+    /// Opens a pty child, putting it in raw mode so data passes through
+    /// unchanged.
+    fn open_raw_child(pty_parent: &mut PtyParent) -> File {
+        let pty_child = pty_parent.open_child().expect("open pty child");
+
+        let mut termios = pty_child.get_termios().expect("get pty termios");
+        termios.make_raw();
+        pty_child.set_termios(&termios).expect("set pty termios");
+
+        // Safe because pty_child owns the fd and gives it up here.
+        unsafe { File::from_raw_fd(pty_child.into_raw_fd()) }
+    }
+
     #[test]
     fn create_pty_parent() {
         PtyParent::new().expect("create new PtyParent");
@@ -200,8 +406,21 @@ mod tests {
 
     #[test]
     fn set_dimensions() {
-        let mut pty_parent = PtyParent::new().expect("create new PtyParent");
-        pty_parent.set_dimensions(80, 25).expect("set pty dimensions");
+        let pty_parent = PtyParent::new().expect("create new PtyParent");
+        pty_parent.set_dimensions(Dimensions::new(80, 25)).expect("set pty dimensions");
+        assert_eq!(
+            pty_parent.get_dimensions().expect("get pty dimensions"),
+            Dimensions::new(80, 25)
+        );
+
+        let dims = Dimensions {
+            rows: 24,
+            cols: 80,
+            width_px: 640,
+            height_px: 384,
+        };
+        pty_parent.set_dimensions(dims).expect("set pty dimensions");
+        assert_eq!(pty_parent.get_dimensions().expect("get pty dimensions"), dims);
     }
 
     #[test]
@@ -210,4 +429,76 @@ mod tests {
         let pty_child = pty_parent.open_child().expect("open pty child");
         let _stdio: Stdio = pty_child.into();
     }
+
+    #[test]
+    fn clone_child() {
+        let mut pty_parent = PtyParent::new().expect("create new PtyParent");
+        let pty_child = pty_parent.open_child().expect("open pty child");
+        let pty_child_clone = pty_child.try_clone().expect("clone pty child");
+        assert_ne!(pty_child.as_raw_fd(), pty_child_clone.as_raw_fd());
+    }
+
+    #[test]
+    fn read_write() {
+        let mut pty_parent = PtyParent::new().expect("create new PtyParent");
+        let mut child = open_raw_child(&mut pty_parent);
+
+        pty_parent.write_all(b"foo").expect("write to pty parent");
+        let mut buf = [0u8; 3];
+        child.read_exact(&mut buf).expect("read from pty child");
+        assert_eq!(&buf, b"foo");
+
+        child.write_all(b"bar").expect("write to pty child");
+        pty_parent.read_exact(&mut buf).expect("read from pty parent");
+        assert_eq!(&buf, b"bar");
+    }
+
+    #[test]
+    fn read_eof_after_child_closed() {
+        let mut pty_parent = PtyParent::new().expect("create new PtyParent");
+        let child = open_raw_child(&mut pty_parent);
+        drop(child);
+
+        let mut buf = [0u8; 16];
+        assert_eq!(pty_parent.read(&mut buf).expect("read from pty parent"), 0);
+    }
+
+    #[test]
+    fn async_read_write() {
+        let mut pty_parent = PtyParent::new().expect("create new PtyParent");
+        let mut child = open_raw_child(&mut pty_parent);
+        let mut pty_parent = AsyncFd::new(pty_parent).expect("make pty parent async");
+
+        async fn write_foo(pty_parent: &mut AsyncPtyParent) {
+            pty_parent.write_all(b"foo").await.unwrap();
+        }
+
+        {
+            let w = write_foo(&mut pty_parent);
+            pin_mut!(w);
+            run_one(w).unwrap();
+        }
+
+        let mut buf = [0u8; 3];
+        child.read_exact(&mut buf).expect("read from pty child");
+        assert_eq!(&buf, b"foo");
+
+        async fn read_all(pty_parent: &mut AsyncPtyParent) -> Vec<u8> {
+            let mut buf = Vec::new();
+            pty_parent.read_to_end(&mut buf).await.unwrap();
+            buf
+        }
+
+        async fn write_and_close(mut child: File) {
+            child.write_all(b"bar").unwrap();
+        }
+
+        let r = read_all(&mut pty_parent);
+        pin_mut!(r);
+        let c = write_and_close(child);
+        pin_mut!(c);
+
+        let (buf, ()) = complete2(r, c).unwrap();
+        assert_eq!(buf, b"bar");
+    }
 }
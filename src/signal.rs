@@ -0,0 +1,80 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Conversions between POSIX signal numbers and vsh protocol signals.
+
+use std::os::raw::c_int;
+
+use vsh_proto::vsh::Signal;
+
+/// Converts a POSIX signal number into a vsh protocol Signal, if the signal
+/// can be forwarded.
+pub fn to_proto(signo: c_int) -> Option<Signal> {
+    let signal = match signo {
+        libc::SIGHUP => Signal::SIGNAL_HUP,
+        libc::SIGINT => Signal::SIGNAL_INT,
+        libc::SIGQUIT => Signal::SIGNAL_QUIT,
+        libc::SIGKILL => Signal::SIGNAL_KILL,
+        libc::SIGUSR1 => Signal::SIGNAL_USR1,
+        libc::SIGUSR2 => Signal::SIGNAL_USR2,
+        libc::SIGTERM => Signal::SIGNAL_TERM,
+        libc::SIGCONT => Signal::SIGNAL_CONT,
+        libc::SIGSTOP => Signal::SIGNAL_STOP,
+        libc::SIGWINCH => Signal::SIGNAL_WINCH,
+        _ => return None,
+    };
+
+    Some(signal)
+}
+
+/// Converts a vsh protocol Signal into a POSIX signal number.
+pub fn from_proto(signal: Signal) -> Option<c_int> {
+    let signo = match signal {
+        Signal::SIGNAL_UNKNOWN => return None,
+        Signal::SIGNAL_HUP => libc::SIGHUP,
+        Signal::SIGNAL_INT => libc::SIGINT,
+        Signal::SIGNAL_QUIT => libc::SIGQUIT,
+        Signal::SIGNAL_KILL => libc::SIGKILL,
+        Signal::SIGNAL_USR1 => libc::SIGUSR1,
+        Signal::SIGNAL_USR2 => libc::SIGUSR2,
+        Signal::SIGNAL_TERM => libc::SIGTERM,
+        Signal::SIGNAL_CONT => libc::SIGCONT,
+        Signal::SIGNAL_STOP => libc::SIGSTOP,
+        Signal::SIGNAL_WINCH => libc::SIGWINCH,
+    };
+
+    Some(signo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let signals = [
+            libc::SIGHUP,
+            libc::SIGINT,
+            libc::SIGQUIT,
+            libc::SIGKILL,
+            libc::SIGUSR1,
+            libc::SIGUSR2,
+            libc::SIGTERM,
+            libc::SIGCONT,
+            libc::SIGSTOP,
+            libc::SIGWINCH,
+        ];
+
+        for signo in signals.iter() {
+            let signal = to_proto(*signo).expect("signal not forwardable");
+            assert_eq!(from_proto(signal), Some(*signo));
+        }
+    }
+
+    #[test]
+    fn unknown_signals() {
+        assert_eq!(to_proto(libc::SIGSEGV), None);
+        assert_eq!(from_proto(Signal::SIGNAL_UNKNOWN), None);
+    }
+}
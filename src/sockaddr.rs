@@ -0,0 +1,682 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Parses the socket addresses accepted by vsh's forwarding mode and opens
+//! sockets for them.
+//!
+//! Addresses take one of the following forms:
+//!
+//! * `unix:/path/to/socket`
+//! * `unix-abstract:name`
+//! * `vsock:cid:port`, where cid may be `any`
+//! * `tcp:host:port`, where an IPv6 host may be wrapped in brackets
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::{TcpListener, ToSocketAddrs};
+use std::os::raw::c_uint;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::{self, UnixDatagram, UnixListener};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::result;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+
+use futures::io::{AsyncRead, AsyncWrite};
+use libchromeos::vsock::{self, VsockListener};
+
+use crate::async_core::{self, nonblocking_socket, ReadHalf, Split, WriteHalf, LISTEN_BACKLOG};
+use crate::async_core::unix::UnixAddr;
+use crate::async_core::vsock::{VsockSeqpacket, VsockSeqpacketListener};
+
+#[remain::sorted]
+#[derive(Debug, PartialEq)]
+pub enum SockAddrError {
+    EmptyPath,
+    InvalidCid(String),
+    InvalidPort(String),
+    MissingPort(String),
+    UnknownType(String),
+}
+
+type Result<T> = result::Result<T, SockAddrError>;
+
+impl fmt::Display for SockAddrError {
+    #[remain::check]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::SockAddrError::*;
+
+        #[remain::sorted]
+        match self {
+            EmptyPath => write!(f, "socket path must not be empty"),
+            InvalidCid(c) => write!(f, "invalid vsock cid: {}", c),
+            InvalidPort(p) => write!(f, "invalid port: {}", p),
+            MissingPort(a) => write!(f, "address is missing a port: {}", a),
+            UnknownType(a) => write!(f, "unknown socket address type: {}", a),
+        }
+    }
+}
+
+/// A parsed socket address.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SocketAddr {
+    /// A unix domain socket bound to a filesystem path.
+    Unix(PathBuf),
+    /// A unix domain socket in the abstract namespace.
+    UnixAbstract(String),
+    /// A vsock socket. The cid is ignored when listening.
+    Vsock { cid: c_uint, port: c_uint },
+    /// A TCP socket. The host is resolved each time a socket is opened.
+    Tcp { host: String, port: u16 },
+}
+
+/// Splits `addr` into a host part and a port, splitting on the last ':'.
+fn split_port(addr: &str) -> Result<(&str, &str)> {
+    let mut parts = addr.rsplitn(2, ':');
+    let port = parts.next().unwrap_or("");
+    match parts.next() {
+        Some(host) => Ok((host, port)),
+        None => Err(SockAddrError::MissingPort(addr.to_string())),
+    }
+}
+
+impl FromStr for SocketAddr {
+    type Err = SockAddrError;
+
+    fn from_str(s: &str) -> Result<SocketAddr> {
+        let mut parts = s.splitn(2, ':');
+        let kind = parts.next().unwrap_or("");
+        let addr = match parts.next() {
+            Some(addr) => addr,
+            None => return Err(SockAddrError::UnknownType(s.to_string())),
+        };
+
+        match kind {
+            "unix" | "unix-abstract" if addr.is_empty() => Err(SockAddrError::EmptyPath),
+            "unix" => Ok(SocketAddr::Unix(PathBuf::from(addr))),
+            "unix-abstract" => Ok(SocketAddr::UnixAbstract(addr.to_string())),
+            "vsock" => {
+                let (cid, port) = split_port(addr)?;
+                let cid = match cid {
+                    "any" => libc::VMADDR_CID_ANY,
+                    cid => cid
+                        .parse::<c_uint>()
+                        .map_err(|_| SockAddrError::InvalidCid(cid.to_string()))?,
+                };
+                let port = port
+                    .parse::<c_uint>()
+                    .map_err(|_| SockAddrError::InvalidPort(port.to_string()))?;
+
+                Ok(SocketAddr::Vsock { cid, port })
+            }
+            "tcp" => {
+                let (host, port) = split_port(addr)?;
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                let port = port
+                    .parse::<u16>()
+                    .map_err(|_| SockAddrError::InvalidPort(port.to_string()))?;
+
+                Ok(SocketAddr::Tcp {
+                    host: host.to_string(),
+                    port,
+                })
+            }
+            _ => Err(SockAddrError::UnknownType(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for SocketAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SocketAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+            SocketAddr::UnixAbstract(name) => write!(f, "unix-abstract:{}", name),
+            SocketAddr::Vsock { cid, port } if *cid == libc::VMADDR_CID_ANY => {
+                write!(f, "vsock:any:{}", port)
+            }
+            SocketAddr::Vsock { cid, port } => write!(f, "vsock:{}:{}", cid, port),
+            SocketAddr::Tcp { host, port } if host.contains(':') => {
+                write!(f, "tcp:[{}]:{}", host, port)
+            }
+            SocketAddr::Tcp { host, port } => write!(f, "tcp:{}:{}", host, port),
+        }
+    }
+}
+
+fn bind_unix(fd: RawFd, addr: &UnixAddr) -> io::Result<()> {
+    let (sockaddr, len) = addr.as_sockaddr();
+
+    // Safe because sockaddr points to a valid address of len bytes, and the
+    // return value is checked.
+    if unsafe { libc::bind(fd, sockaddr, len) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn connect_unix(fd: RawFd, addr: &UnixAddr) -> io::Result<()> {
+    let (sockaddr, len) = addr.as_sockaddr();
+
+    // Safe because sockaddr points to a valid address of len bytes, and the
+    // return value is checked.
+    if unsafe { libc::connect(fd, sockaddr, len) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Binds to a unique address in the abstract namespace chosen by the kernel.
+/// Unix datagram sockets need an address for their peers to send replies to.
+fn autobind_unix(fd: RawFd) -> io::Result<()> {
+    // Safe because sockaddr_un is a plain C struct for which all zeroes is a
+    // valid value.
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+
+    // Safe because addr is a valid sockaddr_un that is longer than the given
+    // length, and the return value is checked.
+    let ret = unsafe {
+        libc::bind(
+            fd,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            mem::size_of::<libc::sa_family_t>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+fn bind_abstract(name: &str) -> io::Result<UnixListener> {
+    let addr = UnixAddr::new_abstract(name.as_bytes())?;
+
+    let fd = nonblocking_socket(libc::AF_UNIX, libc::SOCK_STREAM)?;
+    // Safe because the fd was just created and nothing else owns it.
+    let listener = unsafe { UnixListener::from_raw_fd(fd) };
+    bind_unix(listener.as_raw_fd(), &addr)?;
+
+    // Safe because listen modifies no memory and the return value is checked.
+    if unsafe { libc::listen(listener.as_raw_fd(), LISTEN_BACKLOG) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(listener)
+}
+
+fn no_datagrams(addr: &SocketAddr) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} doesn't support datagrams", addr),
+    )
+}
+
+impl SocketAddr {
+    /// Creates a nonblocking listening socket bound to this address.
+    pub fn listen(&self) -> io::Result<Listener> {
+        match self {
+            SocketAddr::Unix(path) => {
+                async_core::unix::UnixListener::try_from(UnixListener::bind(path)?)
+                    .map(Listener::Unix)
+                    .map_err(io::Error::from)
+            }
+            SocketAddr::UnixAbstract(name) => {
+                async_core::unix::UnixListener::try_from(bind_abstract(name)?)
+                    .map(Listener::Unix)
+                    .map_err(io::Error::from)
+            }
+            SocketAddr::Vsock { port, .. } => {
+                async_core::vsock::VsockListener::try_from(VsockListener::bind(*port)?)
+                    .map(Listener::Vsock)
+                    .map_err(io::Error::from)
+            }
+            SocketAddr::Tcp { host, port } => {
+                async_core::tcp::TcpListener::try_from(TcpListener::bind((host.as_str(), *port))?)
+                    .map(Listener::Tcp)
+                    .map_err(io::Error::from)
+            }
+        }
+    }
+
+    /// Connects a new socket to this address without blocking the executor.
+    /// TCP host names are still resolved synchronously.
+    pub async fn connect(&self) -> io::Result<AsyncStream> {
+        match self {
+            SocketAddr::Unix(path) => {
+                let addr = UnixAddr::new_path(path)?;
+                async_core::unix::UnixStream::connect(&addr)
+                    .await
+                    .map(AsyncStream::Unix)
+            }
+            SocketAddr::UnixAbstract(name) => {
+                let addr = UnixAddr::new_abstract(name.as_bytes())?;
+                async_core::unix::UnixStream::connect(&addr)
+                    .await
+                    .map(AsyncStream::Unix)
+            }
+            SocketAddr::Vsock { cid, port } => async_core::vsock::VsockStream::connect(*cid, *port)
+                .await
+                .map(AsyncStream::Vsock),
+            SocketAddr::Tcp { host, port } => {
+                let mut last_err = None;
+                for addr in (host.as_str(), *port).to_socket_addrs()? {
+                    match async_core::tcp::TcpStream::connect(addr).await {
+                        Ok(s) => return Ok(AsyncStream::Tcp(s)),
+                        Err(e) => last_err = Some(e),
+                    }
+                }
+
+                Err(last_err.unwrap_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("no addresses found for {}", host),
+                    )
+                }))
+            }
+        }
+    }
+
+    /// Returns the unix socket address for unix addresses.
+    fn unix_addr(&self) -> Option<io::Result<UnixAddr>> {
+        match self {
+            SocketAddr::Unix(path) => Some(UnixAddr::new_path(path)),
+            SocketAddr::UnixAbstract(name) => Some(UnixAddr::new_abstract(name.as_bytes())),
+            _ => None,
+        }
+    }
+
+    /// Creates a socket bound to this address that receives datagrams. Unix
+    /// addresses use SOCK_DGRAM, while vsock addresses use SOCK_SEQPACKET.
+    pub fn listen_datagram(&self) -> io::Result<DatagramListener> {
+        if let Some(addr) = self.unix_addr() {
+            let fd = nonblocking_socket(libc::AF_UNIX, libc::SOCK_DGRAM)?;
+            // Safe because the fd was just created and nothing else owns it.
+            let sock = unsafe { UnixDatagram::from_raw_fd(fd) };
+            bind_unix(sock.as_raw_fd(), &addr?)?;
+
+            return async_core::unix::UnixDatagram::try_from(sock)
+                .map(DatagramListener::Unix)
+                .map_err(io::Error::from);
+        }
+
+        match self {
+            SocketAddr::Vsock { port, .. } => {
+                VsockSeqpacketListener::bind(*port).map(DatagramListener::Vsock)
+            }
+            _ => Err(no_datagrams(self)),
+        }
+    }
+
+    /// Connects a new datagram socket to this address. Unix addresses use
+    /// SOCK_DGRAM, while vsock addresses use SOCK_SEQPACKET.
+    pub async fn connect_datagram(&self) -> io::Result<AsyncDatagram> {
+        if let Some(addr) = self.unix_addr() {
+            let fd = nonblocking_socket(libc::AF_UNIX, libc::SOCK_DGRAM)?;
+            // Safe because the fd was just created and nothing else owns it.
+            let sock = unsafe { UnixDatagram::from_raw_fd(fd) };
+            autobind_unix(sock.as_raw_fd())?;
+
+            // Connecting a datagram socket only sets its default destination,
+            // so this never blocks.
+            connect_unix(sock.as_raw_fd(), &addr?)?;
+
+            return async_core::unix::UnixDatagram::try_from(sock)
+                .map(AsyncDatagram::Unix)
+                .map_err(io::Error::from);
+        }
+
+        match self {
+            SocketAddr::Vsock { cid, port } => VsockSeqpacket::connect(*cid, *port)
+                .await
+                .map(AsyncDatagram::Vsock),
+            _ => Err(no_datagrams(self)),
+        }
+    }
+}
+
+/// A nonblocking listening socket for any supported address type.
+pub enum Listener {
+    Unix(async_core::unix::UnixListener),
+    Vsock(async_core::vsock::VsockListener),
+    Tcp(async_core::tcp::TcpListener),
+}
+
+impl Listener {
+    /// Waits for a new connection.
+    pub async fn accept(&self) -> io::Result<AsyncStream> {
+        match self {
+            Listener::Unix(l) => l.accept().await.map(|(s, _)| AsyncStream::Unix(s)),
+            Listener::Vsock(l) => l.accept().await.map(|(s, _)| AsyncStream::Vsock(s)),
+            Listener::Tcp(l) => l.accept().await.map(|(s, _)| AsyncStream::Tcp(s)),
+        }
+    }
+}
+
+/// A connected, nonblocking stream socket for any supported address type.
+pub enum AsyncStream {
+    Unix(async_core::unix::UnixStream),
+    Vsock(async_core::vsock::VsockStream),
+    Tcp(async_core::tcp::TcpStream),
+}
+
+impl AsyncRead for AsyncStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AsyncStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            AsyncStream::Vsock(s) => Pin::new(s).poll_read(cx, buf),
+            AsyncStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for AsyncStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AsyncStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            AsyncStream::Vsock(s) => Pin::new(s).poll_write(cx, buf),
+            AsyncStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Unix(s) => Pin::new(s).poll_flush(cx),
+            AsyncStream::Vsock(s) => Pin::new(s).poll_flush(cx),
+            AsyncStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStream::Unix(s) => Pin::new(s).poll_close(cx),
+            AsyncStream::Vsock(s) => Pin::new(s).poll_close(cx),
+            AsyncStream::Tcp(s) => Pin::new(s).poll_close(cx),
+        }
+    }
+}
+
+impl Split for AsyncStream {
+    type ReadHalf = AsyncStreamReadHalf;
+    type WriteHalf = AsyncStreamWriteHalf;
+
+    fn split(self) -> (AsyncStreamReadHalf, AsyncStreamWriteHalf) {
+        match self {
+            AsyncStream::Unix(s) => {
+                let (r, w) = s.split();
+                (AsyncStreamReadHalf::Unix(r), AsyncStreamWriteHalf::Unix(w))
+            }
+            AsyncStream::Vsock(s) => {
+                let (r, w) = s.split();
+                (AsyncStreamReadHalf::Vsock(r), AsyncStreamWriteHalf::Vsock(w))
+            }
+            AsyncStream::Tcp(s) => {
+                let (r, w) = s.split();
+                (AsyncStreamReadHalf::Tcp(r), AsyncStreamWriteHalf::Tcp(w))
+            }
+        }
+    }
+}
+
+/// The read half of an AsyncStream, created by `Split::split`.
+pub enum AsyncStreamReadHalf {
+    Unix(ReadHalf<net::UnixStream>),
+    Vsock(ReadHalf<vsock::VsockStream>),
+    Tcp(ReadHalf<std::net::TcpStream>),
+}
+
+/// The write half of an AsyncStream, created by `Split::split`.
+pub enum AsyncStreamWriteHalf {
+    Unix(WriteHalf<net::UnixStream>),
+    Vsock(WriteHalf<vsock::VsockStream>),
+    Tcp(WriteHalf<std::net::TcpStream>),
+}
+
+impl AsyncRead for AsyncStreamReadHalf {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AsyncStreamReadHalf::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            AsyncStreamReadHalf::Vsock(s) => Pin::new(s).poll_read(cx, buf),
+            AsyncStreamReadHalf::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for AsyncStreamWriteHalf {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AsyncStreamWriteHalf::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            AsyncStreamWriteHalf::Vsock(s) => Pin::new(s).poll_write(cx, buf),
+            AsyncStreamWriteHalf::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStreamWriteHalf::Unix(s) => Pin::new(s).poll_flush(cx),
+            AsyncStreamWriteHalf::Vsock(s) => Pin::new(s).poll_flush(cx),
+            AsyncStreamWriteHalf::Tcp(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AsyncStreamWriteHalf::Unix(s) => Pin::new(s).poll_close(cx),
+            AsyncStreamWriteHalf::Vsock(s) => Pin::new(s).poll_close(cx),
+            AsyncStreamWriteHalf::Tcp(s) => Pin::new(s).poll_close(cx),
+        }
+    }
+}
+
+/// A socket that receives datagrams from any number of peers.
+pub enum DatagramListener {
+    /// A bound unix datagram socket. Peers are told apart by their address.
+    Unix(async_core::unix::UnixDatagram),
+    /// A vsock seqpacket listener. Each peer has its own connection.
+    Vsock(VsockSeqpacketListener),
+}
+
+/// A connected, nonblocking socket that preserves message boundaries.
+pub enum AsyncDatagram {
+    Unix(async_core::unix::UnixDatagram),
+    Vsock(VsockSeqpacket),
+}
+
+impl AsyncDatagram {
+    /// Receives a single message. Returns None once a connection-oriented peer
+    /// has shut down.
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+        match self {
+            AsyncDatagram::Unix(s) => s.recv(buf).await.map(Some),
+            AsyncDatagram::Vsock(s) => s.recv(buf).await,
+        }
+    }
+
+    /// Sends a single message.
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            AsyncDatagram::Unix(s) => s.send(buf).await,
+            AsyncDatagram::Vsock(s) => s.send(buf).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    use cros_async::{complete2, run_one};
+    use futures::io::{AsyncReadExt, AsyncWriteExt};
+    use futures::pin_mut;
+
+    fn parse(s: &str) -> Result<SocketAddr> {
+        s.parse::<SocketAddr>()
+    }
+
+    #[test]
+    fn parse_valid() {
+        assert_eq!(
+            parse("unix:/run/foo.sock"),
+            Ok(SocketAddr::Unix(PathBuf::from("/run/foo.sock")))
+        );
+        assert_eq!(
+            parse("unix-abstract:foo"),
+            Ok(SocketAddr::UnixAbstract("foo".to_string()))
+        );
+        assert_eq!(
+            parse("vsock:3:5000"),
+            Ok(SocketAddr::Vsock { cid: 3, port: 5000 })
+        );
+        assert_eq!(
+            parse("vsock:any:5000"),
+            Ok(SocketAddr::Vsock {
+                cid: libc::VMADDR_CID_ANY,
+                port: 5000
+            })
+        );
+        assert_eq!(
+            parse("tcp:localhost:8080"),
+            Ok(SocketAddr::Tcp {
+                host: "localhost".to_string(),
+                port: 8080
+            })
+        );
+        assert_eq!(
+            parse("tcp:[::1]:8080"),
+            Ok(SocketAddr::Tcp {
+                host: "::1".to_string(),
+                port: 8080
+            })
+        );
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(
+            parse("/run/foo.sock"),
+            Err(SockAddrError::UnknownType("/run/foo.sock".to_string()))
+        );
+        assert_eq!(
+            parse("udp:localhost:53"),
+            Err(SockAddrError::UnknownType("udp:localhost:53".to_string()))
+        );
+        assert_eq!(parse("unix:"), Err(SockAddrError::EmptyPath));
+        assert_eq!(
+            parse("vsock:3"),
+            Err(SockAddrError::MissingPort("3".to_string()))
+        );
+        assert_eq!(
+            parse("vsock:host:5000"),
+            Err(SockAddrError::InvalidCid("host".to_string()))
+        );
+        assert_eq!(
+            parse("tcp:localhost:http"),
+            Err(SockAddrError::InvalidPort("http".to_string()))
+        );
+        assert_eq!(
+            parse("tcp:localhost:65536"),
+            Err(SockAddrError::InvalidPort("65536".to_string()))
+        );
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for s in &[
+            "unix:/run/foo.sock",
+            "unix-abstract:foo",
+            "vsock:3:5000",
+            "vsock:any:5000",
+            "tcp:localhost:8080",
+            "tcp:[::1]:8080",
+        ] {
+            assert_eq!(parse(s).unwrap().to_string(), *s);
+        }
+    }
+
+    #[test]
+    fn abstract_connect() {
+        let addr = SocketAddr::UnixAbstract(format!("vsh-sockaddr-test-{}", process::id()));
+
+        let listener = addr.listen().expect("failed to listen");
+
+        async fn accept(listener: &Listener) -> String {
+            let mut server = listener.accept().await.expect("failed to accept");
+            match server {
+                AsyncStream::Unix(_) => {}
+                _ => panic!("abstract listener accepted a non-unix socket"),
+            }
+
+            let mut buf = String::new();
+            server.read_to_string(&mut buf).await.unwrap();
+            buf
+        }
+
+        async fn connect(addr: &SocketAddr) {
+            let mut client = addr.connect().await.expect("failed to connect");
+            client.write_all(b"foo").await.unwrap();
+            client.close().await.unwrap();
+        }
+
+        let a = accept(&listener);
+        pin_mut!(a);
+        let c = connect(&addr);
+        pin_mut!(c);
+
+        let (s, ()) = complete2(a, c).unwrap();
+        assert_eq!(s, "foo");
+    }
+
+    #[test]
+    fn abstract_datagram() {
+        let addr = SocketAddr::UnixAbstract(format!("vsh-sockaddr-dgram-test-{}", process::id()));
+
+        let server = match addr.listen_datagram().expect("failed to listen") {
+            DatagramListener::Unix(s) => s,
+            _ => panic!("abstract address bound a non-unix socket"),
+        };
+
+        async fn exchange(server: &async_core::unix::UnixDatagram, addr: &SocketAddr) {
+            let client = addr.connect_datagram().await.expect("failed to connect");
+            client.send(b"foo").await.unwrap();
+
+            let mut buf = [0u8; 16];
+            let (count, peer) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..count], b"foo");
+
+            // The client was bound automatically, so it can receive replies.
+            assert!(!peer.is_unnamed());
+            server.send_to(b"bar", &peer).await.unwrap();
+            assert_eq!(client.recv(&mut buf).await.unwrap(), Some(3));
+            assert_eq!(&buf[..3], b"bar");
+        }
+
+        let fut = exchange(&server, &addr);
+        pin_mut!(fut);
+        run_one(fut).unwrap();
+    }
+
+    #[test]
+    fn tcp_datagram() {
+        let addr = parse("tcp:localhost:8080").unwrap();
+        assert!(addr.listen_datagram().is_err());
+
+        let connect = addr.connect_datagram();
+        pin_mut!(connect);
+        assert!(run_one(connect).unwrap().is_err());
+    }
+}
@@ -0,0 +1,485 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Typed access to terminal attributes.
+//!
+//! This works on any terminal fd, so it is used both for the client's local
+//! tty and for the pseudoterminals that vshd creates.
+
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/// When a change to terminal attributes should take effect.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SetAction {
+    /// The change takes effect immediately.
+    Now,
+    /// The change takes effect after all pending output has been written.
+    Drain,
+    /// Like `Drain`, but pending input is also discarded.
+    Flush,
+}
+
+impl SetAction {
+    fn as_raw(self) -> libc::c_int {
+        match self {
+            SetAction::Now => libc::TCSANOW,
+            SetAction::Drain => libc::TCSADRAIN,
+            SetAction::Flush => libc::TCSAFLUSH,
+        }
+    }
+}
+
+/// A terminal mode flag. Each one lives in one of the input, output, or local
+/// flag sets of termios.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Flag {
+    // Input flags.
+    Ignpar,
+    Parmrk,
+    Inpck,
+    Istrip,
+    Inlcr,
+    Igncr,
+    Icrnl,
+    Ixon,
+    Ixany,
+    Ixoff,
+    Imaxbel,
+    Iutf8,
+    // Local flags.
+    Isig,
+    Icanon,
+    Echo,
+    Echoe,
+    Echok,
+    Echonl,
+    Noflsh,
+    Tostop,
+    Iexten,
+    Echoctl,
+    Echoke,
+    // Output flags.
+    Opost,
+    Onlcr,
+    Ocrnl,
+    Onocr,
+    Onlret,
+}
+
+/// The set of termios flags that a Flag belongs to.
+enum FlagSet {
+    Input,
+    Local,
+    Output,
+}
+
+impl Flag {
+    fn set_and_mask(self) -> (FlagSet, libc::tcflag_t) {
+        use self::Flag::*;
+        use self::FlagSet::*;
+
+        match self {
+            Ignpar => (Input, libc::IGNPAR),
+            Parmrk => (Input, libc::PARMRK),
+            Inpck => (Input, libc::INPCK),
+            Istrip => (Input, libc::ISTRIP),
+            Inlcr => (Input, libc::INLCR),
+            Igncr => (Input, libc::IGNCR),
+            Icrnl => (Input, libc::ICRNL),
+            Ixon => (Input, libc::IXON),
+            Ixany => (Input, libc::IXANY),
+            Ixoff => (Input, libc::IXOFF),
+            Imaxbel => (Input, libc::IMAXBEL),
+            Iutf8 => (Input, libc::IUTF8),
+            Isig => (Local, libc::ISIG),
+            Icanon => (Local, libc::ICANON),
+            Echo => (Local, libc::ECHO),
+            Echoe => (Local, libc::ECHOE),
+            Echok => (Local, libc::ECHOK),
+            Echonl => (Local, libc::ECHONL),
+            Noflsh => (Local, libc::NOFLSH),
+            Tostop => (Local, libc::TOSTOP),
+            Iexten => (Local, libc::IEXTEN),
+            Echoctl => (Local, libc::ECHOCTL),
+            Echoke => (Local, libc::ECHOKE),
+            Opost => (Output, libc::OPOST),
+            Onlcr => (Output, libc::ONLCR),
+            Ocrnl => (Output, libc::OCRNL),
+            Onocr => (Output, libc::ONOCR),
+            Onlret => (Output, libc::ONLRET),
+        }
+    }
+}
+
+/// A special control character.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ControlChar {
+    Intr,
+    Quit,
+    Erase,
+    Kill,
+    Eof,
+    Eol,
+    Eol2,
+    Start,
+    Stop,
+    Susp,
+    Reprint,
+    Werase,
+    Lnext,
+    Discard,
+}
+
+impl ControlChar {
+    fn index(self) -> usize {
+        use self::ControlChar::*;
+
+        match self {
+            Intr => libc::VINTR,
+            Quit => libc::VQUIT,
+            Erase => libc::VERASE,
+            Kill => libc::VKILL,
+            Eof => libc::VEOF,
+            Eol => libc::VEOL,
+            Eol2 => libc::VEOL2,
+            Start => libc::VSTART,
+            Stop => libc::VSTOP,
+            Susp => libc::VSUSP,
+            Reprint => libc::VREPRINT,
+            Werase => libc::VWERASE,
+            Lnext => libc::VLNEXT,
+            Discard => libc::VDISCARD,
+        }
+    }
+}
+
+/// Terminal mode opcodes for control characters, as defined by RFC 4254.
+const CONTROL_CHAR_OPCODES: &[(ControlChar, u32)] = &[
+    (ControlChar::Intr, 1),
+    (ControlChar::Quit, 2),
+    (ControlChar::Erase, 3),
+    (ControlChar::Kill, 4),
+    (ControlChar::Eof, 5),
+    (ControlChar::Eol, 6),
+    (ControlChar::Eol2, 7),
+    (ControlChar::Start, 8),
+    (ControlChar::Stop, 9),
+    (ControlChar::Susp, 10),
+    (ControlChar::Reprint, 12),
+    (ControlChar::Werase, 13),
+    (ControlChar::Lnext, 14),
+    (ControlChar::Discard, 18),
+];
+
+/// Terminal mode opcodes for flags, as defined by RFC 4254 and RFC 8160.
+const FLAG_OPCODES: &[(Flag, u32)] = &[
+    (Flag::Ignpar, 30),
+    (Flag::Parmrk, 31),
+    (Flag::Inpck, 32),
+    (Flag::Istrip, 33),
+    (Flag::Inlcr, 34),
+    (Flag::Igncr, 35),
+    (Flag::Icrnl, 36),
+    (Flag::Ixon, 38),
+    (Flag::Ixany, 39),
+    (Flag::Ixoff, 40),
+    (Flag::Imaxbel, 41),
+    (Flag::Iutf8, 42),
+    (Flag::Isig, 50),
+    (Flag::Icanon, 51),
+    (Flag::Echo, 53),
+    (Flag::Echoe, 54),
+    (Flag::Echok, 55),
+    (Flag::Echonl, 56),
+    (Flag::Noflsh, 57),
+    (Flag::Tostop, 58),
+    (Flag::Iexten, 59),
+    (Flag::Echoctl, 60),
+    (Flag::Echoke, 61),
+    (Flag::Opost, 70),
+    (Flag::Onlcr, 72),
+    (Flag::Ocrnl, 73),
+    (Flag::Onocr, 74),
+    (Flag::Onlret, 75),
+];
+
+/// The encoding of a disabled control character in terminal modes.
+const ENCODED_VDISABLE: u32 = 255;
+
+/// The attributes of a terminal.
+#[derive(Clone, Copy)]
+pub struct Termios {
+    inner: libc::termios,
+}
+
+impl Termios {
+    /// Gets the attributes of the terminal referred to by `fd`.
+    pub fn get(fd: &dyn AsRawFd) -> io::Result<Termios> {
+        // Safe because termios is a plain C struct for which all zeroes is
+        // a valid value.
+        let mut inner: libc::termios = unsafe { mem::zeroed() };
+
+        // Safe because tcgetattr only writes to the provided termios struct
+        // and the return value is checked.
+        if unsafe { libc::tcgetattr(fd.as_raw_fd(), &mut inner) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Termios { inner })
+    }
+
+    /// Applies these attributes to the terminal referred to by `fd`.
+    pub fn set(&self, fd: &dyn AsRawFd, action: SetAction) -> io::Result<()> {
+        set_raw_fd(fd.as_raw_fd(), &self.inner, action)
+    }
+
+    /// Returns true if `flag` is set.
+    pub fn flag(&self, flag: Flag) -> bool {
+        let (set, mask) = flag.set_and_mask();
+        let flags = match set {
+            FlagSet::Input => self.inner.c_iflag,
+            FlagSet::Local => self.inner.c_lflag,
+            FlagSet::Output => self.inner.c_oflag,
+        };
+
+        flags & mask != 0
+    }
+
+    /// Sets or clears `flag`.
+    pub fn set_flag(&mut self, flag: Flag, value: bool) {
+        let (set, mask) = flag.set_and_mask();
+        let flags = match set {
+            FlagSet::Input => &mut self.inner.c_iflag,
+            FlagSet::Local => &mut self.inner.c_lflag,
+            FlagSet::Output => &mut self.inner.c_oflag,
+        };
+
+        if value {
+            *flags |= mask;
+        } else {
+            *flags &= !mask;
+        }
+    }
+
+    /// Returns the value of a special control character. A value of
+    /// `_POSIX_VDISABLE` (0) means the character is disabled.
+    pub fn control_char(&self, cc: ControlChar) -> u8 {
+        self.inner.c_cc[cc.index()]
+    }
+
+    /// Sets the value of a special control character.
+    pub fn set_control_char(&mut self, cc: ControlChar, value: u8) {
+        self.inner.c_cc[cc.index()] = value;
+    }
+
+    /// Returns the input baud rate as a `libc::B*` constant.
+    pub fn input_speed(&self) -> libc::speed_t {
+        // Safe because cfgetispeed only reads the provided termios struct.
+        unsafe { libc::cfgetispeed(&self.inner) }
+    }
+
+    /// Returns the output baud rate as a `libc::B*` constant.
+    pub fn output_speed(&self) -> libc::speed_t {
+        // Safe because cfgetospeed only reads the provided termios struct.
+        unsafe { libc::cfgetospeed(&self.inner) }
+    }
+
+    /// Sets both the input and output baud rate to a `libc::B*` constant.
+    pub fn set_speed(&mut self, speed: libc::speed_t) -> io::Result<()> {
+        // Safe because cfsetspeed only modifies the provided termios struct
+        // and the return value is checked.
+        if unsafe { libc::cfsetspeed(&mut self.inner, speed) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Encodes the control characters and flags as terminal modes, so they
+    /// can be applied to another terminal with `apply_modes`. Baud rates are
+    /// not included, since they are meaningless for a pseudoterminal.
+    pub fn to_modes(&self) -> HashMap<u32, u32> {
+        let mut modes = HashMap::new();
+
+        for &(cc, opcode) in CONTROL_CHAR_OPCODES {
+            let value = match self.control_char(cc) {
+                0 => ENCODED_VDISABLE,
+                c => u32::from(c),
+            };
+            modes.insert(opcode, value);
+        }
+
+        for &(flag, opcode) in FLAG_OPCODES {
+            modes.insert(opcode, u32::from(self.flag(flag)));
+        }
+
+        modes
+    }
+
+    /// Applies terminal modes encoded by `to_modes`. Unknown opcodes and
+    /// out of range control characters are ignored.
+    pub fn apply_modes(&mut self, modes: &HashMap<u32, u32>) {
+        for &(cc, opcode) in CONTROL_CHAR_OPCODES {
+            match modes.get(&opcode) {
+                Some(&ENCODED_VDISABLE) => self.set_control_char(cc, 0),
+                Some(&value) if value < ENCODED_VDISABLE => self.set_control_char(cc, value as u8),
+                _ => {}
+            }
+        }
+
+        for &(flag, opcode) in FLAG_OPCODES {
+            if let Some(&value) = modes.get(&opcode) {
+                self.set_flag(flag, value != 0);
+            }
+        }
+    }
+
+    /// Changes these attributes to raw mode: input is available byte by
+    /// byte, echo is disabled, and no characters are processed specially.
+    pub fn make_raw(&mut self) {
+        // Safe because cfmakeraw only modifies the provided termios struct.
+        unsafe { libc::cfmakeraw(&mut self.inner) };
+    }
+}
+
+fn set_raw_fd(fd: RawFd, termios: &libc::termios, action: SetAction) -> io::Result<()> {
+    // Safe because tcsetattr modifies no memory and the return value is
+    // checked.
+    if unsafe { libc::tcsetattr(fd, action.as_raw(), termios) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Restores a terminal's original attributes when dropped.
+///
+/// Dropping also happens when unwinding from a panic, so the terminal is left
+/// usable on every exit path that doesn't kill the process outright. The fd
+/// must remain open for as long as the guard exists.
+pub struct TermiosGuard {
+    fd: RawFd,
+    orig: Termios,
+}
+
+impl TermiosGuard {
+    /// Saves the attributes of the terminal referred to by `fd` so they can be
+    /// restored later.
+    pub fn new(fd: &dyn AsRawFd) -> io::Result<TermiosGuard> {
+        Ok(TermiosGuard {
+            fd: fd.as_raw_fd(),
+            orig: Termios::get(fd)?,
+        })
+    }
+
+    /// Returns the attributes that will be restored.
+    pub fn original(&self) -> &Termios {
+        &self.orig
+    }
+
+    /// Restores the original attributes now, returning any error.
+    pub fn restore(self) -> io::Result<()> {
+        let res = set_raw_fd(self.fd, &self.orig.inner, SetAction::Drain);
+        mem::forget(self);
+        res
+    }
+}
+
+impl Drop for TermiosGuard {
+    fn drop(&mut self) {
+        // Don't bother checking the result since nothing useful can be done
+        // about it in drop.
+        let _ = set_raw_fd(self.fd, &self.orig.inner, SetAction::Drain);
+    }
+}
+
+/// Puts the terminal referred to by `fd` into raw mode, returning a guard
+/// that restores the original mode.
+pub fn make_raw(fd: &dyn AsRawFd) -> io::Result<TermiosGuard> {
+    let guard = TermiosGuard::new(fd)?;
+
+    let mut raw = *guard.original();
+    raw.make_raw();
+    raw.set(fd, SetAction::Drain)?;
+
+    Ok(guard)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pty::PtyParent;
+
+    #[test]
+    fn flags_and_control_chars() {
+        let mut pty_parent = PtyParent::new().expect("create new PtyParent");
+        let pty_child = pty_parent.open_child().expect("open pty child");
+
+        let mut termios = Termios::get(&pty_child).expect("get termios");
+        termios.set_flag(Flag::Echo, false);
+        termios.set_flag(Flag::Iutf8, true);
+        termios.set_control_char(ControlChar::Erase, 0x08);
+        termios.set_speed(libc::B9600).expect("set speed");
+        termios.set(&pty_child, SetAction::Now).expect("set termios");
+
+        let termios = Termios::get(&pty_child).expect("get termios");
+        assert!(!termios.flag(Flag::Echo));
+        assert!(termios.flag(Flag::Iutf8));
+        assert_eq!(termios.control_char(ControlChar::Erase), 0x08);
+        assert_eq!(termios.input_speed(), libc::B9600);
+        assert_eq!(termios.output_speed(), libc::B9600);
+    }
+
+    #[test]
+    fn terminal_modes() {
+        let mut pty_parent = PtyParent::new().expect("create new PtyParent");
+        let pty_child = pty_parent.open_child().expect("open pty child");
+
+        let mut termios = Termios::get(&pty_child).expect("get termios");
+        termios.set_control_char(ControlChar::Erase, 0x08);
+        termios.set_control_char(ControlChar::Lnext, 0);
+        termios.set_flag(Flag::Iutf8, true);
+        termios.set_flag(Flag::Echoctl, false);
+
+        let modes = termios.to_modes();
+        assert_eq!(modes[&3], 0x08);
+        assert_eq!(modes[&14], ENCODED_VDISABLE);
+        assert_eq!(modes[&42], 1);
+        assert_eq!(modes[&60], 0);
+
+        let mut other = Termios::get(&pty_child).expect("get termios");
+        other.make_raw();
+        let mut unknown = modes.clone();
+        unknown.insert(200, 1);
+        other.apply_modes(&unknown);
+
+        assert_eq!(other.to_modes(), modes);
+        assert_eq!(other.control_char(ControlChar::Erase), 0x08);
+        assert_eq!(other.control_char(ControlChar::Lnext), 0);
+        assert!(other.flag(Flag::Icanon));
+    }
+
+    #[test]
+    fn raw_mode_guard() {
+        let mut pty_parent = PtyParent::new().expect("create new PtyParent");
+        let pty_child = pty_parent.open_child().expect("open pty child");
+        assert!(Termios::get(&pty_child).unwrap().flag(Flag::Icanon));
+
+        {
+            let _guard = make_raw(&pty_child).expect("make pty raw");
+            let termios = Termios::get(&pty_child).unwrap();
+            assert!(!termios.flag(Flag::Icanon));
+            assert!(!termios.flag(Flag::Echo));
+            assert!(!termios.flag(Flag::Isig));
+        }
+        assert!(Termios::get(&pty_child).unwrap().flag(Flag::Icanon));
+
+        let guard = make_raw(&pty_child).expect("make pty raw");
+        guard.restore().expect("restore termios");
+        assert!(Termios::get(&pty_child).unwrap().flag(Flag::Echo));
+    }
+}
@@ -0,0 +1,455 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Lookups of user accounts in the passwd and group databases.
+
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::mem;
+use std::os::raw::{c_char, c_int};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Component, Path, PathBuf};
+use std::ptr;
+use std::result;
+
+// Initial size of the buffer for getpwnam_r and getpwuid_r if sysconf doesn't
+// suggest one.
+const DEFAULT_PASSWD_BUF_SIZE: usize = 1024;
+
+// Maximum number of symlinks followed while resolving a path inside a root,
+// the same limit the kernel uses.
+const MAX_SYMLINKS: usize = 40;
+
+/// Errors that can be encountered while looking up a user.
+#[remain::sorted]
+#[derive(Debug)]
+pub enum UserError {
+    /// Failed to get the supplementary groups of the user.
+    GetGroups(String),
+    /// Failed to read the passwd database.
+    LookupUser(io::Error),
+    /// The user name contained a nul byte.
+    NulInName,
+    /// No user has the given uid.
+    UnknownUid(libc::uid_t),
+    /// No user has the given name.
+    UnknownUser(String),
+}
+
+type Result<T> = result::Result<T, UserError>;
+
+impl fmt::Display for UserError {
+    #[remain::check]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::UserError::*;
+
+        #[remain::sorted]
+        match self {
+            GetGroups(name) => write!(f, "failed to get groups of user '{}'", name),
+            LookupUser(e) => write!(f, "failed to look up user: {}", e),
+            NulInName => write!(f, "user name contains a nul byte"),
+            UnknownUid(uid) => write!(f, "no user with uid {}", uid),
+            UnknownUser(name) => write!(f, "unknown user '{}'", name),
+        }
+    }
+}
+
+/// An entry from the passwd database.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct User {
+    pub name: String,
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    pub home: PathBuf,
+    pub shell: PathBuf,
+}
+
+/// Calls getpwnam_r or getpwuid_r through `lookup`, growing the buffer until
+/// the entry fits. Returns None if there is no matching entry.
+fn get_passwd<F>(mut lookup: F) -> Result<Option<User>>
+where
+    F: FnMut(*mut libc::passwd, *mut c_char, usize, *mut *mut libc::passwd) -> c_int,
+{
+    // Safe because sysconf modifies no memory.
+    let suggested = unsafe { libc::sysconf(libc::_SC_GETPW_R_SIZE_MAX) };
+    let mut buf_size = if suggested > 0 {
+        suggested as usize
+    } else {
+        DEFAULT_PASSWD_BUF_SIZE
+    };
+
+    loop {
+        let mut buf: Vec<c_char> = vec![0; buf_size];
+        // Safe because passwd is a plain C struct for which all zeroes is a
+        // valid value.
+        let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+        let mut result: *mut libc::passwd = ptr::null_mut();
+
+        match lookup(&mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) {
+            0 => {}
+            libc::ERANGE => {
+                buf_size *= 2;
+                continue;
+            }
+            e => return Err(UserError::LookupUser(io::Error::from_raw_os_error(e))),
+        }
+
+        if result.is_null() {
+            return Ok(None);
+        }
+
+        // Safe because a successful lookup points these fields at nul
+        // terminated strings within buf, which is still alive.
+        let (name, home, shell) = unsafe {
+            (
+                CStr::from_ptr(pwd.pw_name),
+                CStr::from_ptr(pwd.pw_dir),
+                CStr::from_ptr(pwd.pw_shell),
+            )
+        };
+
+        return Ok(Some(User {
+            name: name.to_string_lossy().into_owned(),
+            uid: pwd.pw_uid,
+            gid: pwd.pw_gid,
+            home: PathBuf::from(OsStr::from_bytes(home.to_bytes())),
+            shell: PathBuf::from(OsStr::from_bytes(shell.to_bytes())),
+        }));
+    }
+}
+
+/// Parses a line of a passwd file.
+fn parse_passwd_line(line: &str) -> Option<User> {
+    let fields: Vec<&str> = line.split(':').collect();
+    if fields.len() != 7 {
+        return None;
+    }
+
+    Some(User {
+        name: fields[0].to_string(),
+        uid: fields[2].parse().ok()?,
+        gid: fields[3].parse().ok()?,
+        home: PathBuf::from(fields[5]),
+        shell: PathBuf::from(fields[6]),
+    })
+}
+
+/// Opens `name` within the directory `dir` with `flags`, never following a
+/// symlink in its place.
+fn open_at(dir: &File, name: &OsStr, flags: c_int) -> io::Result<File> {
+    let name =
+        CString::new(name.as_bytes()).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+
+    // Safe because openat modifies no memory and the return value is checked.
+    let fd = unsafe {
+        libc::openat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            flags | libc::O_NOFOLLOW | libc::O_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // Safe because the fd was just opened and nothing else owns it.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Returns the target of the symlink `name` within the directory `dir`.
+fn read_link_at(dir: &File, name: &OsStr) -> io::Result<PathBuf> {
+    let name =
+        CString::new(name.as_bytes()).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+    // Safe because readlinkat writes at most buf.len() bytes to buf and the
+    // return value is checked.
+    let len = unsafe {
+        libc::readlinkat(
+            dir.as_raw_fd(),
+            name.as_ptr(),
+            buf.as_mut_ptr() as *mut c_char,
+            buf.len(),
+        )
+    };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    buf.truncate(len as usize);
+    Ok(PathBuf::from(OsString::from_vec(buf)))
+}
+
+/// Pushes the components of `path` onto `pending` so that they are popped in
+/// order.
+fn push_components(pending: &mut Vec<OsString>, path: &Path) {
+    for component in path.components().rev() {
+        match component {
+            Component::Normal(name) => pending.push(name.to_os_string()),
+            Component::ParentDir => pending.push(OsString::from("..")),
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+}
+
+/// Reads the file at `path` as if `root` were the root directory, the way
+/// openat2 does with RESOLVE_IN_ROOT. Absolute symlinks and ".." components
+/// are resolved relative to `root`, so a path under a container's root can't
+/// lead to files outside of it.
+fn read_in_root(root: &Path, path: &Path) -> io::Result<String> {
+    let root = OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_DIRECTORY)
+        .open(root)?;
+
+    // Directories below root that lead to the current one, innermost last.
+    let mut dirs: Vec<File> = Vec::new();
+    let mut pending = Vec::new();
+    push_components(&mut pending, path);
+    let mut symlinks = 0;
+
+    while let Some(name) = pending.pop() {
+        if name == ".." {
+            // Going up from root stays at root.
+            dirs.pop();
+            continue;
+        }
+
+        let dir = dirs.last().unwrap_or(&root);
+        let entry = open_at(dir, &name, libc::O_PATH)?;
+        if entry.metadata()?.file_type().is_symlink() {
+            symlinks += 1;
+            if symlinks > MAX_SYMLINKS {
+                return Err(io::Error::from_raw_os_error(libc::ELOOP));
+            }
+
+            let target = read_link_at(dir, &name)?;
+            if target.has_root() {
+                dirs.clear();
+            }
+            push_components(&mut pending, &target);
+            continue;
+        }
+
+        if pending.is_empty() {
+            let mut file = open_at(dir, &name, libc::O_RDONLY)?;
+            let mut contents = String::new();
+            file.read_to_string(&mut contents)?;
+            return Ok(contents);
+        }
+        dirs.push(entry);
+    }
+
+    // The path resolved to a directory.
+    Err(io::Error::from_raw_os_error(libc::EISDIR))
+}
+
+/// Finds the first entry in `root`/etc/passwd that matches `pred`.
+fn find_in_passwd_file<P>(root: &Path, pred: P) -> Result<Option<User>>
+where
+    P: Fn(&User) -> bool,
+{
+    let passwd = read_in_root(root, Path::new("/etc/passwd")).map_err(UserError::LookupUser)?;
+    Ok(passwd
+        .lines()
+        .filter_map(parse_passwd_line)
+        .find(|user| pred(user)))
+}
+
+impl User {
+    /// Looks up a user by name.
+    pub fn from_name(name: &str) -> Result<User> {
+        let c_name = CString::new(name).map_err(|_| UserError::NulInName)?;
+
+        get_passwd(|pwd, buf, len, result| {
+            // Safe because getpwnam_r only writes to pwd, at most len bytes of
+            // buf, and result, all of which are valid.
+            unsafe { libc::getpwnam_r(c_name.as_ptr(), pwd, buf, len, result) }
+        })?
+        .ok_or_else(|| UserError::UnknownUser(name.to_string()))
+    }
+
+    /// Looks up a user by uid.
+    pub fn from_uid(uid: libc::uid_t) -> Result<User> {
+        get_passwd(|pwd, buf, len, result| {
+            // Safe because getpwuid_r only writes to pwd, at most len bytes of
+            // buf, and result, all of which are valid.
+            unsafe { libc::getpwuid_r(uid, pwd, buf, len, result) }
+        })?
+        .ok_or_else(|| UserError::UnknownUid(uid))
+    }
+
+    /// Looks up a user by name in the passwd file of the filesystem at `root`,
+    /// such as a container's root. The name service switch isn't consulted,
+    /// and symlinks are resolved as if `root` were the root directory.
+    pub fn from_name_in(root: &Path, name: &str) -> Result<User> {
+        find_in_passwd_file(root, |user| user.name == name)?
+            .ok_or_else(|| UserError::UnknownUser(name.to_string()))
+    }
+
+    /// Looks up a user by uid in the passwd file of the filesystem at `root`.
+    pub fn from_uid_in(root: &Path, uid: libc::uid_t) -> Result<User> {
+        find_in_passwd_file(root, |user| user.uid == uid)?.ok_or_else(|| UserError::UnknownUid(uid))
+    }
+
+    /// Returns the groups the user belongs to according to the group file of
+    /// the filesystem at `root`, including their primary group.
+    pub fn groups_in(&self, root: &Path) -> Result<Vec<libc::gid_t>> {
+        let group = read_in_root(root, Path::new("/etc/group")).map_err(UserError::LookupUser)?;
+
+        let mut groups = vec![self.gid];
+        for line in group.lines() {
+            let fields: Vec<&str> = line.split(':').collect();
+            if fields.len() != 4 {
+                continue;
+            }
+            let gid = match fields[2].parse() {
+                Ok(gid) => gid,
+                Err(_) => continue,
+            };
+            if fields[3].split(',').any(|member| member == self.name) && !groups.contains(&gid) {
+                groups.push(gid);
+            }
+        }
+
+        Ok(groups)
+    }
+
+    /// Returns the groups the user belongs to, including their primary group.
+    pub fn groups(&self) -> Result<Vec<libc::gid_t>> {
+        let c_name = CString::new(self.name.as_str()).map_err(|_| UserError::NulInName)?;
+
+        let mut groups: Vec<libc::gid_t> = vec![0; 16];
+        loop {
+            let mut ngroups = groups.len() as c_int;
+
+            // Safe because getgrouplist writes at most ngroups entries to
+            // groups and updates ngroups with the number of groups found.
+            let ret = unsafe {
+                libc::getgrouplist(c_name.as_ptr(), self.gid, groups.as_mut_ptr(), &mut ngroups)
+            };
+            if ret >= 0 {
+                groups.truncate(ngroups as usize);
+                return Ok(groups);
+            }
+
+            // The list didn't fit, and ngroups is now the number needed.
+            if ngroups as usize <= groups.len() {
+                return Err(UserError::GetGroups(self.name.clone()));
+            }
+            groups.resize(ngroups as usize, 0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn lookup_current_user() {
+        // Safe because getuid modifies no memory.
+        let uid = unsafe { libc::getuid() };
+
+        let user = User::from_uid(uid).expect("look up current user");
+        assert_eq!(user.uid, uid);
+        assert_eq!(User::from_name(&user.name).expect("look up user by name"), user);
+
+        let groups = user.groups().expect("get groups");
+        assert!(groups.contains(&user.gid));
+    }
+
+    #[test]
+    fn lookup_in_root() {
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir(root.path().join("etc")).unwrap();
+        fs::write(
+            root.path().join("etc/passwd"),
+            "root:x:0:0:root:/root:/bin/bash\n\
+             bad line\n\
+             alice:x:1000:1000:Alice:/home/alice:/bin/zsh\n",
+        )
+        .unwrap();
+        fs::write(
+            root.path().join("etc/group"),
+            "root:x:0:\n\
+             alice:x:1000:\n\
+             audio:x:29:bob,alice\n\
+             video:x:44:bob\n",
+        )
+        .unwrap();
+
+        let alice = User::from_name_in(root.path(), "alice").unwrap();
+        assert_eq!(
+            alice,
+            User {
+                name: "alice".to_string(),
+                uid: 1000,
+                gid: 1000,
+                home: PathBuf::from("/home/alice"),
+                shell: PathBuf::from("/bin/zsh"),
+            }
+        );
+        assert_eq!(User::from_uid_in(root.path(), 0).unwrap().name, "root");
+        assert_eq!(alice.groups_in(root.path()).unwrap(), vec![1000, 29]);
+
+        match User::from_name_in(root.path(), "bob") {
+            Err(UserError::UnknownUser(name)) => assert_eq!(name, "bob"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn symlinks_stay_in_root() {
+        let outside = tempfile::tempdir().unwrap();
+        let outside_passwd = outside.path().join("passwd");
+        fs::write(&outside_passwd, "mallory:x:0:0::/root:/bin/sh\n").unwrap();
+
+        let root = tempfile::tempdir().unwrap();
+        fs::create_dir_all(root.path().join("usr/etc")).unwrap();
+        fs::create_dir_all(root.path().join("usr/lib")).unwrap();
+        fs::write(
+            root.path().join("usr/etc/passwd"),
+            "alice:x:1000:1000:Alice:/home/alice:/bin/zsh\n",
+        )
+        .unwrap();
+        fs::write(root.path().join("usr/lib/group"), "audio:x:29:alice\n").unwrap();
+
+        // Absolute symlinks, and relative ones that climb past the top, are
+        // resolved within the root.
+        symlink("/usr/etc", root.path().join("etc")).unwrap();
+        symlink("../../../../usr/lib/group", root.path().join("usr/etc/group")).unwrap();
+        let alice = User::from_name_in(root.path(), "alice").unwrap();
+        assert_eq!(alice.uid, 1000);
+        assert_eq!(alice.groups_in(root.path()).unwrap(), vec![1000, 29]);
+
+        // A symlink to a file outside the root resolves to the same path
+        // inside it instead.
+        fs::remove_file(root.path().join("usr/etc/passwd")).unwrap();
+        symlink(&outside_passwd, root.path().join("usr/etc/passwd")).unwrap();
+        match User::from_name_in(root.path(), "mallory") {
+            Err(UserError::LookupUser(e)) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn unknown_user() {
+        match User::from_name("vsh-no-such-user") {
+            Err(UserError::UnknownUser(name)) => assert_eq!(name, "vsh-no-such-user"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        match User::from_name("bad\0name") {
+            Err(UserError::NulInName) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+}
@@ -0,0 +1,316 @@
+// Copyright 2020 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Looks up VMs and containers by name through the vm_tools D-Bus services,
+//! so users don't need to know vsock cids.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::result;
+use std::time::Duration;
+
+use dbus::blocking::Connection;
+use protobuf::{Message, ProtobufError};
+use system_api::cicerone_service::{
+    GetLxdContainerInfoRequest, GetLxdContainerInfoResponse, GetLxdContainerInfoResponse_Status,
+};
+use system_api::concierge_service::{GetVmInfoRequest, GetVmInfoResponse};
+
+// How long to wait for a D-Bus method call to complete.
+const DBUS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A D-Bus service exported by one of the vm_tools daemons.
+struct Service {
+    name: &'static str,
+    path: &'static str,
+    interface: &'static str,
+}
+
+/// vm_concierge, which knows the cids of running VMs.
+const CONCIERGE: Service = Service {
+    name: "org.chromium.VmConcierge",
+    path: "/org/chromium/VmConcierge",
+    interface: "org.chromium.VmConcierge",
+};
+const GET_VM_INFO_METHOD: &str = "GetVmInfo";
+
+/// vm_cicerone, which tracks the containers running in each VM.
+const CICERONE: Service = Service {
+    name: "org.chromium.VmCicerone",
+    path: "/org/chromium/VmCicerone",
+    interface: "org.chromium.VmCicerone",
+};
+const GET_LXD_CONTAINER_INFO_METHOD: &str = "GetLxdContainerInfo";
+
+/// Errors that can be encountered while looking up a VM or container.
+#[remain::sorted]
+#[derive(Debug)]
+pub enum LookupError {
+    /// The D-Bus method call failed.
+    CallMethod(&'static str, dbus::Error),
+    /// The VM has no container with the given name.
+    ContainerNotFound(String),
+    /// The container exists but isn't running.
+    ContainerNotRunning(String),
+    /// The response from the service couldn't be parsed.
+    DeserializeProto(ProtobufError),
+    /// The service failed to get the container's info.
+    GetContainerInfo(String),
+    /// The service reported a cid that isn't valid.
+    InvalidCid(i64),
+    /// The request couldn't be serialized.
+    SerializeProto(ProtobufError),
+    /// No VM with the given name is running for the owner.
+    UnknownVm(String),
+}
+
+type Result<T> = result::Result<T, LookupError>;
+
+impl fmt::Display for LookupError {
+    #[remain::check]
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::LookupError::*;
+
+        #[remain::sorted]
+        match self {
+            CallMethod(method, e) => write!(f, "failed to call {}: {}", method, e),
+            ContainerNotFound(name) => write!(f, "container '{}' does not exist", name),
+            ContainerNotRunning(name) => write!(f, "container '{}' is not running", name),
+            DeserializeProto(e) => write!(f, "failed to deserialize response: {}", e),
+            GetContainerInfo(reason) => write!(f, "failed to get container info: {}", reason),
+            InvalidCid(cid) => write!(f, "invalid cid: {}", cid),
+            SerializeProto(e) => write!(f, "failed to serialize request: {}", e),
+            UnknownVm(name) => write!(f, "VM '{}' is not running", name),
+        }
+    }
+}
+
+/// Information about a running container.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ContainerInfo {
+    /// The primary user of the container.
+    pub username: String,
+    /// The home directory of the primary user.
+    pub homedir: String,
+}
+
+/// Calls a method that takes and returns a serialized protobuf.
+fn call_method<Req: Message, Resp: Message>(
+    conn: &Connection,
+    service: &Service,
+    method: &'static str,
+    req: &Req,
+) -> Result<Resp> {
+    let req_bytes = req.write_to_bytes().map_err(LookupError::SerializeProto)?;
+
+    let proxy = conn.with_proxy(service.name, service.path, DBUS_TIMEOUT);
+    let (resp_bytes,): (Vec<u8>,) = proxy
+        .method_call(service.interface, method, (req_bytes,))
+        .map_err(|e| LookupError::CallMethod(method, e))?;
+
+    let mut resp = Resp::new();
+    resp.merge_from_bytes(&resp_bytes)
+        .map_err(LookupError::DeserializeProto)?;
+    Ok(resp)
+}
+
+/// Returns the vsock cid of the VM `vm_name` belonging to `owner_id`.
+pub fn get_vm_cid(conn: &Connection, vm_name: &str, owner_id: &str) -> Result<u32> {
+    let mut req = GetVmInfoRequest::new();
+    req.set_name(vm_name.to_string());
+    req.set_owner_id(owner_id.to_string());
+
+    let resp: GetVmInfoResponse = call_method(conn, &CONCIERGE, GET_VM_INFO_METHOD, &req)?;
+    if !resp.get_success() {
+        return Err(LookupError::UnknownVm(vm_name.to_string()));
+    }
+
+    let cid = resp.get_vm_info().get_cid();
+    u32::try_from(cid).map_err(|_| LookupError::InvalidCid(cid))
+}
+
+/// Returns information about `container_name` in the VM `vm_name` belonging
+/// to `owner_id`. Fails unless the container is running.
+pub fn get_container_info(
+    conn: &Connection,
+    vm_name: &str,
+    container_name: &str,
+    owner_id: &str,
+) -> Result<ContainerInfo> {
+    let mut req = GetLxdContainerInfoRequest::new();
+    req.set_vm_name(vm_name.to_string());
+    req.set_container_name(container_name.to_string());
+    req.set_owner_id(owner_id.to_string());
+
+    let resp: GetLxdContainerInfoResponse =
+        call_method(conn, &CICERONE, GET_LXD_CONTAINER_INFO_METHOD, &req)?;
+    match resp.get_status() {
+        GetLxdContainerInfoResponse_Status::RUNNING => Ok(ContainerInfo {
+            username: resp.get_container_username().to_string(),
+            homedir: resp.get_container_homedir().to_string(),
+        }),
+        GetLxdContainerInfoResponse_Status::STOPPED => {
+            Err(LookupError::ContainerNotRunning(container_name.to_string()))
+        }
+        GetLxdContainerInfoResponse_Status::NOT_FOUND => {
+            Err(LookupError::ContainerNotFound(container_name.to_string()))
+        }
+        _ => Err(LookupError::GetContainerInfo(
+            resp.get_failure_reason().to_string(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::sync::mpsc;
+    use std::thread;
+
+    use dbus::channel::{Channel, MatchingReceiver, Sender};
+    use dbus::message::MatchRule;
+
+    /// A private session bus that is shut down when dropped.
+    struct PrivateBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl PrivateBus {
+        /// Starts a new bus. Returns None if dbus-daemon isn't available.
+        fn start() -> Option<PrivateBus> {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(&["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .ok()?;
+
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap())
+                .read_line(&mut address)
+                .expect("failed to read bus address");
+
+            Some(PrivateBus {
+                daemon,
+                address: address.trim().to_string(),
+            })
+        }
+
+        fn connect(&self) -> Connection {
+            let mut channel = Channel::open_private(&self.address).expect("failed to open bus");
+            channel.register().expect("failed to register on bus");
+            Connection::from(channel)
+        }
+
+        /// Serves `service` on the bus, replying to every method call with
+        /// the serialized `resp`.
+        fn serve<Resp: Message>(&self, service: &'static Service, resp: Resp) {
+            let resp_bytes = resp.write_to_bytes().unwrap();
+            let conn = self.connect();
+            let (ready_tx, ready_rx) = mpsc::channel();
+
+            thread::spawn(move || {
+                conn.request_name(service.name, false, true, false)
+                    .expect("failed to request service name");
+                conn.start_receive(
+                    MatchRule::new_method_call(),
+                    Box::new(move |msg, conn| {
+                        let reply = msg.method_return().append1(resp_bytes.clone());
+                        let _ = conn.send(reply);
+                        true
+                    }),
+                );
+                ready_tx.send(()).unwrap();
+
+                // Processing fails once the bus goes away.
+                while conn.process(Duration::from_millis(100)).is_ok() {}
+            });
+
+            ready_rx.recv().unwrap();
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    #[test]
+    fn vm_cid() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+
+        let mut resp = GetVmInfoResponse::new();
+        resp.set_success(true);
+        resp.mut_vm_info().set_cid(42);
+        bus.serve(&CONCIERGE, resp);
+
+        let conn = bus.connect();
+        assert_eq!(get_vm_cid(&conn, "termina", "owner").unwrap(), 42);
+    }
+
+    #[test]
+    fn unknown_vm() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+
+        bus.serve(&CONCIERGE, GetVmInfoResponse::new());
+
+        let conn = bus.connect();
+        match get_vm_cid(&conn, "termina", "owner") {
+            Err(LookupError::UnknownVm(name)) => assert_eq!(name, "termina"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn container_info() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+
+        let mut resp = GetLxdContainerInfoResponse::new();
+        resp.set_status(GetLxdContainerInfoResponse_Status::RUNNING);
+        resp.set_container_username("penguin".to_string());
+        resp.set_container_homedir("/home/penguin".to_string());
+        bus.serve(&CICERONE, resp);
+
+        let conn = bus.connect();
+        assert_eq!(
+            get_container_info(&conn, "termina", "penguin", "owner").unwrap(),
+            ContainerInfo {
+                username: "penguin".to_string(),
+                homedir: "/home/penguin".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn stopped_container() {
+        let bus = match PrivateBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+
+        let mut resp = GetLxdContainerInfoResponse::new();
+        resp.set_status(GetLxdContainerInfoResponse_Status::STOPPED);
+        bus.serve(&CICERONE, resp);
+
+        let conn = bus.connect();
+        match get_container_info(&conn, "termina", "penguin", "owner") {
+            Err(LookupError::ContainerNotRunning(name)) => assert_eq!(name, "penguin"),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+}
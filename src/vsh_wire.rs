@@ -4,18 +4,91 @@
 
 //! Implements the vsh wire protocol on top of a stream-based socket.
 
+use std::cmp::{max, min};
 use std::convert::TryFrom;
 use std::fmt;
-use std::io::{self, Read, Write};
+use std::io::{self, IoSlice, Read, Write};
+use std::marker::PhantomData;
+use std::os::raw::c_uint;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
 use std::result;
+use std::task::{Context, Poll};
 
+use futures::future::poll_fn;
 use futures::prelude::*;
+use futures::ready;
 
-use protobuf::{ProtobufError, Message};
+use protobuf::{Message, ProtobufEnum, ProtobufError};
+use vsh_proto::vsh::{Capabilities, DataMessage, GuestMessage, HostMessage, StdioStream};
 
+use crate::async_core::{AsyncFd, ReadHalf, WriteHalf};
+
+// Frame size that every peer supports, including those that predate
+// negotiation.
 const VSH_BUF_SIZE: usize = 4096;
 
+/// Largest frame size that this implementation will negotiate.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// Version of the vsh protocol implemented here. Version 1 added the
+/// negotiation of capabilities during connection setup.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Optional protocol features supported by this implementation. None are
+// defined yet.
+const FEATURES: &[&str] = &[];
+
+// Size of the little-endian length that precedes each frame.
+const FRAME_LEN_SIZE: usize = 4;
+
+/// Well-known vsock port that vshd listens on.
+pub const VSH_PORT: c_uint = 9001;
+
+/// Target that requests a shell in the VM itself rather than in a container.
+pub const VM_SHELL_TARGET: &str = "vm_shell";
+
+// Protobuf wire types and DataMessage field numbers, for encoding DataMessage
+// frames without building a DataMessage.
+const WIRE_TYPE_VARINT: usize = 0;
+const WIRE_TYPE_LENGTH_DELIMITED: usize = 2;
+const DATA_MESSAGE_STREAM_FIELD: usize = 1;
+const DATA_MESSAGE_DATA_FIELD: usize = 2;
+
+/// Returns the number of bytes in the varint encoding of `value`.
+fn varint_size(mut value: usize) -> usize {
+    let mut size = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        size += 1;
+    }
+    size
+}
+
+/// Appends the varint encoding of `value` to `buf`.
+fn push_varint(buf: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buf.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+/// Returns the protobuf tag for `field` with the given wire type.
+fn tag(field: usize, wire_type: usize) -> usize {
+    (field << 3) | wire_type
+}
+
+/// Returns the maximum amount of stdio data that fits in a single DataMessage
+/// frame of `frame_size` bytes.
+fn max_data_size(frame_size: usize) -> usize {
+    // The HostMessage or GuestMessage encoding adds a tag and a length for the
+    // DataMessage field, a tag and a value for the stream, and a tag and a
+    // length for the data. Both lengths are less than the frame size, so
+    // their varints are no longer than its.
+    frame_size - (4 + 2 * varint_size(frame_size))
+}
+
 #[remain::sorted]
 #[derive(Debug)]
 pub enum VshWireError {
@@ -44,10 +117,222 @@ impl fmt::Display for VshWireError {
     }
 }
 
+/// A top-level vsh message, which can carry a DataMessage.
+pub trait StdioMessage: Message {
+    /// Field number of the DataMessage within this message.
+    const DATA_MESSAGE_FIELD: usize;
+
+    /// Wraps `data_msg` in a new message.
+    fn from_data_message(data_msg: DataMessage) -> Self;
+
+    /// Returns the DataMessage carried by this message, if any.
+    fn data_message(&self) -> Option<&DataMessage>;
+
+    /// Returns the DataMessage carried by this message mutably, if any.
+    fn data_message_mut(&mut self) -> Option<&mut DataMessage>;
+}
+
+impl StdioMessage for HostMessage {
+    const DATA_MESSAGE_FIELD: usize = 1;
+
+    fn from_data_message(data_msg: DataMessage) -> Self {
+        let mut host_msg = HostMessage::new();
+        host_msg.set_data_message(data_msg);
+        host_msg
+    }
+
+    fn data_message(&self) -> Option<&DataMessage> {
+        if self.has_data_message() {
+            Some(self.get_data_message())
+        } else {
+            None
+        }
+    }
+
+    fn data_message_mut(&mut self) -> Option<&mut DataMessage> {
+        if self.has_data_message() {
+            Some(self.mut_data_message())
+        } else {
+            None
+        }
+    }
+}
+
+impl StdioMessage for GuestMessage {
+    const DATA_MESSAGE_FIELD: usize = 1;
+
+    fn from_data_message(data_msg: DataMessage) -> Self {
+        let mut guest_msg = GuestMessage::new();
+        guest_msg.set_data_message(data_msg);
+        guest_msg
+    }
+
+    fn data_message(&self) -> Option<&DataMessage> {
+        if self.has_data_message() {
+            Some(self.get_data_message())
+        } else {
+            None
+        }
+    }
+
+    fn data_message_mut(&mut self) -> Option<&mut DataMessage> {
+        if self.has_data_message() {
+            Some(self.mut_data_message())
+        } else {
+            None
+        }
+    }
+}
+
+/// Splits `data` into chunks that each fit in a DataMessage frame of
+/// `frame_size` bytes. Empty data becomes a single empty chunk, which vsh
+/// uses to signal EOF.
+fn data_chunks(data: &[u8], frame_size: usize) -> impl Iterator<Item = &[u8]> {
+    let eof = if data.is_empty() { Some(data) } else { None };
+
+    data.chunks(max_data_size(frame_size)).chain(eof)
+}
+
+/// Appends a frame length and the start of the encoding of a message of type
+/// `M` carrying a DataMessage for `stream` to `buf`. The frame is completed
+/// by the `data_len` bytes of data that follow.
+///
+/// The encoding matches what protobuf produces for the same message, without
+/// copying the data into a DataMessage first.
+fn encode_data_header<M: StdioMessage>(buf: &mut Vec<u8>, stream: StdioStream, data_len: usize) {
+    // Fields with default values are omitted, as protobuf does.
+    // All StdioStream values are small and positive.
+    let stream_value = stream.value() as usize;
+    let mut data_msg_len = 0;
+    if stream_value != 0 {
+        data_msg_len += varint_size(tag(DATA_MESSAGE_STREAM_FIELD, WIRE_TYPE_VARINT))
+            + varint_size(stream_value);
+    }
+    if data_len != 0 {
+        data_msg_len += varint_size(tag(DATA_MESSAGE_DATA_FIELD, WIRE_TYPE_LENGTH_DELIMITED))
+            + varint_size(data_len)
+            + data_len;
+    }
+    let data_msg_tag = tag(M::DATA_MESSAGE_FIELD, WIRE_TYPE_LENGTH_DELIMITED);
+    let frame_len = varint_size(data_msg_tag) + varint_size(data_msg_len) + data_msg_len;
+
+    // Cast is safe since data_len is limited by the frame size, which is
+    // <= MAX_FRAME_SIZE < u32::max.
+    buf.extend_from_slice(&(frame_len as u32).to_le_bytes());
+    push_varint(buf, data_msg_tag);
+    push_varint(buf, data_msg_len);
+    if stream_value != 0 {
+        push_varint(buf, tag(DATA_MESSAGE_STREAM_FIELD, WIRE_TYPE_VARINT));
+        push_varint(buf, stream_value);
+    }
+    if data_len != 0 {
+        push_varint(
+            buf,
+            tag(DATA_MESSAGE_DATA_FIELD, WIRE_TYPE_LENGTH_DELIMITED),
+        );
+        push_varint(buf, data_len);
+    }
+}
+
+/// Writes all of `header` followed by all of `payload` to `writer`, with a
+/// single vectored write unless the writer only accepts part of them.
+fn write_all_vectored<W: Write>(writer: &mut W, header: &[u8], payload: &[u8]) -> io::Result<()> {
+    let mut written = 0;
+    while written < header.len() + payload.len() {
+        let result = if written < header.len() {
+            writer.write_vectored(&[IoSlice::new(&header[written..]), IoSlice::new(payload)])
+        } else {
+            writer.write(&payload[written - header.len()..])
+        };
+        match result {
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+            Ok(count) => written += count,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Splits `data` into messages carrying DataMessages for `stream`, each of
+/// which fits in a single frame of `frame_size` bytes. Empty data becomes a
+/// single empty message, which vsh uses to signal EOF.
+pub fn data_messages<'a, M: StdioMessage>(
+    stream: StdioStream,
+    data: &'a [u8],
+    frame_size: usize,
+) -> impl Iterator<Item = M> + 'a {
+    data_chunks(data, frame_size).map(move |chunk| {
+        let mut data_msg = DataMessage::new();
+        data_msg.set_stream(stream);
+        data_msg.set_data(chunk.to_vec());
+        M::from_data_message(data_msg)
+    })
+}
+
+/// Returns the capabilities to advertise to a peer in a
+/// SetupConnectionRequest or SetupConnectionResponse.
+pub fn local_capabilities() -> Capabilities {
+    let mut caps = Capabilities::new();
+    // This will always succeed since MAX_FRAME_SIZE fits in a u32.
+    caps.set_max_frame_size(u32::try_from(MAX_FRAME_SIZE).unwrap());
+    caps.set_features(FEATURES.iter().map(|f| f.to_string()).collect());
+    caps
+}
+
+/// Protocol parameters agreed on with a peer during connection setup.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Negotiated {
+    /// Protocol version that both sides implement.
+    pub version: u32,
+    /// Largest frame that may be sent to the peer.
+    pub max_send_frame_size: usize,
+    /// Largest frame that the peer may send, which is the size this side
+    /// advertised.
+    pub max_receive_frame_size: usize,
+    /// Optional features that both sides support.
+    pub features: Vec<String>,
+}
+
+impl Negotiated {
+    /// Negotiates with a peer that sent `version` and `caps`. A peer that
+    /// predates negotiation sends version 0, and gets the original protocol.
+    pub fn with_peer(version: u32, caps: &Capabilities) -> Self {
+        if version == 0 {
+            return Negotiated {
+                version: 0,
+                max_send_frame_size: VSH_BUF_SIZE,
+                max_receive_frame_size: VSH_BUF_SIZE,
+                features: Vec::new(),
+            };
+        }
+
+        // This will always succeed on 32 or 64 bit architectures.
+        let peer_frame_size = usize::try_from(caps.get_max_frame_size()).unwrap();
+        Negotiated {
+            version: min(version, PROTOCOL_VERSION),
+            max_send_frame_size: max(min(peer_frame_size, MAX_FRAME_SIZE), VSH_BUF_SIZE),
+            max_receive_frame_size: MAX_FRAME_SIZE,
+            features: FEATURES
+                .iter()
+                .filter(|f| caps.get_features().iter().any(|peer_f| peer_f == *f))
+                .map(|f| f.to_string())
+                .collect(),
+        }
+    }
+
+    /// Returns whether both sides support `feature`.
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
 pub struct VshWire<T: Read + Write + AsRawFd> {
     sock: T,
     rx_buf: Vec<u8>,
     tx_buf: Vec<u8>,
+    max_send_frame_size: usize,
+    max_receive_frame_size: usize,
 }
 
 impl<T: Read + Write + AsRawFd> VshWire<T> {
@@ -55,10 +340,34 @@ impl<T: Read + Write + AsRawFd> VshWire<T> {
         VshWire {
             sock,
             rx_buf: vec![0u8; VSH_BUF_SIZE],
-            tx_buf: Vec::with_capacity(VSH_BUF_SIZE),
+            tx_buf: Vec::with_capacity(FRAME_LEN_SIZE + VSH_BUF_SIZE),
+            max_send_frame_size: VSH_BUF_SIZE,
+            max_receive_frame_size: VSH_BUF_SIZE,
         }
     }
 
+    /// Raises the largest frame that can be sent, as negotiated with the
+    /// peer. Sizes below the current limit are ignored.
+    pub fn set_max_send_frame_size(&mut self, size: usize) {
+        self.max_send_frame_size = max(self.max_send_frame_size, size);
+    }
+
+    /// Raises the largest frame that can be received, which should be the
+    /// size advertised to the peer. Sizes below the current limit are
+    /// ignored.
+    pub fn set_max_receive_frame_size(&mut self, size: usize) {
+        if size > self.max_receive_frame_size {
+            self.max_receive_frame_size = size;
+            self.rx_buf.resize(size, 0);
+        }
+    }
+
+    /// Returns the maximum amount of stdio data that `send_data` puts in each
+    /// DataMessage.
+    pub fn max_data_size(&self) -> usize {
+        max_data_size(self.max_send_frame_size)
+    }
+
     /// Receives a full frame from the socket.
     fn receive_frame(&mut self) -> io::Result<usize> {
         let mut frame_len_bytes = [0u8; 4];
@@ -67,8 +376,11 @@ impl<T: Read + Write + AsRawFd> VshWire<T> {
         // This will always succeed on 32 or 64 bit architectures.
         let frame_len = usize::try_from(u32::from_le_bytes(frame_len_bytes)).unwrap();
 
-        if frame_len > VSH_BUF_SIZE {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid vsh frame size"));
+        if frame_len > self.max_receive_frame_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid vsh frame size",
+            ));
         }
 
         self.sock.read_exact(&mut self.rx_buf[..frame_len])?;
@@ -76,35 +388,48 @@ impl<T: Read + Write + AsRawFd> VshWire<T> {
         Ok(frame_len)
     }
 
-    fn send_frame(&mut self, frame_len: u32) -> io::Result<()> {
-        let frame_len_bytes = frame_len.to_le_bytes();
-        self.sock.write_all(&frame_len_bytes[..])?;
-
-        // This will always succeed on 32 or 64 bit architectures.
-        let frame_len = usize::try_from(u32::from_le_bytes(frame_len_bytes)).unwrap();
-
-        self.sock.write_all(&self.tx_buf[..frame_len])?;
-
-        Ok(())
-    }
-
     pub fn receive_message<M: Message>(&mut self, msg: &mut M) -> Result<()> {
         let frame_len = self.receive_frame().map_err(VshWireError::ReceiveMessage)?;
 
-        msg.merge_from_bytes(&self.rx_buf[..frame_len]).map_err(VshWireError::DeserializeProto)
+        msg.merge_from_bytes(&self.rx_buf[..frame_len])
+            .map_err(VshWireError::DeserializeProto)
     }
 
+    /// Sends `msg` as a single frame. The message is serialized directly
+    /// after space reserved for the frame length, so the whole frame is
+    /// written at once.
     pub fn send_message<M: Message>(&mut self, msg: &M) -> Result<()> {
-        self.tx_buf.truncate(0);
-        msg.write_to_vec(&mut self.tx_buf).map_err(VshWireError::SerializeProto)?;
+        self.tx_buf.clear();
+        self.tx_buf.extend_from_slice(&[0u8; FRAME_LEN_SIZE]);
+        msg.write_to_vec(&mut self.tx_buf)
+            .map_err(VshWireError::SerializeProto)?;
 
-        let frame_len = self.tx_buf.len();
-        if frame_len > VSH_BUF_SIZE {
+        let frame_len = self.tx_buf.len() - FRAME_LEN_SIZE;
+        if frame_len > self.max_send_frame_size {
             return Err(VshWireError::MessageTooBig(frame_len));
         }
 
-        // Cast is safe since we've verified frame_len is <= VSH_FRAME_SIZE < u32::max.
-        self.send_frame(frame_len as u32).map_err(VshWireError::SendMessage)
+        // Cast is safe since we've verified frame_len is <= MAX_FRAME_SIZE < u32::max.
+        self.tx_buf[..FRAME_LEN_SIZE].copy_from_slice(&(frame_len as u32).to_le_bytes());
+        self.sock
+            .write_all(&self.tx_buf)
+            .map_err(VshWireError::SendMessage)
+    }
+
+    /// Sends `data` for `stream` as messages of type `M`, splitting it into as
+    /// many DataMessages as needed. Empty data is sent as a single empty
+    /// DataMessage.
+    ///
+    /// The data isn't copied. Each frame's header is encoded separately and
+    /// written together with its chunk of data in a vectored write.
+    pub fn send_data<M: StdioMessage>(&mut self, stream: StdioStream, data: &[u8]) -> Result<()> {
+        for chunk in data_chunks(data, self.max_send_frame_size) {
+            self.tx_buf.clear();
+            encode_data_header::<M>(&mut self.tx_buf, stream, chunk.len());
+            write_all_vectored(&mut self.sock, &self.tx_buf, chunk)
+                .map_err(VshWireError::SendMessage)?;
+        }
+        Ok(())
     }
 }
 
@@ -114,79 +439,394 @@ impl<T: Read + Write + AsRawFd> AsRawFd for VshWire<T> {
     }
 }
 
+/// Receives vsh frames from an async stream.
+///
+/// A partially received frame is kept in the VshAsyncRead rather than in the
+/// future receiving it, so dropping a pending `receive_message` future or
+/// stream item, as `select!` does with the branches that lose, never loses
+/// data.
 pub struct VshAsyncRead<T: AsyncRead + Unpin> {
     sock: T,
     rx_buf: Vec<u8>,
+    /// Number of bytes of the current frame, including its length, received
+    /// so far.
+    rx_filled: usize,
+    /// Payload length of a complete frame in rx_buf that was looked at while
+    /// coalescing but not consumed.
+    rx_held: Option<usize>,
+    max_receive_frame_size: usize,
 }
 
 impl<T: AsyncRead + Unpin> VshAsyncRead<T> {
     pub fn new(sock: T) -> Self {
         VshAsyncRead {
             sock,
-            rx_buf: vec![0u8; VSH_BUF_SIZE],
+            rx_buf: vec![0u8; FRAME_LEN_SIZE + VSH_BUF_SIZE],
+            rx_filled: 0,
+            rx_held: None,
+            max_receive_frame_size: VSH_BUF_SIZE,
         }
     }
 
-    /// Receives a full frame from the socket.
-    async fn receive_frame(&mut self) -> io::Result<usize> {
-        let mut frame_len_bytes = [0u8; 4];
-        self.sock.read_exact(&mut frame_len_bytes[..]).await?;
+    /// Raises the largest frame that can be received, which should be the
+    /// size advertised to the peer. Sizes below the current limit are
+    /// ignored.
+    pub fn set_max_receive_frame_size(&mut self, size: usize) {
+        if size > self.max_receive_frame_size {
+            self.max_receive_frame_size = size;
+            self.rx_buf.resize(FRAME_LEN_SIZE + size, 0);
+        }
+    }
 
-        // This will always succeed on 32 or 64 bit architectures.
-        let frame_len = usize::try_from(u32::from_le_bytes(frame_len_bytes)).unwrap();
+    /// Polls for the rest of the current frame. Returns the length of the
+    /// frame's payload once it is complete, or None if the stream reached EOF
+    /// between frames.
+    fn poll_receive_frame(&mut self, cx: &mut Context) -> Poll<io::Result<Option<usize>>> {
+        if let Some(frame_len) = self.rx_held.take() {
+            return Poll::Ready(Ok(Some(frame_len)));
+        }
 
-        if frame_len > VSH_BUF_SIZE {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid vsh frame size"));
+        loop {
+            let frame_len = if self.rx_filled >= FRAME_LEN_SIZE {
+                let mut frame_len_bytes = [0u8; FRAME_LEN_SIZE];
+                frame_len_bytes.copy_from_slice(&self.rx_buf[..FRAME_LEN_SIZE]);
+
+                // This will always succeed on 32 or 64 bit architectures.
+                let frame_len = usize::try_from(u32::from_le_bytes(frame_len_bytes)).unwrap();
+                if frame_len > self.max_receive_frame_size {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "invalid vsh frame size",
+                    )));
+                }
+
+                if self.rx_filled == FRAME_LEN_SIZE + frame_len {
+                    self.rx_filled = 0;
+                    return Poll::Ready(Ok(Some(frame_len)));
+                }
+                Some(frame_len)
+            } else {
+                None
+            };
+
+            let wanted = FRAME_LEN_SIZE + frame_len.unwrap_or(0);
+            let count =
+                ready!(Pin::new(&mut self.sock)
+                    .poll_read(cx, &mut self.rx_buf[self.rx_filled..wanted]))?;
+            if count == 0 {
+                if self.rx_filled == 0 {
+                    return Poll::Ready(Ok(None));
+                }
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::UnexpectedEof)));
+            }
+            self.rx_filled += count;
         }
+    }
 
-        self.sock.read_exact(&mut self.rx_buf[..frame_len]).await?;
+    /// Polls for the next message. Returns None if the stream reached EOF
+    /// between messages.
+    fn poll_receive_message<M: Message>(&mut self, cx: &mut Context) -> Poll<Option<Result<M>>> {
+        let frame_len = match ready!(self.poll_receive_frame(cx)) {
+            Ok(Some(frame_len)) => frame_len,
+            Ok(None) => return Poll::Ready(None),
+            Err(e) => return Poll::Ready(Some(Err(VshWireError::ReceiveMessage(e)))),
+        };
 
-        Ok(frame_len)
+        Poll::Ready(Some(self.parse_frame(frame_len)))
+    }
+
+    /// Parses the complete frame in rx_buf.
+    fn parse_frame<M: Message>(&self, frame_len: usize) -> Result<M> {
+        let mut msg = M::new();
+        let payload = &self.rx_buf[FRAME_LEN_SIZE..FRAME_LEN_SIZE + frame_len];
+        msg.merge_from_bytes(payload)
+            .map(|_| msg)
+            .map_err(VshWireError::DeserializeProto)
+    }
+
+    /// Polls for the next message like `poll_receive_message`, then appends
+    /// the data of any following DataMessages for the same stream that have
+    /// already arrived, up to `limit` bytes in total. Empty DataMessages are
+    /// never merged, since they signal EOF.
+    fn poll_receive_coalesced<M: StdioMessage>(
+        &mut self,
+        cx: &mut Context,
+        limit: usize,
+    ) -> Poll<Option<Result<M>>> {
+        let mut msg: M = match ready!(self.poll_receive_message(cx)) {
+            Some(Ok(msg)) => msg,
+            r => return Poll::Ready(r),
+        };
+
+        while let Some(data_msg) = msg.data_message_mut() {
+            if data_msg.get_data().is_empty() {
+                break;
+            }
+
+            // Stop at the first frame that isn't complete yet. EOF and
+            // errors are reported by the next receive instead.
+            let frame_len = match self.poll_receive_frame(cx) {
+                Poll::Ready(Ok(Some(frame_len))) => frame_len,
+                _ => break,
+            };
+
+            let next: Option<M> = self.parse_frame(frame_len).ok();
+            let mergeable =
+                next.as_ref()
+                    .and_then(|next| next.data_message())
+                    .filter(|next_data| {
+                        next_data.get_stream() == data_msg.get_stream()
+                            && !next_data.get_data().is_empty()
+                            && data_msg.get_data().len() + next_data.get_data().len() <= limit
+                    });
+            match mergeable {
+                Some(next_data) => data_msg.mut_data().extend_from_slice(next_data.get_data()),
+                None => {
+                    // Leave the frame for the next receive.
+                    self.rx_held = Some(frame_len);
+                    break;
+                }
+            }
+        }
+
+        Poll::Ready(Some(Ok(msg)))
     }
 
     pub async fn receive_message<M: Message>(&mut self, msg: &mut M) -> Result<()> {
-        let frame_len = self.receive_frame().await.map_err(VshWireError::ReceiveMessage)?;
+        let frame_len = poll_fn(|cx| self.poll_receive_frame(cx))
+            .await
+            .map_err(VshWireError::ReceiveMessage)?
+            .ok_or_else(|| {
+                VshWireError::ReceiveMessage(io::Error::from(io::ErrorKind::UnexpectedEof))
+            })?;
+
+        msg.merge_from_bytes(&self.rx_buf[FRAME_LEN_SIZE..FRAME_LEN_SIZE + frame_len])
+            .map_err(VshWireError::DeserializeProto)
+    }
 
-        msg.merge_from_bytes(&self.rx_buf[..frame_len]).map_err(VshWireError::DeserializeProto)
+    /// Returns a Stream of incoming messages of type `M`, which ends when the
+    /// socket reaches EOF between messages.
+    ///
+    /// Dropping the stream or switching to a different message type doesn't
+    /// lose a partially received message.
+    pub fn messages<M: Message>(&mut self) -> VshMessages<'_, T, M> {
+        VshMessages {
+            reader: self,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Returns a Stream of incoming messages like `messages`, except that
+    /// consecutive DataMessages for the same stream that have already arrived
+    /// are merged into one carrying up to `limit` bytes of data.
+    pub fn coalesced_messages<M: StdioMessage>(
+        &mut self,
+        limit: usize,
+    ) -> VshCoalescedMessages<'_, T, M> {
+        VshCoalescedMessages {
+            reader: self,
+            limit,
+            phantom: PhantomData,
+        }
+    }
+}
+
+/// A Stream of messages received by a VshAsyncRead.
+pub struct VshMessages<'a, T: AsyncRead + Unpin, M: Message> {
+    reader: &'a mut VshAsyncRead<T>,
+    phantom: PhantomData<fn() -> M>,
+}
+
+impl<'a, T: AsyncRead + Unpin, M: Message> Stream for VshMessages<'a, T, M> {
+    type Item = Result<M>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<M>>> {
+        self.get_mut().reader.poll_receive_message(cx)
+    }
+}
+
+/// A Stream of messages received by a VshAsyncRead, with consecutive
+/// DataMessages merged.
+pub struct VshCoalescedMessages<'a, T: AsyncRead + Unpin, M: StdioMessage> {
+    reader: &'a mut VshAsyncRead<T>,
+    limit: usize,
+    phantom: PhantomData<fn() -> M>,
+}
+
+impl<'a, T: AsyncRead + Unpin, M: StdioMessage> Stream for VshCoalescedMessages<'a, T, M> {
+    type Item = Result<M>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<M>>> {
+        let this = self.get_mut();
+        this.reader.poll_receive_coalesced(cx, this.limit)
     }
 }
 
+/// Sends vsh frames on an async stream.
+///
+/// This is a Sink for any protobuf message. Each message is buffered as a
+/// whole frame before any of it is written, and a frame that is partially
+/// written when a send is dropped is finished by the next send or flush, so
+/// frames are never interleaved or cut short.
 pub struct VshAsyncWrite<T: AsyncWrite + Unpin> {
     sock: T,
     tx_buf: Vec<u8>,
+    /// Number of bytes of tx_buf that have been written to the socket.
+    tx_written: usize,
+    max_send_frame_size: usize,
 }
 
 impl<T: AsyncWrite + Unpin> VshAsyncWrite<T> {
     pub fn new(sock: T) -> Self {
         VshAsyncWrite {
             sock,
-            tx_buf: vec![0u8; VSH_BUF_SIZE],
+            tx_buf: Vec::with_capacity(FRAME_LEN_SIZE + VSH_BUF_SIZE),
+            tx_written: 0,
+            max_send_frame_size: VSH_BUF_SIZE,
         }
     }
 
-    async fn send_frame(&mut self, frame_len: u32) -> io::Result<()> {
-        let frame_len_bytes = frame_len.to_le_bytes();
-        self.sock.write_all(&frame_len_bytes[..]).await?;
+    /// Raises the largest frame that can be sent, as negotiated with the
+    /// peer. Sizes below the current limit are ignored.
+    pub fn set_max_send_frame_size(&mut self, size: usize) {
+        self.max_send_frame_size = max(self.max_send_frame_size, size);
+    }
+
+    /// Returns the maximum amount of stdio data that `send_data` puts in each
+    /// DataMessage.
+    pub fn max_data_size(&self) -> usize {
+        max_data_size(self.max_send_frame_size)
+    }
 
-        // This will always succeed on 32 or 64 bit architectures.
-        let frame_len = usize::try_from(u32::from_le_bytes(frame_len_bytes)).unwrap();
+    /// Writes out any buffered frame.
+    fn poll_write_frame(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+        while self.tx_written < self.tx_buf.len() {
+            let count =
+                ready!(Pin::new(&mut self.sock).poll_write(cx, &self.tx_buf[self.tx_written..]))?;
+            if count == 0 {
+                return Poll::Ready(Err(io::Error::from(io::ErrorKind::WriteZero)));
+            }
+            self.tx_written += count;
+        }
 
-        self.sock.write_all(&self.tx_buf[..frame_len]).await?;
+        self.tx_buf.clear();
+        self.tx_written = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Buffers `msg` as a frame. There must not be a frame already buffered.
+    fn buffer_frame<M: Message>(&mut self, msg: &M) -> Result<()> {
+        self.tx_buf.clear();
+        self.tx_buf.extend_from_slice(&[0u8; FRAME_LEN_SIZE]);
+        if let Err(e) = msg.write_to_vec(&mut self.tx_buf) {
+            self.tx_buf.clear();
+            return Err(VshWireError::SerializeProto(e));
+        }
 
+        let frame_len = self.tx_buf.len() - FRAME_LEN_SIZE;
+        if frame_len > self.max_send_frame_size {
+            self.tx_buf.clear();
+            return Err(VshWireError::MessageTooBig(frame_len));
+        }
+
+        // Cast is safe since we've verified frame_len is <= MAX_FRAME_SIZE < u32::max.
+        self.tx_buf[..FRAME_LEN_SIZE].copy_from_slice(&(frame_len as u32).to_le_bytes());
         Ok(())
     }
 
     pub async fn send_message<M: Message>(&mut self, msg: &M) -> Result<()> {
-        self.tx_buf.truncate(0);
-        msg.write_to_vec(&mut self.tx_buf).map_err(VshWireError::SerializeProto)?;
+        poll_fn(|cx| self.poll_write_frame(cx))
+            .await
+            .map_err(VshWireError::SendMessage)?;
+        self.buffer_frame(msg)?;
+        poll_fn(|cx| self.poll_write_frame(cx))
+            .await
+            .map_err(VshWireError::SendMessage)
+    }
 
-        let frame_len = self.tx_buf.len();
-        if frame_len > VSH_BUF_SIZE {
-            return Err(VshWireError::MessageTooBig(frame_len));
+    /// Sends `data` for `stream` as messages of type `M`, splitting it into as
+    /// many DataMessages as needed. Empty data is sent as a single empty
+    /// DataMessage.
+    ///
+    /// Each chunk of data is copied once, straight into the frame buffer
+    /// after its header. If this is dropped before completing, a prefix of
+    /// the chunks will have been sent.
+    pub async fn send_data<M: StdioMessage>(
+        &mut self,
+        stream: StdioStream,
+        data: &[u8],
+    ) -> Result<()> {
+        for chunk in data_chunks(data, self.max_send_frame_size) {
+            poll_fn(|cx| self.poll_write_frame(cx))
+                .await
+                .map_err(VshWireError::SendMessage)?;
+
+            self.tx_buf.clear();
+            encode_data_header::<M>(&mut self.tx_buf, stream, chunk.len());
+            self.tx_buf.extend_from_slice(chunk);
+
+            poll_fn(|cx| self.poll_write_frame(cx))
+                .await
+                .map_err(VshWireError::SendMessage)?;
         }
+        Ok(())
+    }
+}
+
+impl<T: AsyncWrite + Unpin, M: Message> Sink<M> for VshAsyncWrite<T> {
+    type Error = VshWireError;
 
-        // Cast is safe since we've verified frame_len is <= VSH_FRAME_SIZE < u32::max.
-        self.send_frame(frame_len as u32).await.map_err(VshWireError::SendMessage)
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        self.get_mut()
+            .poll_write_frame(cx)
+            .map_err(VshWireError::SendMessage)
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: M) -> Result<()> {
+        self.get_mut().buffer_frame(&msg)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_frame(cx)).map_err(VshWireError::SendMessage)?;
+        Pin::new(&mut this.sock)
+            .poll_flush(cx)
+            .map_err(VshWireError::SendMessage)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_frame(cx)).map_err(VshWireError::SendMessage)?;
+        Pin::new(&mut this.sock)
+            .poll_close(cx)
+            .map_err(VshWireError::SendMessage)
+    }
+}
+
+/// An async vsh connection on a single socket.
+///
+/// Both ends of a vsh connection receive and send at the same time, so the
+/// wire is split into a VshAsyncRead and a VshAsyncWrite that share the
+/// socket. The read pump and write pump can then run as separate tasks.
+pub struct VshAsyncWire<T: AsRawFd> {
+    sock: AsyncFd<T>,
+}
+
+impl<T: AsRawFd + Read + Write + Unpin> VshAsyncWire<T> {
+    pub fn new(sock: AsyncFd<T>) -> Self {
+        VshAsyncWire { sock }
+    }
+
+    /// Splits the wire into halves for receiving and sending messages.
+    pub fn split(self) -> (VshAsyncRead<ReadHalf<T>>, VshAsyncWrite<WriteHalf<T>>) {
+        let (read_half, write_half) = self.sock.split();
+        (VshAsyncRead::new(read_half), VshAsyncWrite::new(write_half))
+    }
+}
+
+impl<T: AsRawFd> AsRawFd for VshAsyncWire<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
     }
 }
 
@@ -195,8 +835,431 @@ mod tests {
     use super::*;
     use std::os::unix::net::UnixStream;
 
+    use cros_async::complete2;
+    use futures::executor::block_on;
+    use futures::pin_mut;
+    use futures::sink::SinkExt;
     use vsh_proto::vsh::*;
 
+    /// An async stream that transfers at most one byte per poll and is
+    /// pending on every other poll, so operations on it take many polls.
+    #[derive(Default)]
+    struct Trickle {
+        data: Vec<u8>,
+        read_pos: usize,
+        ready: bool,
+    }
+
+    impl Trickle {
+        /// Returns whether this poll should make progress.
+        fn take_turn(&mut self, cx: &mut Context) -> bool {
+            self.ready = !self.ready;
+            if !self.ready {
+                cx.waker().wake_by_ref();
+            }
+            self.ready
+        }
+    }
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            if !this.take_turn(cx) {
+                return Poll::Pending;
+            }
+            if this.read_pos == this.data.len() || buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            buf[0] = this.data[this.read_pos];
+            this.read_pos += 1;
+            Poll::Ready(Ok(1))
+        }
+    }
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let this = self.get_mut();
+            if !this.take_turn(cx) {
+                return Poll::Pending;
+            }
+            this.data.push(buf[0]);
+            Poll::Ready(Ok(1))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn stdout_message(data: &[u8]) -> HostMessage {
+        let mut host_msg = HostMessage::new();
+        let data_msg = host_msg.mut_data_message();
+        data_msg.set_stream(StdioStream::STDOUT_STREAM);
+        data_msg.set_data(data.to_vec());
+        host_msg
+    }
+
+    fn data_message(data: &[u8]) -> GuestMessage {
+        let mut guest_msg = GuestMessage::new();
+        let data_msg = guest_msg.mut_data_message();
+        data_msg.set_stream(StdioStream::STDIN_STREAM);
+        data_msg.set_data(data.to_vec());
+        guest_msg
+    }
+
+    #[test]
+    fn async_stream_and_sink() {
+        let mut writer = VshAsyncWrite::new(Trickle::default());
+        block_on(async {
+            writer.send(data_message(b"one")).await.unwrap();
+            writer.send(data_message(b"two")).await.unwrap();
+        });
+
+        let mut reader = VshAsyncRead::new(Trickle {
+            data: writer.sock.data,
+            ..Default::default()
+        });
+        let received: Vec<GuestMessage> = block_on(
+            reader
+                .messages::<GuestMessage>()
+                .map(|msg| msg.unwrap())
+                .collect(),
+        );
+        assert_eq!(received, vec![data_message(b"one"), data_message(b"two")]);
+    }
+
+    #[test]
+    fn async_cancellation() {
+        let msg = data_message(b"not lost");
+
+        // Drop each send after a single poll, as a losing select! branch
+        // would be. Flushing afterwards must still produce a whole frame.
+        let mut writer = VshAsyncWrite::new(Trickle::default());
+        assert!(writer.send_message(&msg).now_or_never().is_none());
+        block_on(SinkExt::<GuestMessage>::flush(&mut writer)).unwrap();
+
+        let mut reader = VshAsyncRead::new(Trickle {
+            data: writer.sock.data,
+            ..Default::default()
+        });
+        let received = loop {
+            if let Some(received) = reader.messages::<GuestMessage>().next().now_or_never() {
+                break received;
+            }
+        };
+        assert_eq!(received.unwrap().unwrap(), msg);
+        assert!(block_on(reader.messages::<GuestMessage>().next()).is_none());
+    }
+
+    #[test]
+    fn async_split() {
+        let (host_sock, guest_sock) = UnixStream::pair().unwrap();
+        let host = VshAsyncWire::new(AsyncFd::new(host_sock).unwrap());
+        let guest = VshAsyncWire::new(AsyncFd::new(guest_sock).unwrap());
+        let (mut host_rx, mut host_tx) = host.split();
+        let (mut guest_rx, mut guest_tx) = guest.split();
+
+        // The guest echoes every message back. The host sends all of its
+        // messages before receiving any, so the echoes must be received by
+        // a task of their own while the host is still sending.
+        let echo = async {
+            let mut messages = guest_rx.messages::<GuestMessage>();
+            while let Some(msg) = messages.next().await {
+                guest_tx.send(msg.unwrap()).await.unwrap();
+            }
+            SinkExt::<GuestMessage>::close(&mut guest_tx).await.unwrap();
+        };
+        pin_mut!(echo);
+        let host_pumps = async {
+            let sent = vec![data_message(b"one"), data_message(b"two")];
+            let send = async {
+                for msg in &sent {
+                    host_tx.send_message(msg).await.unwrap();
+                }
+                SinkExt::<GuestMessage>::close(&mut host_tx).await.unwrap();
+            };
+            let receive = host_rx
+                .messages::<GuestMessage>()
+                .map(|msg| msg.unwrap())
+                .collect::<Vec<_>>();
+            let ((), received) = future::join(send, receive).await;
+            assert_eq!(received, sent);
+        };
+        pin_mut!(host_pumps);
+
+        complete2(echo, host_pumps).unwrap();
+    }
+
+    #[test]
+    fn max_data_size_fits() {
+        for &frame_size in &[VSH_BUF_SIZE, 16 * 1024, MAX_FRAME_SIZE] {
+            let data_size = max_data_size(frame_size);
+            let data = vec![0u8; data_size + 1];
+            let host_msg = HostMessage::from_data_message({
+                let mut data_msg = DataMessage::new();
+                data_msg.set_stream(StdioStream::STDERR_STREAM);
+                data_msg.set_data(data[..data_size].to_vec());
+                data_msg
+            });
+            assert!(host_msg.compute_size() as usize <= frame_size);
+
+            // One more byte doesn't fit in the default frame size.
+            if frame_size == VSH_BUF_SIZE {
+                assert_eq!(host_msg.compute_size() as usize, frame_size);
+                let mut guest_msg = data_message(&data);
+                guest_msg
+                    .mut_data_message()
+                    .set_stream(StdioStream::STDERR_STREAM);
+                assert!(guest_msg.compute_size() as usize > frame_size);
+            }
+        }
+    }
+
+    /// Returns the frame that send_message would produce for `msg`.
+    fn frame_of<M: Message>(msg: &M) -> Vec<u8> {
+        let payload = msg.write_to_bytes().unwrap();
+        let mut frame = (payload.len() as u32).to_le_bytes().to_vec();
+        frame.extend_from_slice(&payload);
+        frame
+    }
+
+    #[test]
+    fn data_header_matches_protobuf() {
+        let streams = [
+            StdioStream::INVALID_STREAM,
+            StdioStream::STDIN_STREAM,
+            StdioStream::STDERR_STREAM,
+        ];
+        let sizes = [
+            0,
+            1,
+            127,
+            128,
+            max_data_size(VSH_BUF_SIZE),
+            max_data_size(MAX_FRAME_SIZE),
+        ];
+        for &stream in &streams {
+            for &size in &sizes {
+                let data = vec![0xa5; size];
+                let mut data_msg = DataMessage::new();
+                data_msg.set_stream(stream);
+                data_msg.set_data(data.clone());
+
+                let mut frame = Vec::new();
+                encode_data_header::<HostMessage>(&mut frame, stream, size);
+                frame.extend_from_slice(&data);
+                assert_eq!(
+                    frame,
+                    frame_of(&HostMessage::from_data_message(data_msg.clone()))
+                );
+
+                frame.clear();
+                encode_data_header::<GuestMessage>(&mut frame, stream, size);
+                frame.extend_from_slice(&data);
+                assert_eq!(frame, frame_of(&GuestMessage::from_data_message(data_msg)));
+            }
+        }
+    }
+
+    /// A writer that accepts at most three bytes per write.
+    struct Dribble(Vec<u8>);
+
+    impl Write for Dribble {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let count = min(buf.len(), 3);
+            self.0.extend_from_slice(&buf[..count]);
+            Ok(count)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn partial_vectored_writes() {
+        let mut writer = Dribble(Vec::new());
+        write_all_vectored(&mut writer, b"head", b"payload").unwrap();
+        assert_eq!(writer.0, b"headpayload");
+
+        let mut buf = Vec::new();
+        write_all_vectored(&mut buf, b"head", b"").unwrap();
+        assert_eq!(buf, b"head");
+    }
+
+    #[test]
+    fn async_send_data() {
+        let max_data = max_data_size(VSH_BUF_SIZE);
+        let data: Vec<u8> = (0..max_data + 10).map(|i| i as u8).collect();
+        let mut writer = VshAsyncWrite::new(Trickle::default());
+        block_on(writer.send_data::<HostMessage>(StdioStream::STDOUT_STREAM, &data)).unwrap();
+
+        let mut expected = frame_of(&stdout_message(&data[..max_data]));
+        expected.extend(frame_of(&stdout_message(&data[max_data..])));
+        assert_eq!(writer.sock.data, expected);
+    }
+
+    #[test]
+    fn negotiate() {
+        // Old peers get the original protocol.
+        let legacy = Negotiated::with_peer(0, &local_capabilities());
+        assert_eq!(legacy.version, 0);
+        assert_eq!(legacy.max_send_frame_size, VSH_BUF_SIZE);
+        assert_eq!(legacy.max_receive_frame_size, VSH_BUF_SIZE);
+
+        let both = Negotiated::with_peer(PROTOCOL_VERSION, &local_capabilities());
+        assert_eq!(both.version, PROTOCOL_VERSION);
+        assert_eq!(both.max_send_frame_size, MAX_FRAME_SIZE);
+        assert_eq!(both.max_receive_frame_size, MAX_FRAME_SIZE);
+
+        // Newer peers are limited to what this side supports, and no peer
+        // can lower the frame size below the original one. What this side
+        // receives is limited only by what it advertised.
+        let mut caps = Capabilities::new();
+        caps.set_max_frame_size(1024);
+        caps.set_features(vec!["from-the-future".to_string()].into());
+        let newer = Negotiated::with_peer(PROTOCOL_VERSION + 1, &caps);
+        assert_eq!(newer.version, PROTOCOL_VERSION);
+        assert_eq!(newer.max_send_frame_size, VSH_BUF_SIZE);
+        assert_eq!(newer.max_receive_frame_size, MAX_FRAME_SIZE);
+        assert!(!newer.has_feature("from-the-future"));
+
+        caps.set_max_frame_size(std::u32::MAX);
+        let huge = Negotiated::with_peer(PROTOCOL_VERSION, &caps);
+        assert_eq!(huge.max_send_frame_size, MAX_FRAME_SIZE);
+    }
+
+    #[test]
+    fn negotiated_frame_size() {
+        let (host_sock, guest_sock) = UnixStream::pair().unwrap();
+        let mut host = VshWire::new(host_sock);
+        let mut guest = VshWire::new(guest_sock);
+
+        // Frames beyond the original size are refused until both sides
+        // have raised their limits.
+        let data = vec![0x5a; max_data_size(VSH_BUF_SIZE) * 3];
+        let big_msg = stdout_message(&data);
+        match host.send_message(&big_msg) {
+            Err(VshWireError::MessageTooBig(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // The guest advertised the largest size, but the host only a
+        // slightly larger one than the original. The guest must still accept
+        // whatever it advertised, while only sending what the host accepts.
+        let host_frame_size = VSH_BUF_SIZE * 2;
+        host.set_max_send_frame_size(MAX_FRAME_SIZE);
+        host.set_max_receive_frame_size(host_frame_size);
+        guest.set_max_send_frame_size(host_frame_size);
+        guest.set_max_receive_frame_size(MAX_FRAME_SIZE);
+        host.send_data::<HostMessage>(StdioStream::STDOUT_STREAM, &data)
+            .unwrap();
+
+        let mut received = HostMessage::new();
+        guest.receive_message(&mut received).unwrap();
+        assert_eq!(received, big_msg);
+
+        match guest.send_message(&big_msg) {
+            Err(VshWireError::MessageTooBig(_)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        guest
+            .send_data::<HostMessage>(StdioStream::STDOUT_STREAM, &data)
+            .unwrap();
+        let mut received = Vec::new();
+        while received.len() < data.len() {
+            let mut host_msg = HostMessage::new();
+            host.receive_message(&mut host_msg).unwrap();
+            received.extend_from_slice(host_msg.get_data_message().get_data());
+        }
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn send_large_data() {
+        let (host_sock, guest_sock) = UnixStream::pair().unwrap();
+        let mut host = VshWire::new(host_sock);
+        let mut guest = VshWire::new(guest_sock);
+
+        let max_data = max_data_size(VSH_BUF_SIZE);
+        let data: Vec<u8> = (0..max_data * 2 + 100).map(|i| i as u8).collect();
+        host.send_data::<HostMessage>(StdioStream::STDOUT_STREAM, &data)
+            .unwrap();
+        host.send_data::<HostMessage>(StdioStream::STDOUT_STREAM, &[])
+            .unwrap();
+
+        let mut chunk_lens = Vec::new();
+        let mut received = Vec::new();
+        loop {
+            let mut host_msg = HostMessage::new();
+            guest.receive_message(&mut host_msg).unwrap();
+            let data_msg = host_msg.get_data_message();
+            assert_eq!(data_msg.get_stream(), StdioStream::STDOUT_STREAM);
+            chunk_lens.push(data_msg.get_data().len());
+            if data_msg.get_data().is_empty() {
+                break;
+            }
+            received.extend_from_slice(data_msg.get_data());
+        }
+        assert_eq!(chunk_lens, vec![max_data, max_data, 100, 0]);
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn async_coalesce() {
+        let mut stderr_msg = stdout_message(b"err");
+        stderr_msg
+            .mut_data_message()
+            .set_stream(StdioStream::STDERR_STREAM);
+        let mut status_msg = HostMessage::new();
+        status_msg
+            .mut_status_message()
+            .set_status(ConnectionStatus::EXITED);
+
+        let mut writer = VshAsyncWrite::new(Trickle::default());
+        block_on(async {
+            for data in &[&b"one"[..], b"two", b"three"] {
+                writer.send(stdout_message(data)).await.unwrap();
+            }
+            writer.send(stderr_msg.clone()).await.unwrap();
+            writer.send(status_msg.clone()).await.unwrap();
+            writer
+                .send_data::<HostMessage>(StdioStream::STDOUT_STREAM, &[])
+                .await
+                .unwrap();
+        });
+
+        let mut reader = VshAsyncRead::new(futures::io::Cursor::new(writer.sock.data));
+        let received: Vec<HostMessage> = block_on(
+            reader
+                .coalesced_messages::<HostMessage>(6)
+                .map(|msg| msg.unwrap())
+                .collect(),
+        );
+        assert_eq!(
+            received,
+            vec![
+                stdout_message(b"onetwo"),
+                stdout_message(b"three"),
+                stderr_msg,
+                status_msg,
+                stdout_message(b""),
+            ]
+        );
+    }
+
     #[test]
     fn send_recv_valid() {
         let (host_sock, guest_sock) = UnixStream::pair().unwrap();
@@ -214,7 +1277,6 @@ mod tests {
         status_msg1.set_code(code);
         host.send_message(&guest_msg1).unwrap();
 
-
         // Receive the GuestMessage and ensure the fields match what was sent.
         let mut guest_msg2 = GuestMessage::new();
         guest.receive_message(&mut guest_msg2).unwrap();
@@ -235,7 +1297,9 @@ mod tests {
         host_sock.write_all(&std::u32::MAX.to_le_bytes()).unwrap();
 
         let mut guest_msg = GuestMessage::new();
-        guest.receive_message(&mut guest_msg).expect_err("allowed invalid size");
+        guest
+            .receive_message(&mut guest_msg)
+            .expect_err("allowed invalid size");
     }
 
     #[test]
@@ -248,6 +1312,8 @@ mod tests {
         host_sock.write_all(&std::u32::MAX.to_le_bytes()).unwrap();
 
         let mut guest_msg = GuestMessage::new();
-        guest.receive_message(&mut guest_msg).expect_err("allowed invalid size");
+        guest
+            .receive_message(&mut guest_msg)
+            .expect_err("allowed invalid size");
     }
 }
@@ -26,11 +26,13 @@ fn main() {
     };
     let chunneld_dir = proto_root.join("dbus/chunneld");
     let cicerone_dir = proto_root.join("dbus/vm_cicerone");
+    let concierge_dir = proto_root.join("dbus/vm_concierge");
     let input_files = [
         chunneld_dir.join("chunneld_service.proto"),
         cicerone_dir.join("cicerone_service.proto"),
+        concierge_dir.join("concierge_service.proto"),
     ];
-    let include_dirs = [chunneld_dir, cicerone_dir];
+    let include_dirs = [chunneld_dir, cicerone_dir, concierge_dir];
 
     protoc_rust::run(protoc_rust::Args {
         out_dir: out_dir.as_os_str().to_str().unwrap(),